@@ -0,0 +1,278 @@
+//! Derive macro generating the parameter boilerplate of physynth gadgets.
+//!
+//! ```ignore
+//! #[derive(Clone, GadgetParameters)]
+//! #[gadget(name = "DO")]
+//! pub struct DampedOscillatorGadget {
+//!     #[param(default = 440.0, range = 0.0..=20000.0, units = "Hz")]
+//!     frequency: Parameter,
+//!     #[param(default = 1.0, output)]
+//!     x: Parameter,
+//!     #[init(1.0 / DEFAULT_SAMPLE_RATE as f32)]
+//!     dt: f32,
+//!     instance_name: String,
+//! }
+//! ```
+//!
+//! Every field of type `Parameter` becomes a parameter, in declaration order.
+//! Parameters written by the gadget are marked with `output`, this defines the evaluation order.
+//! Discrete parameters name their options with `choices = &[...]` (any `&'static [&'static str]` expression).
+//! The derive implements `GadgetParameters`, `GadgetUI` and `GadgetWithUI`
+//! and an inherent `new(instance_name: &str)` constructor.
+//! Gadgets with their own GUI add `custom_ui` (`#[gadget(name = "MB", custom_ui)]`)
+//! and implement `GadgetUI` and `GadgetWithUI` themselves.
+//! Fields that are not parameters are initialized with `#[init(expr)]` or `Default::default()`,
+//! except `instance_name`, which receives the constructor argument.
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::parse::{Parse, ParseStream};
+use syn::punctuated::Punctuated;
+use syn::{parse_macro_input, Data, DeriveInput, Expr, Fields, Ident, LitStr, Token, Type};
+
+#[proc_macro_derive(GadgetParameters, attributes(gadget, param, init))]
+pub fn derive_gadget_parameters(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    match expand(&input) {
+        Ok(tokens) => tokens.into(),
+        Err(e) => e.to_compile_error().into(),
+    }
+}
+
+/// `key = value` pair or bare flag inside `#[gadget(...)]` or `#[param(...)]`.
+struct Setting {
+    key: Ident,
+    value: Option<Expr>,
+}
+
+impl Parse for Setting {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let key: Ident = input.parse()?;
+        let value = if input.peek(Token![=]) {
+            input.parse::<Token![=]>()?;
+            Some(input.parse()?)
+        } else {
+            None
+        };
+        Ok(Setting { key, value })
+    }
+}
+
+impl Setting {
+    fn value(&self) -> syn::Result<&Expr> {
+        self.value.as_ref().ok_or_else(|| {
+            syn::Error::new_spanned(&self.key, format!("{} expects a value", self.key))
+        })
+    }
+}
+
+fn settings(attr: &syn::Attribute) -> syn::Result<Vec<Setting>> {
+    Ok(attr
+        .parse_args_with(Punctuated::<Setting, Token![,]>::parse_terminated)?
+        .into_iter()
+        .collect())
+}
+
+fn string_value(setting: &Setting) -> syn::Result<LitStr> {
+    match setting.value()? {
+        Expr::Lit(syn::ExprLit {
+            lit: syn::Lit::Str(s),
+            ..
+        }) => Ok(s.clone()),
+        _ => Err(syn::Error::new_spanned(
+            &setting.key,
+            format!("{} expects a string literal", setting.key),
+        )),
+    }
+}
+
+fn is_parameter(ty: &Type) -> bool {
+    match ty {
+        Type::Path(path) => path
+            .path
+            .segments
+            .last()
+            .map(|s| s.ident == "Parameter")
+            .unwrap_or(false),
+        _ => false,
+    }
+}
+
+fn parameter_constructor(name: &Ident, attrs: &[syn::Attribute]) -> syn::Result<TokenStream2> {
+    let name_text = name.to_string();
+    let mut default = quote!(0.0);
+    let mut modifiers = Vec::new();
+    for attr in attrs.iter().filter(|a| a.path().is_ident("param")) {
+        for setting in settings(attr)? {
+            match setting.key.to_string().as_str() {
+                "default" => {
+                    let value = setting.value()?;
+                    default = quote!(#value);
+                }
+                "range" => match setting.value()? {
+                    Expr::Range(syn::ExprRange {
+                        start: Some(start),
+                        end: Some(end),
+                        limits: syn::RangeLimits::Closed(_),
+                        ..
+                    }) => modifiers.push(quote!(.with_range(#start, #end))),
+                    value => {
+                        return Err(syn::Error::new_spanned(
+                            value,
+                            "range expects a closed range like 0.0..=1.0",
+                        ))
+                    }
+                },
+                "units" => {
+                    let units = string_value(&setting)?;
+                    modifiers.push(quote!(.with_units(#units)));
+                }
+                "choices" => {
+                    let choices = setting.value()?;
+                    modifiers.push(quote!(.with_choices(#choices)));
+                }
+                "output" => modifiers.push(quote!(.as_output())),
+                _ => {
+                    return Err(syn::Error::new_spanned(
+                        &setting.key,
+                        "unknown parameter setting, expected default, range, units, choices or output",
+                    ))
+                }
+            }
+        }
+    }
+    Ok(quote! {
+        ::physynth::gadget::Parameter::new(#name_text, #default) #(#modifiers)*
+    })
+}
+
+fn expand(input: &DeriveInput) -> syn::Result<TokenStream2> {
+    let ident = &input.ident;
+    let mut gadget_name: Option<LitStr> = None;
+    let mut custom_ui = false;
+    for attr in input.attrs.iter().filter(|a| a.path().is_ident("gadget")) {
+        for setting in settings(attr)? {
+            match setting.key.to_string().as_str() {
+                "name" => gadget_name = Some(string_value(&setting)?),
+                "custom_ui" => {
+                    if let Some(value) = &setting.value {
+                        return Err(syn::Error::new_spanned(value, "custom_ui takes no value"));
+                    }
+                    custom_ui = true;
+                }
+                _ => {
+                    return Err(syn::Error::new_spanned(
+                        &setting.key,
+                        "unknown gadget setting, expected name or custom_ui",
+                    ))
+                }
+            }
+        }
+    }
+    let gadget_name = gadget_name.ok_or_else(|| {
+        syn::Error::new_spanned(ident, "missing #[gadget(name = \"...\")] attribute")
+    })?;
+
+    let fields = match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(fields) => &fields.named,
+            _ => {
+                return Err(syn::Error::new_spanned(
+                    ident,
+                    "GadgetParameters requires named fields",
+                ))
+            }
+        },
+        _ => {
+            return Err(syn::Error::new_spanned(
+                ident,
+                "GadgetParameters can only be derived for structs",
+            ))
+        }
+    };
+
+    let mut parameters = Vec::new();
+    let mut initializers = Vec::new();
+    let mut has_instance_name = false;
+    for field in fields.iter() {
+        let name = field.ident.as_ref().expect("named field");
+        if is_parameter(&field.ty) {
+            let constructor = parameter_constructor(name, &field.attrs)?;
+            initializers.push(quote!(#name: #constructor));
+            parameters.push(name);
+        } else if name == "instance_name" {
+            has_instance_name = true;
+            initializers.push(quote!(instance_name: instance_name.to_owned()));
+        } else {
+            let init = field.attrs.iter().find(|a| a.path().is_ident("init"));
+            let value = match init {
+                Some(attr) => attr.parse_args::<Expr>()?,
+                None => syn::parse_quote!(::std::default::Default::default()),
+            };
+            initializers.push(quote!(#name: #value));
+        }
+    }
+    if !has_instance_name {
+        return Err(syn::Error::new_spanned(
+            ident,
+            "GadgetParameters requires an `instance_name: String` field",
+        ));
+    }
+
+    let count = parameters.len();
+    let indices = 0..count;
+    let indices_mut = 0..count;
+    let parameters_mut = parameters.clone();
+    let panic_message = format!("Invalid parameter number {{}} in {}", ident);
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    let ui = if custom_ui {
+        quote!()
+    } else {
+        quote! {
+            impl #impl_generics ::physynth::gadget::GadgetUI for #ident #ty_generics #where_clause {
+                fn gui(&mut self, state: &mut ::physynth::gadget::GuiState, ui: &mut ::egui::Ui) {
+                    ::physynth::gadget::gadget_gui(self, state, ui);
+                }
+            }
+
+            impl #impl_generics ::physynth::gadget::GadgetWithUI for #ident #ty_generics #where_clause {}
+        }
+    };
+
+    Ok(quote! {
+        impl #impl_generics #ident #ty_generics #where_clause {
+            pub fn new(instance_name: &str) -> Self {
+                #ident {
+                    #(#initializers,)*
+                }
+            }
+        }
+
+        impl #impl_generics ::physynth::gadget::GadgetParameters for #ident #ty_generics #where_clause {
+            fn name(&self) -> &'static str {
+                #gadget_name
+            }
+            fn get_instance_name(&self) -> String {
+                self.instance_name.to_owned()
+            }
+            fn par(&self, i: usize) -> &::physynth::gadget::Parameter {
+                match i {
+                    #(#indices => &self.#parameters,)*
+                    _ => panic!(#panic_message, i),
+                }
+            }
+            fn par_mut(&mut self, i: usize) -> &mut ::physynth::gadget::Parameter {
+                match i {
+                    #(#indices_mut => &mut self.#parameters_mut,)*
+                    _ => panic!(#panic_message, i),
+                }
+            }
+            fn parameter_count(&self) -> usize {
+                #count
+            }
+        }
+
+        #ui
+    })
+}
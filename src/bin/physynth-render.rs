@@ -0,0 +1,79 @@
+use physynth::patch::*;
+use physynth::registry::*;
+use physynth::render::*;
+use std::error::Error;
+use std::process;
+
+const USAGE: &str = "Usage: physynth-render [OPTIONS] OUTPUT.wav
+
+Renders a patch offline into a WAV file.
+
+Options:
+  -i, --patch FILE          patch to render (default: a single oscillator)
+  -d, --duration SECONDS    length of the rendering in seconds (default 1)
+  -n, --samples COUNT       length of the rendering in samples
+  -r, --sample-rate HZ      sample rate (default 48000)
+  -f, --format FORMAT       16, 24 or 32f (default 16)
+  -p, --parameter NAME      parameter written to the next channel, can be repeated
+                            (default: the channels of the output gadget)
+      --normalize           scale the peak to full scale
+  -h, --help                print this message";
+
+fn value<'a>(args: &'a [String], i: &mut usize) -> Result<&'a str, Box<dyn Error>> {
+    *i += 1;
+    args.get(*i)
+        .map(|x| x.as_str())
+        .ok_or_else(|| format!("Missing value for {}", args[*i - 1]).into())
+}
+
+fn run(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let mut options = RenderOptions::default();
+    let mut duration: Option<f32> = None;
+    let mut samples: Option<usize> = None;
+    let mut path: Option<&str> = None;
+    let mut patch_path: Option<&str> = None;
+    let mut i = 0;
+    while i < args.len() {
+        match args[i].as_str() {
+            "-i" | "--patch" => patch_path = Some(value(args, &mut i)?),
+            "-d" | "--duration" => duration = Some(value(args, &mut i)?.parse()?),
+            "-n" | "--samples" => samples = Some(value(args, &mut i)?.parse()?),
+            "-r" | "--sample-rate" => options.sample_rate = value(args, &mut i)?.parse()?,
+            "-f" | "--format" => {
+                let text = value(args, &mut i)?;
+                options.format =
+                    WavFormat::parse(text).ok_or_else(|| format!("Unknown format {}", text))?;
+            }
+            "-p" | "--parameter" => options.outputs.push(value(args, &mut i)?.to_owned()),
+            "--normalize" => options.normalize = true,
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return Ok(());
+            }
+            x if x.starts_with('-') => return Err(format!("Unknown option {}", x).into()),
+            x => path = Some(x),
+        }
+        i += 1;
+    }
+    let path = path.ok_or("Missing output file")?;
+    options.set_duration(duration.unwrap_or(1.0));
+    if let Some(n) = samples {
+        options.samples = n;
+    }
+
+    let patch = match patch_path {
+        Some(p) => Patch::load(p)?.to_container(&GadgetRegistry::standard())?,
+        None => default_patch(),
+    };
+    let data = render(&patch, &options)?;
+    write_wav(path, &data.samples, data.channels, options.sample_rate, options.format)?;
+    Ok(())
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if let Err(e) = run(&args) {
+        eprintln!("Error: {}\n\n{}", e, USAGE);
+        process::exit(1);
+    }
+}
@@ -1,131 +1,375 @@
 use crate::gadget::*;
+use crate::midi::*;
+use crate::registry::*;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
 use std::time::Duration;
 use egui::{Ui};
 use rodio::{source::Source};
 
+/// Maximum number of channels of an output gadget and of the audio stream.
+pub const MAX_CHANNELS: usize = 8;
+
+/// Gadget type identifiers of the output gadgets, by number of channels.
+const OUTPUT_TYPES: [&str; MAX_CHANNELS] = [
+    "Output", "Output2", "Output3", "Output4", "Output5", "Output6", "Output7", "Output8",
+];
+const CHANNEL_NAMES: [&str; MAX_CHANNELS] = ["OUT1", "OUT2", "OUT3", "OUT4", "OUT5", "OUT6", "OUT7", "OUT8"];
+
+pub fn register(registry: &mut GadgetRegistry) {
+    let constructors: [GadgetConstructor; MAX_CHANNELS] = [
+        |_| Box::new(OutputGadget::new()),
+        |_| Box::new(OutputGadget::with_channels(2)),
+        |_| Box::new(OutputGadget::with_channels(3)),
+        |_| Box::new(OutputGadget::with_channels(4)),
+        |_| Box::new(OutputGadget::with_channels(5)),
+        |_| Box::new(OutputGadget::with_channels(6)),
+        |_| Box::new(OutputGadget::with_channels(7)),
+        |_| Box::new(OutputGadget::with_channels(8)),
+    ];
+    const DISPLAY_NAMES: [&str; MAX_CHANNELS] = [
+        "Mono output",
+        "Stereo output",
+        "3 channel output",
+        "4 channel output",
+        "5 channel output",
+        "6 channel output",
+        "7 channel output",
+        "8 channel output",
+    ];
+    for (i, constructor) in constructors.iter().enumerate() {
+        registry.register(GadgetType {
+            id: OUTPUT_TYPES[i],
+            display_name: DISPLAY_NAMES[i],
+            category: "Output",
+            description: "Sends its channels (OUT, L/R or OUT1...) to the audio output",
+            prefix: "Output",
+            constructor: *constructor,
+        });
+    }
+}
+
+/// Audio output of a patch. A mono output has the parameter OUT, a stereo output L and R,
+/// more channels are called OUT1, OUT2, ...
+/// Parameter names are not prefixed by the instance name, a patch has a single output.
+#[derive(Clone)]
 pub struct OutputGadget {
-    output: Parameter,
+    outputs: Vec<Parameter>,
 }
 
 impl OutputGadget {
     pub fn new() -> OutputGadget {
+        Self::with_channels(1)
+    }
+    /// Output with 1 to `MAX_CHANNELS` channels.
+    pub fn with_channels(channels: usize) -> OutputGadget {
+        let names: &[&'static str] = match channels {
+            1 => &["OUT"],
+            2 => &["L", "R"],
+            n => &CHANNEL_NAMES[..n.clamp(1, MAX_CHANNELS)],
+        };
         OutputGadget {
-            output: Parameter::new("OUT", 0.0),
+            outputs: names.iter().map(|&name| Parameter::new(name, 0.0)).collect(),
         }
     }
+    pub fn channels(&self) -> usize {
+        self.outputs.len()
+    }
+}
+
+impl Default for OutputGadget {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl GadgetUI for OutputGadget {
-    fn gui(&mut self, link: &mut Option<String>, ui: &mut Ui) {
-        gadget_gui(self, link, ui);
+    fn gui(&mut self, state: &mut GuiState, ui: &mut Ui) {
+        gadget_gui(self, state, ui);
     }
 }
 
 impl GadgetWithUI for OutputGadget {}
 
-impl Gadget for OutputGadget {
+impl GadgetParameters for OutputGadget {
     fn name(&self) -> &'static str {
-        "Output"
+        OUTPUT_TYPES[self.outputs.len() - 1]
     }
     fn get_instance_name(&self) -> String {
         "Output".to_owned()
     }
-    fn parameter_names(&self) -> Vec<String> {
-        vec!["OUT".to_owned()]
-    }
 
     fn par(&self, i: usize) -> &Parameter {
-        if i != 0 {
-            panic!("Invalid parameter number {} in output", i);
-        }
-        &self.output
+        self.outputs
+            .get(i)
+            .unwrap_or_else(|| panic!("Invalid parameter number {} in output", i))
     }
     fn par_mut(&mut self, i: usize) -> &mut Parameter {
-        if i != 0 {
-            panic!("Invalid parameter number {} in output", i);
-        }
-        &mut self.output
+        self.outputs
+            .get_mut(i)
+            .unwrap_or_else(|| panic!("Invalid parameter number {} in output", i))
     }
     fn parameter_count(&self) -> usize {
-        1
+        self.outputs.len()
     }
-    fn run(&mut self) {}
 }
 
+impl Gadget for OutputGadget {
+    fn parameter_names(&self) -> Vec<String> {
+        self.outputs.iter().map(|p| p.name.to_owned()).collect()
+    }
+    fn output_channels(&self) -> Vec<String> {
+        self.parameter_names()
+    }
+    fn run(&mut self, _signals: &mut Signals) {}
+}
+
+/// Reasons why the links of a patch cannot be bound.
+#[derive(Debug, Clone, PartialEq)]
+pub enum BindError {
+    /// Parameters linking to each other in a loop, in link order.
+    LinkCycle(Vec<String>),
+    UnknownTarget { parameter: String, target: String },
+    DuplicateInstanceName(String),
+    DuplicateParameterName(String),
+}
+
+impl fmt::Display for BindError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BindError::LinkCycle(path) => {
+                write!(f, "Link cycle: {} -> {}", path.join(" -> "), path[0])
+            }
+            BindError::UnknownTarget { parameter, target } => {
+                write!(f, "{} is linked to unknown parameter {}", parameter, target)
+            }
+            BindError::DuplicateInstanceName(name) => {
+                write!(f, "Instance name {} is used more than once", name)
+            }
+            BindError::DuplicateParameterName(name) => {
+                write!(f, "Parameter name {} is used more than once", name)
+            }
+        }
+    }
+}
+
+impl std::error::Error for BindError {}
+
 pub struct Engine<G: Gadget> {
     pub gadget: G,
-    pub buffer: Vec<f32>,
-    pub output: *mut f32,
+    pub signals: Signals,
+    /// Slots sent to the audio channels.
+    pub outputs: Vec<usize>,
+    /// Channel returned by the next call of `next`, samples are interleaved.
+    channel: usize,
+    pub sample_rate: u32,
+    /// Feedback links found by the last bind.
+    pub feedback: Vec<Feedback>,
+    /// Slot copies (from, to) performed after every sample to delay feedback links.
+    delays: Vec<(usize, usize)>,
+    /// MIDI controllers bound to slots, allocated with a fixed capacity.
+    midi_map: Vec<MidiMapping>,
+}
+
+impl<G: Gadget + Clone> Clone for Engine<G> {
+    /// Copies keep the fixed capacity of the MIDI map, so that MIDI learn does not allocate on them.
+    fn clone(&self) -> Self {
+        let mut midi_map = Vec::with_capacity(MAX_MIDI_MAPPINGS.max(self.midi_map.len()));
+        midi_map.extend_from_slice(&self.midi_map);
+        Self {
+            gadget: self.gadget.clone(),
+            signals: self.signals.clone(),
+            outputs: self.outputs.clone(),
+            channel: self.channel,
+            sample_rate: self.sample_rate,
+            feedback: self.feedback.clone(),
+            delays: self.delays.clone(),
+            midi_map,
+        }
+    }
 }
 
 impl<G: Gadget> Engine<G> {
     pub fn new(gadget: G) -> Self {
+        Self::with_sample_rate(gadget, DEFAULT_SAMPLE_RATE)
+    }
+    pub fn with_sample_rate(gadget: G, sample_rate: u32) -> Self {
         Self {
-            gadget: gadget,
-            buffer: Vec::new(),
-            output: ZERO,
+            gadget,
+            signals: Signals::new(),
+            outputs: Vec::new(),
+            channel: 0,
+            sample_rate,
+            feedback: Vec::new(),
+            delays: Vec::new(),
+            midi_map: Vec::new(),
         }
     }
-    fn root_parameter_name(&self, name: &str) -> String {
-        match self.gadget.parameter(name) {
-            Some(&Parameter {
-                link: Link::Link(ref lnk),
-                ..
-            }) => self.root_parameter_name(lnk),
-            _ => name.to_owned(),
-        }
+    /// Changes the sample rate and prepares the gadgets for it.
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.sample_rate = sample_rate;
+        self.gadget.prepare(sample_rate);
     }
-    /*
-    fn root_parameter_mut(&mut self, name: &str) -> Option<&mut Parameter> {
-        let name = self.root_parameter_name(name);
-        self.gadget.parameter_mut(&name)
+    /// Time step between two samples in seconds.
+    pub fn dt(&self) -> f32 {
+        1.0 / self.sample_rate as f32
     }
-    */
-    fn root_parameter_pointer(&self, name: &str) -> *mut f32 {
-        let name = self.root_parameter_name(name);
-        self.gadget
-            .parameter(&name)
-            .expect(&format!("Parameter {} should be known", name))
-            .value
+    /// Follows the links starting at parameter `i` and returns the index of the parameter owning the slot.
+    fn root_parameter(
+        &self,
+        i: usize,
+        names: &[String],
+        index: &HashMap<&str, usize>,
+    ) -> Result<usize, BindError> {
+        let mut path = vec![i];
+        let mut current = i;
+        while let Link::Link(ref target) = self.gadget.par(current).link {
+            current = *index
+                .get(target.as_str())
+                .ok_or_else(|| BindError::UnknownTarget {
+                    parameter: names[current].clone(),
+                    target: target.clone(),
+                })?;
+            if let Some(start) = path.iter().position(|&j| j == current) {
+                return Err(BindError::LinkCycle(
+                    path[start..].iter().map(|&j| names[j].clone()).collect(),
+                ));
+            }
+            path.push(current);
+        }
+        Ok(current)
     }
 
-    pub fn bind(&mut self) {
-        self.buffer.resize(self.gadget.free_parameter_count(), 0.0);
-        let mut ptr = self.buffer.as_mut_ptr();
+    /// Checks instance names and links, returns the root parameter of every parameter.
+    fn validate(&self) -> Result<Vec<usize>, BindError> {
+        let mut instances = HashSet::new();
+        for name in self.gadget.instance_names() {
+            if !instances.insert(name.clone()) {
+                return Err(BindError::DuplicateInstanceName(name));
+            }
+        }
+        let names = self.gadget.parameter_names();
+        let mut index = HashMap::with_capacity(names.len());
+        for (i, name) in names.iter().enumerate() {
+            if index.insert(name.as_str(), i).is_some() {
+                return Err(BindError::DuplicateParameterName(name.clone()));
+            }
+        }
+        (0..names.len())
+            .map(|i| self.root_parameter(i, &names, &index))
+            .collect()
+    }
+
+    /// Validates the links and assigns a slot in the signal arena to every parameter.
+    pub fn bind(&mut self) -> Result<(), BindError> {
+        let roots = self.validate()?;
+        self.feedback = self.gadget.schedule();
+        self.signals = Signals::with_len(self.gadget.free_parameter_count());
+        let mut slot = 0;
         for i in 0..self.gadget.parameter_count() {
             let p = self.gadget.par_mut(i);
             p.unbind();
 
             if let Link::Value(x) = p.link {
-                p.bind(ptr);
-                **p = x;
+                p.bind(slot);
+                self.signals[slot] = x;
+                slot += 1;
+            }
+        }
+        for (i, &root) in roots.iter().enumerate() {
+            if i != root {
+                let slot = self.gadget.par(root).slot;
+                if let Some(slot) = slot {
+                    self.gadget.par_mut(i).bind(slot);
+                }
             }
-            unsafe { ptr = ptr.add(1) };
         }
+        // Parameters closing a feedback loop read a copy of the shared slot taken after each sample.
+        self.delays.clear();
+        for f in self.feedback.iter() {
+            let p = self.gadget.par_mut(f.parameter);
+            if let Some(slot) = p.slot {
+                let delayed = self.signals.push(self.signals[slot]);
+                self.delays.push((slot, delayed));
+                p.bind(delayed);
+            }
+        }
+        self.gadget.prepare(self.sample_rate);
+        self.midi_map = Vec::with_capacity(MAX_MIDI_MAPPINGS);
         for i in 0..self.gadget.parameter_count() {
+            // A linked parameter would overwrite the slot of its target, e.g. oscillator state.
             let p = self.gadget.par(i);
-            if let Link::Link(ref x) = p.link {
-                let ptr = self.root_parameter_pointer(x);
-                self.gadget.par_mut(i).bind(ptr);
+            if let (Link::Value(_), Some(slot), Some(binding)) = (&p.link, p.slot, p.midi) {
+                self.map_midi(slot, Some(binding));
+            }
+        }
+        let channels = self.gadget.output_channels();
+        self.bind_outputs(&channels);
+        Ok(())
+    }
+    /// Binds a MIDI controller to a slot, replacing its previous binding; None removes the binding.
+    /// Does not allocate, bindings beyond `MAX_MIDI_MAPPINGS` are ignored.
+    pub fn map_midi(&mut self, slot: usize, binding: Option<MidiBinding>) {
+        self.midi_map.retain(|m| m.slot != slot);
+        if let Some(binding) = binding {
+            if self.midi_map.len() < self.midi_map.capacity() && slot < self.signals.len() {
+                self.midi_map.push(MidiMapping::new(slot, binding));
             }
         }
-        let name = self.root_parameter_name("OUT");
-        if let Some(p) = self.gadget.parameter(&name) {
-            self.output = p.value;
-        } else {
-            self.output = ZERO;
+    }
+    /// Applies a MIDI event to all slots bound to its controller.
+    pub fn apply_midi(&mut self, event: &MidiEvent) {
+        for mapping in self.midi_map.iter_mut() {
+            if let Some(value) = mapping.apply(event) {
+                self.signals[mapping.slot] = value;
+            }
+        }
+    }
+    /// Selects a single parameter sent to the (mono) output, returns false if it is not known or not bound.
+    pub fn bind_output(&mut self, name: &str) -> bool {
+        self.bind_outputs(&[name.to_owned()])
+    }
+    /// Selects the parameters sent to the output channels, returns false if one is not known or not bound.
+    /// Channels after `MAX_CHANNELS` are ignored.
+    pub fn bind_outputs(&mut self, names: &[String]) -> bool {
+        let slots: Option<Vec<usize>> = names
+            .iter()
+            .take(MAX_CHANNELS)
+            .map(|name| self.gadget.parameter(name).and_then(|p| p.slot))
+            .collect();
+        self.channel = 0;
+        match slots {
+            Some(slots) => {
+                self.outputs = slots;
+                true
+            }
+            None => {
+                self.outputs.clear();
+                false
+            }
         }
     }
+    /// Number of interleaved channels, at least one.
+    pub fn channels(&self) -> usize {
+        self.outputs.len().max(1)
+    }
     #[inline]
     pub fn run(&mut self) {
-        self.gadget.run();
+        self.gadget.run(&mut self.signals);
+        for &(from, to) in self.delays.iter() {
+            self.signals[to] = self.signals[from];
+        }
     }
+    /// Current value of the first channel.
     #[inline]
     pub fn out(&self) -> f32 {
-        if std::ptr::eq(self.output, ZERO) {
-            0.0
-        } else {
-            unsafe { *self.output }
+        self.out_channel(0)
+    }
+    #[inline]
+    pub fn out_channel(&self, channel: usize) -> f32 {
+        match self.outputs.get(channel) {
+            Some(&slot) => self.signals[slot],
+            None => 0.0,
         }
     }
 }
@@ -133,10 +377,15 @@ impl<G: Gadget> Engine<G> {
 impl<G: Gadget> Iterator for Engine<G> {
     type Item = f32;
 
+    /// Interleaved samples of all channels, the engine runs once per frame.
     #[inline]
     fn next(&mut self) -> Option<f32> {
-        self.run();
-        Some(self.out())
+        if self.channel == 0 {
+            self.run();
+        }
+        let x = self.out_channel(self.channel);
+        self.channel = (self.channel + 1) % self.channels();
+        Some(x)
     }
 }
 
@@ -148,12 +397,12 @@ impl<G: Gadget> Source for Engine<G> {
 
     #[inline]
     fn channels(&self) -> u16 {
-        1
+        Engine::channels(self) as u16
     }
 
     #[inline]
     fn sample_rate(&self) -> u32 {
-        SAMPLERATE
+        self.sample_rate
     }
 
     #[inline]
@@ -0,0 +1,425 @@
+use crate::gadget::*;
+use crate::registry::*;
+use egui::Ui;
+use physynth_derive::GadgetParameters;
+use serde::{Deserialize, Serialize};
+
+pub fn register(registry: &mut GadgetRegistry) {
+    registry.register(GadgetType {
+        id: "ADSR",
+        display_name: "ADSR envelope",
+        category: "Modulation",
+        description: "Attack, decay, sustain and release envelope driven by a gate",
+        prefix: "ADSR",
+        constructor: |name| Box::new(AdsrGadget::new(name)),
+    });
+    registry.register(GadgetType {
+        id: "AR",
+        display_name: "AR envelope",
+        category: "Modulation",
+        description: "Attack and release envelope, held while the gate is on",
+        prefix: "AR",
+        constructor: |name| Box::new(ArGadget::new(name)),
+    });
+    registry.register(GadgetType {
+        id: "MSEG",
+        display_name: "Multi-segment envelope",
+        category: "Modulation",
+        description: "Envelope through a list of levels with an optional sustain point",
+        prefix: "Env",
+        constructor: |name| Box::new(MultiSegmentGadget::new(name)),
+    });
+}
+
+/// Names of the segment curves, in the order of their parameter values.
+pub const ENVELOPE_CURVES: &[&str] = &["Linear", "Exponential"];
+/// Names of the retrigger modes, in the order of their parameter values.
+pub const ENVELOPE_MODES: &[&str] = &["Retrigger", "Legato"];
+/// Largest number of segments of a multi-segment envelope.
+pub const MAX_SEGMENTS: usize = 32;
+
+/// Segment moving the envelope from its current value to `level` in `time` seconds.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Segment {
+    pub level: f32,
+    pub time: f32,
+    /// Fast start and slow approach of the level instead of a straight line.
+    #[serde(default)]
+    pub exponential: bool,
+}
+
+/// Shape of exponential segments, 1 - exp(-5 u) scaled to reach 1 at the end of the segment.
+#[inline]
+fn curve(exponential: bool, u: f32) -> f32 {
+    if exponential {
+        (1.0 - (-5.0 * u).exp()) / (1.0 - (-5.0f32).exp())
+    } else {
+        u
+    }
+}
+
+/// State of an envelope running through a list of segments.
+///
+/// A rising `gate` or a rising edge on `trigger` starts the first segment from the current value,
+/// so restarts do not click. The envelope holds the level of the `sustain` segment while the gate
+/// is on; when the gate falls before or during the sustain it continues with the segment after it.
+/// In legato mode triggers are ignored while the gate is on.
+#[derive(Debug, Clone, Default)]
+pub struct Envelope {
+    pub value: f32,
+    start: f32,
+    stage: Option<usize>,
+    /// Progress through the current segment, from 0 to 1.
+    phase: f32,
+    gate: bool,
+    trigger: bool,
+}
+
+impl Envelope {
+    pub fn is_active(&self) -> bool {
+        self.stage.is_some()
+    }
+
+    pub fn step(
+        &mut self,
+        segments: &[Segment],
+        sustain: Option<usize>,
+        gate: f32,
+        trigger: f32,
+        legato: bool,
+        dt: f32,
+    ) -> f32 {
+        let gate = gate > 0.0;
+        let rising = gate && !self.gate;
+        let triggered = trigger > 0.0 && !self.trigger;
+        self.trigger = trigger > 0.0;
+        if rising || (triggered && !(legato && self.gate)) {
+            self.enter(0);
+        } else if !gate && self.gate {
+            if let (Some(s), Some(stage)) = (sustain, self.stage) {
+                if stage <= s {
+                    self.enter(s + 1);
+                }
+            }
+        }
+        self.gate = gate;
+
+        while let Some(stage) = self.stage {
+            let segment = match segments.get(stage) {
+                Some(segment) => segment,
+                None => {
+                    self.stage = None;
+                    break;
+                }
+            };
+            if self.phase >= 1.0 {
+                if gate && sustain == Some(stage) {
+                    // Follows changes of the sustain level.
+                    self.value = segment.level;
+                    break;
+                }
+                self.enter(stage + 1);
+                continue;
+            }
+            self.phase = if segment.time > dt {
+                (self.phase + dt / segment.time).min(1.0)
+            } else {
+                1.0
+            };
+            self.value =
+                self.start + (segment.level - self.start) * curve(segment.exponential, self.phase);
+            break;
+        }
+        self.value
+    }
+
+    fn enter(&mut self, stage: usize) {
+        self.stage = Some(stage);
+        self.start = self.value;
+        self.phase = 0.0;
+    }
+}
+
+/// ADSR envelope from 0 to `gain` and back; times are in seconds.
+///
+/// `gate` holds the envelope at `sustain` (relative to `gain`), a rising edge on `trigger`
+/// (e.g. the voice trigger) restarts the attack.
+#[derive(Clone, GadgetParameters)]
+#[gadget(name = "ADSR")]
+pub struct AdsrGadget {
+    gate: Parameter,
+    trigger: Parameter,
+    #[param(default = 0.01, range = 0.0..=10.0, units = "s")]
+    attack: Parameter,
+    #[param(default = 0.2, range = 0.0..=10.0, units = "s")]
+    decay: Parameter,
+    #[param(default = 0.5, range = 0.0..=1.0)]
+    sustain: Parameter,
+    #[param(default = 0.5, range = 0.0..=30.0, units = "s")]
+    release: Parameter,
+    #[param(default = 1.0)]
+    gain: Parameter,
+    #[param(choices = ENVELOPE_CURVES)]
+    curve: Parameter,
+    #[param(choices = ENVELOPE_MODES)]
+    mode: Parameter,
+    #[param(output)]
+    out: Parameter,
+    #[init(1.0 / DEFAULT_SAMPLE_RATE as f32)]
+    dt: f32,
+    envelope: Envelope,
+    instance_name: String,
+}
+
+impl Gadget for AdsrGadget {
+    fn prepare(&mut self, sample_rate: u32) {
+        self.dt = 1.0 / sample_rate as f32;
+        self.envelope = Envelope::default();
+    }
+    fn run(&mut self, s: &mut Signals) {
+        let exponential = s[&self.curve].round() as i32 == 1;
+        let segment = |level, time| Segment {
+            level,
+            time,
+            exponential,
+        };
+        let segments = [
+            // The attack stays linear, exponential attacks sound late.
+            Segment {
+                level: 1.0,
+                time: s[&self.attack],
+                exponential: false,
+            },
+            segment(s[&self.sustain], s[&self.decay]),
+            segment(0.0, s[&self.release]),
+        ];
+        let legato = s[&self.mode].round() as i32 == 1;
+        let value = self
+            .envelope
+            .step(&segments, Some(1), s[&self.gate], s[&self.trigger], legato, self.dt);
+        s[&self.out] = s[&self.gain] * value;
+    }
+}
+
+/// Attack and release envelope from 0 to `gain` and back, held while `gate` is on.
+/// A `trigger` without gate plays the attack and release at once, like a percussive hit.
+#[derive(Clone, GadgetParameters)]
+#[gadget(name = "AR")]
+pub struct ArGadget {
+    gate: Parameter,
+    trigger: Parameter,
+    #[param(default = 0.005, range = 0.0..=10.0, units = "s")]
+    attack: Parameter,
+    #[param(default = 0.5, range = 0.0..=30.0, units = "s")]
+    release: Parameter,
+    #[param(default = 1.0)]
+    gain: Parameter,
+    #[param(default = 1.0, choices = ENVELOPE_CURVES)]
+    curve: Parameter,
+    #[param(choices = ENVELOPE_MODES)]
+    mode: Parameter,
+    #[param(output)]
+    out: Parameter,
+    #[init(1.0 / DEFAULT_SAMPLE_RATE as f32)]
+    dt: f32,
+    envelope: Envelope,
+    instance_name: String,
+}
+
+impl Gadget for ArGadget {
+    fn prepare(&mut self, sample_rate: u32) {
+        self.dt = 1.0 / sample_rate as f32;
+        self.envelope = Envelope::default();
+    }
+    fn run(&mut self, s: &mut Signals) {
+        let segments = [
+            Segment {
+                level: 1.0,
+                time: s[&self.attack],
+                exponential: false,
+            },
+            Segment {
+                level: 0.0,
+                time: s[&self.release],
+                exponential: s[&self.curve].round() as i32 == 1,
+            },
+        ];
+        let legato = s[&self.mode].round() as i32 == 1;
+        let value = self
+            .envelope
+            .step(&segments, Some(0), s[&self.gate], s[&self.trigger], legato, self.dt);
+        s[&self.out] = s[&self.gain] * value;
+    }
+}
+
+/// Segments of a multi-segment envelope, saved with the patch.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EnvelopeShape {
+    pub segments: Vec<Segment>,
+    /// Segment whose level is held while the gate is on.
+    #[serde(default)]
+    pub sustain: Option<usize>,
+}
+
+impl Default for EnvelopeShape {
+    fn default() -> Self {
+        let segment = |level, time, exponential| Segment {
+            level,
+            time,
+            exponential,
+        };
+        EnvelopeShape {
+            segments: vec![
+                segment(1.0, 0.01, false),
+                segment(0.3, 0.2, true),
+                segment(0.6, 1.0, false),
+                segment(0.0, 0.5, true),
+            ],
+            sustain: Some(2),
+        }
+    }
+}
+
+impl EnvelopeShape {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.segments.is_empty() || self.segments.len() > MAX_SEGMENTS {
+            return Err(format!("An envelope has 1 to {} segments", MAX_SEGMENTS));
+        }
+        if self
+            .segments
+            .iter()
+            .any(|s| !(s.level.is_finite() && s.time.is_finite() && s.time >= 0.0))
+        {
+            return Err("Segments need a level and a time of at least 0 s".to_owned());
+        }
+        if self
+            .sustain
+            .map(|s| s >= self.segments.len())
+            .unwrap_or(false)
+        {
+            return Err("The sustain point is not a segment".to_owned());
+        }
+        Ok(())
+    }
+}
+
+/// Envelope through the segments of an [`EnvelopeShape`], scaled by `gain`.
+/// `gate`, `trigger` and `mode` work like those of the ADSR envelope.
+#[derive(Clone, GadgetParameters)]
+#[gadget(name = "MSEG", custom_ui)]
+pub struct MultiSegmentGadget {
+    shape: EnvelopeShape,
+    gate: Parameter,
+    trigger: Parameter,
+    #[param(default = 1.0)]
+    gain: Parameter,
+    #[param(choices = ENVELOPE_MODES)]
+    mode: Parameter,
+    #[param(output)]
+    out: Parameter,
+    #[init(1.0 / DEFAULT_SAMPLE_RATE as f32)]
+    dt: f32,
+    envelope: Envelope,
+    instance_name: String,
+}
+
+impl MultiSegmentGadget {
+    pub fn shape(&self) -> &EnvelopeShape {
+        &self.shape
+    }
+    pub fn set_shape(&mut self, shape: EnvelopeShape) -> Result<(), String> {
+        shape.validate()?;
+        self.shape = shape;
+        self.envelope = Envelope::default();
+        Ok(())
+    }
+
+    fn shape_gui(&mut self, ui: &mut Ui) {
+        let mut shape = self.shape.clone();
+        let n = shape.segments.len();
+        let mut remove = None;
+        egui::Grid::new(format!("_Segments_{}", self.instance_name)).show(ui, |ui| {
+            ui.label("");
+            ui.label("level");
+            ui.label("time");
+            ui.end_row();
+            for (i, segment) in shape.segments.iter_mut().enumerate() {
+                ui.label(format!("{}", i + 1));
+                ui.add(egui::widgets::DragValue::new(&mut segment.level).speed(0.01));
+                ui.add(
+                    egui::widgets::DragValue::new(&mut segment.time)
+                        .speed(0.01)
+                        .clamp_range(0.0..=60.0)
+                        .suffix(" s"),
+                );
+                ui.checkbox(&mut segment.exponential, "exp");
+                let mut sustain = shape.sustain == Some(i);
+                if ui.checkbox(&mut sustain, "sustain").changed() {
+                    shape.sustain = if sustain { Some(i) } else { None };
+                }
+                if n > 1 && ui.button("Remove").clicked() {
+                    remove = Some(i);
+                }
+                ui.end_row();
+            }
+        });
+        if n < MAX_SEGMENTS && ui.button("Add segment").clicked() {
+            shape.segments.push(Segment {
+                level: 0.0,
+                time: 0.1,
+                exponential: false,
+            });
+        }
+        if let Some(i) = remove {
+            shape.segments.remove(i);
+            shape.sustain = match shape.sustain {
+                Some(s) if s == i => None,
+                Some(s) if s > i => Some(s - 1),
+                sustain => sustain,
+            };
+        }
+        if shape != self.shape {
+            if let Err(e) = self.set_shape(shape) {
+                ui.colored_label(egui::Color32::RED, e);
+            }
+        }
+    }
+}
+
+impl Gadget for MultiSegmentGadget {
+    fn config(&self) -> Option<serde_json::Value> {
+        serde_json::to_value(&self.shape).ok()
+    }
+    fn set_config(&mut self, config: &serde_json::Value) -> Result<(), String> {
+        let shape = serde_json::from_value(config.clone()).map_err(|e| e.to_string())?;
+        self.set_shape(shape)
+    }
+    fn prepare(&mut self, sample_rate: u32) {
+        self.dt = 1.0 / sample_rate as f32;
+        self.envelope = Envelope::default();
+    }
+    fn run(&mut self, s: &mut Signals) {
+        let legato = s[&self.mode].round() as i32 == 1;
+        let value = self.envelope.step(
+            &self.shape.segments,
+            self.shape.sustain,
+            s[&self.gate],
+            s[&self.trigger],
+            legato,
+            self.dt,
+        );
+        s[&self.out] = s[&self.gain] * value;
+    }
+}
+
+impl GadgetUI for MultiSegmentGadget {
+    fn gui(&mut self, state: &mut GuiState, ui: &mut Ui) {
+        let name = self.instance_name.clone();
+        ui.collapsing(&name, |ui| {
+            parameter_grid(self, state, ui);
+            ui.collapsing("Segments", |ui| self.shape_gui(ui));
+        });
+    }
+}
+
+impl GadgetWithUI for MultiSegmentGadget {}
@@ -0,0 +1,468 @@
+//! Nonlinear excitation gadgets, coupled to a resonator by links: they read the motion
+//! of the resonator at the contact point and return the force (or flow) acting on it.
+//! The links form a feedback loop, which the scheduler delays by one sample.
+
+use crate::gadget::*;
+use crate::integrators::LinearPropagator;
+use crate::registry::*;
+use crate::waveguides::Bore;
+use physynth_derive::GadgetParameters;
+use std::f32::consts::PI;
+
+pub fn register(registry: &mut GadgetRegistry) {
+    registry.register(GadgetType {
+        id: "BOW",
+        display_name: "Bow",
+        category: "Exciters",
+        description:
+            "Bow friction producing stick-slip oscillation of a linked string or resonator",
+        prefix: "Bow",
+        constructor: |name| Box::new(BowGadget::new(name)),
+    });
+    registry.register(GadgetType {
+        id: "CONTACT",
+        display_name: "Contact",
+        category: "Exciters",
+        description: "Hunt-Crossley collision force between two linked bodies",
+        prefix: "Contact",
+        constructor: |name| Box::new(ContactGadget::new(name)),
+    });
+    registry.register(GadgetType {
+        id: "HAMMER",
+        display_name: "Hammer / mallet",
+        category: "Exciters",
+        description: "Mass thrown at a linked resonator through a nonlinear felt spring",
+        prefix: "Hammer",
+        constructor: |name| Box::new(HammerGadget::new(name)),
+    });
+    registry.register(GadgetType {
+        id: "REED",
+        display_name: "Reed / lips",
+        category: "Exciters",
+        description: "Reed or lip valve whose flow, driven by the mouth pressure, feeds a linked bore or resonator",
+        prefix: "Reed",
+        constructor: |name| Box::new(ReedGadget::new(name)),
+    });
+    registry.register(GadgetType {
+        id: "WIND",
+        display_name: "Reed + tube",
+        category: "Winds",
+        description: "Reed or lip valve coupled to a waveguide tube, a complete wind instrument",
+        prefix: "Wind",
+        constructor: |name| Box::new(WindGadget::new(name)),
+    });
+}
+
+/// Names of the friction curves, in the order of their parameter values.
+pub const BOW_CURVES: &[&str] = &["Soft", "Exponential", "Hyperbolic"];
+
+/// Friction coefficient for the relative velocity `slip`, `width` is the characteristic velocity of the curve.
+/// All curves are odd with a maximum of 1 near `slip = width` (or at 0 for the static friction ones).
+pub fn friction(curve: f32, slip: f32, width: f32) -> f32 {
+    let eta = slip / width.max(1e-6);
+    match curve.round() as i32 {
+        // Smooth curve sqrt(2a) v exp(-a v^2 + 1/2), well behaved with explicit coupling.
+        0 => eta * (0.5 - 0.5 * eta * eta).exp(),
+        // Static friction 1 falling exponentially to the dynamic friction 0.4.
+        1 => (10.0 * eta).tanh() * (0.4 + 0.6 * (-eta.abs()).exp()),
+        // Static friction 1 falling hyperbolically to 0.4.
+        _ => (10.0 * eta).tanh() * (0.4 + 0.6 / (1.0 + eta.abs())),
+    }
+}
+
+/// Bow with `velocity` (m/s) pressed with `pressure` (N) on a string or resonator.
+///
+/// Link `v` to the velocity of the resonator at the bowing point and the force input of the
+/// resonator to `force`: `v1`/`F1` of a mass-spring network, `v`/`force` of an oscillator or
+/// `velocity`/`force` of a string. The friction force is `-pressure * friction(v - velocity)`,
+/// `slip` is the relative velocity. Above a threshold of velocity and pressure the resonator
+/// starts a self-sustained stick-slip oscillation.
+///
+/// The linked velocity lags the force by one sample, which makes the steep curves chatter.
+/// With `admittance` set to the velocity response of the resonator to a force in the same
+/// sample (1 / (2 `impedance`) for a string, whose `velocity` does not include the new force),
+/// the slip is solved together with the force, staying on the sticking branch while it exists.
+#[derive(Clone, GadgetParameters)]
+#[gadget(name = "BOW")]
+pub struct BowGadget {
+    #[param(default = 0.2, range = -2.0..=2.0, units = "m/s")]
+    velocity: Parameter,
+    #[param(default = 1.0, range = 0.0..=20.0, units = "N")]
+    pressure: Parameter,
+    #[param(default = 0.05, range = 0.001..=1.0, units = "m/s")]
+    width: Parameter,
+    #[param(choices = BOW_CURVES)]
+    curve: Parameter,
+    #[param(units = "m/s")]
+    v: Parameter,
+    #[param(output, units = "N")]
+    force: Parameter,
+    #[param(output, units = "m/s")]
+    slip: Parameter,
+    #[param(range = 0.0..=10.0, units = "m/(N s)")]
+    admittance: Parameter,
+    instance_name: String,
+}
+
+impl BowGadget {
+    /// Solves `slip + a * friction(slip) = free` with `a` = admittance * pressure by Newton steps
+    /// from the last slip, falling back to bisection. The root lies within `a` of `free`.
+    fn solve(&self, free: f32, a: f32, curve: f32, width: f32, last: f32) -> f32 {
+        let g = |slip: f32| slip + a * friction(curve, slip, width) - free;
+        let (mut lo, mut hi) = (free - a, free + a);
+        let tolerance = 1e-6 * width;
+        let mut slip = last.clamp(lo, hi);
+        for _ in 0..50 {
+            let value = g(slip);
+            if value == 0.0 || hi - lo < tolerance {
+                break;
+            }
+            if value < 0.0 {
+                lo = slip;
+            } else {
+                hi = slip;
+            }
+            let h = 1e-3 * width;
+            let slope = (g(slip + h) - g(slip - h)) / (2.0 * h);
+            let next = slip - value / slope;
+            if (next - slip).abs() < tolerance {
+                slip = next.clamp(lo, hi);
+                break;
+            }
+            slip = if next > lo && next < hi {
+                next
+            } else {
+                0.5 * (lo + hi)
+            };
+        }
+        slip
+    }
+}
+
+impl Gadget for BowGadget {
+    #[inline]
+    fn run(&mut self, s: &mut Signals) {
+        let (curve, width, pressure) = (s[&self.curve], s[&self.width], s[&self.pressure]);
+        let free = s[&self.v] - s[&self.velocity];
+        let a = s[&self.admittance].max(0.0) * pressure;
+        let slip = if a > 0.0 {
+            self.solve(free, a, curve, width, s[&self.slip])
+        } else {
+            free
+        };
+        s[&self.force] = -pressure * friction(curve, slip, width);
+        s[&self.slip] = slip;
+    }
+}
+
+/// Hunt-Crossley contact force `stiffness * depth^exponent * (1 + damping * rate)` for the
+/// penetration `depth` and its rate `rate`; zero when the bodies are apart or separating
+/// faster than the damping allows (the bodies never stick).
+#[inline]
+pub fn contact_force(depth: f32, rate: f32, stiffness: f32, exponent: f32, damping: f32) -> f32 {
+    if depth <= 0.0 {
+        return 0.0;
+    }
+    (stiffness * depth.powf(exponent) * (1.0 + damping * rate)).max(0.0)
+}
+
+/// Unilateral contact between two bodies with displacements `x1`, `x2` and velocities `v1`, `v2`.
+///
+/// The bodies touch when `x1 - x2` exceeds `gap`; the penetration `depth` produces the force
+/// [`contact_force`] pushing them apart, with the rate of penetration `v1 - v2`. The force is
+/// returned as `f1` (acting on the first body, negative) and `f2 = -f1`. Positions are in m,
+/// velocities in m/s and forces in N: for damped oscillators link `x1`/`v1` to `x`/`v` of the
+/// first one and its `force` to `f1`, likewise for the second one. Leave `x2` and `v2` at 0 for
+/// a fixed obstacle.
+#[derive(Clone, GadgetParameters)]
+#[gadget(name = "CONTACT")]
+pub struct ContactGadget {
+    #[param(default = 100000.0, range = 0.0..=10000000.0)]
+    stiffness: Parameter,
+    #[param(default = 1.5, range = 1.0..=4.0)]
+    exponent: Parameter,
+    #[param(default = 0.5, range = 0.0..=10.0, units = "s/m")]
+    damping: Parameter,
+    gap: Parameter,
+    x1: Parameter,
+    v1: Parameter,
+    x2: Parameter,
+    v2: Parameter,
+    #[param(output)]
+    f1: Parameter,
+    #[param(output)]
+    f2: Parameter,
+    #[param(output)]
+    depth: Parameter,
+    last_depth: f32,
+    instance_name: String,
+}
+
+impl Gadget for ContactGadget {
+    #[inline]
+    fn run(&mut self, s: &mut Signals) {
+        let depth = s[&self.x1] - s[&self.x2] - s[&self.gap];
+        let rate = s[&self.v1] - s[&self.v2];
+        // The linked bodies see the force one sample late, evaluating it at the penetration
+        // extrapolated one sample ahead limits the energy stiff collisions gain from the delay.
+        let ahead = if self.last_depth > 0.0 {
+            2.0 * depth - self.last_depth
+        } else {
+            depth
+        };
+        self.last_depth = depth;
+        let force = contact_force(
+            ahead,
+            rate,
+            s[&self.stiffness],
+            s[&self.exponent],
+            s[&self.damping],
+        );
+        s[&self.f1] = -force;
+        s[&self.f2] = force;
+        s[&self.depth] = depth.max(0.0);
+    }
+}
+
+/// Hammer or mallet: a mass thrown at a resonator, compressing a nonlinear felt when they touch.
+///
+/// A positive edge on `trigger` (e.g. the voice trigger, set to the note velocity) launches the
+/// hammer from the surface of the resonator at `trigger * velocity` m/s. The felt force is
+/// [`contact_force`] of the compression `hammer - x` with `stiffness`, `exponent` and `hysteresis`,
+/// it is returned as `force` and decelerates the hammer of `mass` kg; the hammer can bounce on the
+/// resonator several times before it flies away. Link `x` to the displacement of the resonator at
+/// the striking point and `force` to its force input (`force` of an oscillator, `F1` of a
+/// mass-spring network, `inp` of a modal bank). Leave `x` at 0 to strike a rigid surface.
+#[derive(Clone, GadgetParameters)]
+#[gadget(name = "HAMMER")]
+pub struct HammerGadget {
+    trigger: Parameter,
+    #[param(default = 3.0, range = 0.0..=10.0, units = "m/s")]
+    velocity: Parameter,
+    #[param(default = 0.01, range = 0.001..=1.0, units = "kg")]
+    mass: Parameter,
+    #[param(default = 10000000.0, range = 1000.0..=1000000000.0)]
+    stiffness: Parameter,
+    #[param(default = 2.5, range = 1.0..=4.0)]
+    exponent: Parameter,
+    #[param(default = 0.1, range = 0.0..=10.0, units = "s/m")]
+    hysteresis: Parameter,
+    #[param(units = "m")]
+    x: Parameter,
+    #[param(output, units = "N")]
+    force: Parameter,
+    #[param(output, units = "m")]
+    compression: Parameter,
+    #[init(1.0 / DEFAULT_SAMPLE_RATE as f32)]
+    dt: f32,
+    /// Position and velocity of the hammer, None while it rests.
+    hammer: Option<(f32, f32)>,
+    last_x: f32,
+    last_trigger: f32,
+    instance_name: String,
+}
+
+/// Distance from the resonator (in m) at which a hammer flying away is caught.
+const HAMMER_CATCH: f32 = 0.01;
+
+impl Gadget for HammerGadget {
+    fn prepare(&mut self, sample_rate: u32) {
+        self.dt = 1.0 / sample_rate as f32;
+        self.hammer = None;
+    }
+    fn run(&mut self, s: &mut Signals) {
+        let x = s[&self.x];
+        let trigger = s[&self.trigger];
+        if trigger > 0.0 && self.last_trigger <= 0.0 {
+            self.hammer = Some((x, trigger * s[&self.velocity]));
+        }
+        self.last_trigger = trigger;
+        let (force, compression) = match self.hammer {
+            Some((position, speed)) => {
+                let compression = position - x;
+                let rate = speed - (x - self.last_x) / self.dt;
+                let force = contact_force(
+                    compression,
+                    rate,
+                    s[&self.stiffness],
+                    s[&self.exponent],
+                    s[&self.hysteresis],
+                );
+                // Symplectic Euler: the felt decelerates the hammer, then it moves.
+                let speed = speed - force / s[&self.mass].max(1e-6) * self.dt;
+                let position = position + speed * self.dt;
+                self.hammer = if compression < -HAMMER_CATCH && speed < 0.0 {
+                    None
+                } else {
+                    Some((position, speed))
+                };
+                (force, compression.max(0.0))
+            }
+            None => (0.0, 0.0),
+        };
+        self.last_x = x;
+        s[&self.force] = force;
+        s[&self.compression] = compression;
+    }
+}
+
+/// Names of the valve types, in the order of their parameter values.
+pub const VALVE_TYPES: &[&str] = &["Reed", "Lips"];
+
+/// Reed (closed by the mouth pressure) or lips (opened by it), moving like a damped oscillator.
+///
+/// Pressures are relative to the closing pressure of the valve and flows are in units where
+/// the characteristic impedance of the bore is 1. The opening at rest is `embouchure`, a pressure
+/// difference of `stiffness * embouchure` closes a reed.
+#[derive(Debug, Clone, Default)]
+struct Valve {
+    /// Displacement and velocity of the valve.
+    z: [f32; 2],
+    propagator: LinearPropagator,
+}
+
+impl Valve {
+    /// Moves the valve under the pressure difference `dp` (mouth - bore), returns the opening.
+    #[inline]
+    fn step(
+        &mut self,
+        valve: f32,
+        dp: f32,
+        embouchure: f32,
+        stiffness: f32,
+        frequency: f32,
+        dt: f32,
+    ) -> f32 {
+        let lips = valve.round() as i32 == 1;
+        let omega = 2.0 * PI * frequency.max(1.0);
+        let (sign, damping) = if lips { (1.0, 0.1) } else { (-1.0, 0.3) };
+        let a = [[0.0, 1.0], [-omega * omega, -2.0 * damping * omega]];
+        let b = [0.0, sign * omega * omega * dp / stiffness.max(1e-3)];
+        self.propagator.step(a, b, &mut self.z, dt);
+        (embouchure + self.z[0]).max(0.0)
+    }
+}
+
+/// Bernoulli flow through the opening `h` for the pressure difference `dp`.
+#[inline]
+pub fn valve_flow(aperture: f32, h: f32, dp: f32) -> f32 {
+    aperture * h * dp.abs().sqrt() * dp.signum()
+}
+
+/// Reed or lip valve exciting a bore or resonator linked to `bore` and `flow`.
+///
+/// The valve reads the pressure `bore` at the mouthpiece and returns the `flow` into it,
+/// e.g. to `flow` and from `pressure` of a tube, or to `ys` and from `x` of a damped oscillator.
+/// `frequency` is the resonance of the reed or lips, `aperture` scales the flow.
+#[derive(Clone, GadgetParameters)]
+#[gadget(name = "REED")]
+pub struct ReedGadget {
+    #[param(choices = VALVE_TYPES)]
+    valve: Parameter,
+    #[param(default = 0.5, range = 0.0..=2.0)]
+    pressure: Parameter,
+    #[param(default = 1.0, range = 0.0..=1.0)]
+    embouchure: Parameter,
+    #[param(default = 1.0, range = 0.01..=10.0)]
+    stiffness: Parameter,
+    #[param(default = 2000.0, range = 20.0..=5000.0, units = "Hz")]
+    frequency: Parameter,
+    #[param(default = 0.4, range = 0.0..=2.0)]
+    aperture: Parameter,
+    bore: Parameter,
+    #[param(output)]
+    flow: Parameter,
+    #[param(output)]
+    opening: Parameter,
+    #[init(1.0 / DEFAULT_SAMPLE_RATE as f32)]
+    dt: f32,
+    valve_state: Valve,
+    instance_name: String,
+}
+
+impl Gadget for ReedGadget {
+    fn prepare(&mut self, sample_rate: u32) {
+        self.dt = 1.0 / sample_rate as f32;
+    }
+    #[inline]
+    fn run(&mut self, s: &mut Signals) {
+        let dp = s[&self.pressure] - s[&self.bore];
+        let h = self.valve_state.step(
+            s[&self.valve],
+            dp,
+            s[&self.embouchure],
+            s[&self.stiffness],
+            s[&self.frequency],
+            self.dt,
+        );
+        s[&self.flow] = valve_flow(s[&self.aperture], h, dp);
+        s[&self.opening] = h;
+    }
+}
+
+/// Reed or lips coupled to a waveguide tube without the delay of linked gadgets.
+///
+/// The flow is solved together with the pressure at the mouthpiece in every sample.
+/// `out` is the pressure radiated by the tube, `mouthpiece` the pressure inside the mouthpiece.
+#[derive(Clone, GadgetParameters)]
+#[gadget(name = "WIND")]
+pub struct WindGadget {
+    #[param(choices = VALVE_TYPES)]
+    valve: Parameter,
+    #[param(default = 0.5, range = 0.0..=2.0)]
+    pressure: Parameter,
+    #[param(default = 1.0, range = 0.0..=1.0)]
+    embouchure: Parameter,
+    #[param(default = 1.0, range = 0.01..=10.0)]
+    stiffness: Parameter,
+    #[param(default = 2000.0, range = 20.0..=5000.0, units = "Hz")]
+    valve_frequency: Parameter,
+    #[param(default = 0.4, range = 0.0..=2.0)]
+    aperture: Parameter,
+    #[param(default = 147.0, range = 20.0..=1500.0, units = "Hz")]
+    frequency: Parameter,
+    #[param(default = 0.5, range = 0.01..=10.0, units = "s")]
+    decay: Parameter,
+    #[param(default = 0.5, range = 0.0..=1.0)]
+    brightness: Parameter,
+    #[param(output)]
+    out: Parameter,
+    #[param(output)]
+    mouthpiece: Parameter,
+    #[init(DEFAULT_SAMPLE_RATE)]
+    sample_rate: u32,
+    valve_state: Valve,
+    bore: Bore,
+    instance_name: String,
+}
+
+impl Gadget for WindGadget {
+    fn prepare(&mut self, sample_rate: u32) {
+        self.sample_rate = sample_rate;
+        self.bore.prepare(sample_rate);
+    }
+    fn run(&mut self, s: &mut Signals) {
+        let (frequency, decay, brightness) =
+            (s[&self.frequency], s[&self.decay], s[&self.brightness]);
+        self.bore
+            .update(self.sample_rate, frequency, decay, brightness);
+        let (incoming, radiated) = self.bore.reflect();
+        let mouth = s[&self.pressure];
+        // The valve moves with the pressure difference of the previous sample.
+        let h = self.valve_state.step(
+            s[&self.valve],
+            mouth - s[&self.mouthpiece],
+            s[&self.embouchure],
+            s[&self.stiffness],
+            s[&self.valve_frequency],
+            1.0 / self.sample_rate as f32,
+        );
+        // u = g sign(d - u) sqrt(|d - u|) with the bore pressure u + 2 p-, solved for u.
+        let d = mouth - 2.0 * incoming;
+        let g2 = (s[&self.aperture] * h).powi(2);
+        let flow = 0.5 * ((g2 * g2 + 4.0 * g2 * d.abs()).sqrt() - g2) * d.signum();
+        self.bore.push(flow + incoming);
+        s[&self.mouthpiece] = flow + 2.0 * incoming;
+        s[&self.out] = radiated;
+    }
+}
@@ -0,0 +1,267 @@
+//! Audio filters with cutoff and resonance that can be modulated every sample.
+//!
+//! All filters are built from trapezoidal (zero delay feedback) integrators, whose state stays
+//! meaningful when the coefficients change, so fast sweeps neither click nor blow up.
+
+use crate::gadget::*;
+use crate::registry::*;
+use physynth_derive::GadgetParameters;
+use std::f32::consts::PI;
+
+pub fn register(registry: &mut GadgetRegistry) {
+    registry.register(GadgetType {
+        id: "SVF",
+        display_name: "State variable filter",
+        category: "Filters",
+        description: "Resonant 12 dB/oct filter with lowpass, highpass, bandpass and notch outputs",
+        prefix: "SVF",
+        constructor: |name| Box::new(SvfGadget::new(name)),
+    });
+    registry.register(GadgetType {
+        id: "BIQ",
+        display_name: "Biquad",
+        category: "Filters",
+        description: "Biquad filter with the responses of the RBJ audio EQ cookbook",
+        prefix: "BQ",
+        constructor: |name| Box::new(BiquadGadget::new(name)),
+    });
+    registry.register(GadgetType {
+        id: "LADDER",
+        display_name: "Ladder filter",
+        category: "Filters",
+        description: "Nonlinear 24 dB/oct lowpass ladder with saturating feedback",
+        prefix: "Ladder",
+        constructor: |name| Box::new(LadderGadget::new(name)),
+    });
+}
+
+/// Names of the biquad responses, in the order of their parameter values.
+pub const BIQUAD_TYPES: &[&str] = &[
+    "Lowpass",
+    "Highpass",
+    "Bandpass",
+    "Notch",
+    "Allpass",
+    "Peaking",
+    "Low shelf",
+    "High shelf",
+];
+
+/// Prewarped integrator gain tan(pi f / sample rate), the cutoff is kept below Nyquist.
+#[inline]
+fn prewarp(cutoff: f32, dt: f32) -> f32 {
+    (PI * (cutoff * dt).clamp(1e-5, 0.49)).tan()
+}
+
+/// Trapezoidal state variable filter (A. Simper, Cytomic).
+#[derive(Debug, Clone, Default)]
+struct Svf {
+    k: f32,
+    a1: f32,
+    a2: f32,
+    a3: f32,
+    ic1eq: f32,
+    ic2eq: f32,
+}
+
+impl Svf {
+    /// Sets the integrator gain `g` and the damping `k` = 1/Q.
+    fn set(&mut self, g: f32, k: f32) {
+        self.k = k;
+        self.a1 = 1.0 / (1.0 + g * (g + k));
+        self.a2 = g * self.a1;
+        self.a3 = g * self.a2;
+    }
+
+    /// Returns the bandpass and lowpass outputs v1, v2.
+    #[inline]
+    fn tick(&mut self, v0: f32) -> (f32, f32) {
+        let v3 = v0 - self.ic2eq;
+        let v1 = self.a1 * self.ic1eq + self.a2 * v3;
+        let v2 = self.ic2eq + self.a2 * self.ic1eq + self.a3 * v3;
+        self.ic1eq = 2.0 * v1 - self.ic1eq;
+        self.ic2eq = 2.0 * v2 - self.ic2eq;
+        (v1, v2)
+    }
+}
+
+/// State variable filter. `resonance` from 0 to 1 raises Q from 0.5 to 50.
+///
+/// The bandpass has unity gain at the cutoff, so `lp` + `bp` + `hp` is the input
+/// and `notch` is `lp` + `hp`.
+#[derive(Clone, GadgetParameters)]
+#[gadget(name = "SVF")]
+pub struct SvfGadget {
+    inp: Parameter,
+    #[param(default = 1000.0, range = 20.0..=20000.0, units = "Hz")]
+    cutoff: Parameter,
+    #[param(range = 0.0..=1.0)]
+    resonance: Parameter,
+    #[param(output)]
+    lp: Parameter,
+    #[param(output)]
+    hp: Parameter,
+    #[param(output)]
+    bp: Parameter,
+    #[param(output)]
+    notch: Parameter,
+    #[init(1.0 / DEFAULT_SAMPLE_RATE as f32)]
+    dt: f32,
+    settings: [f32; 2],
+    svf: Svf,
+    instance_name: String,
+}
+
+impl Gadget for SvfGadget {
+    fn prepare(&mut self, sample_rate: u32) {
+        self.dt = 1.0 / sample_rate as f32;
+        self.settings = [-1.0; 2];
+        self.svf = Svf::default();
+    }
+    fn run(&mut self, s: &mut Signals) {
+        let settings = [s[&self.cutoff], s[&self.resonance]];
+        if settings != self.settings {
+            self.settings = settings;
+            let k = 2.0 - 1.98 * settings[1].clamp(0.0, 1.0);
+            self.svf.set(prewarp(settings[0], self.dt), k);
+        }
+        let v0 = s[&self.inp];
+        let (v1, v2) = self.svf.tick(v0);
+        let bp = self.svf.k * v1;
+        s[&self.lp] = v2;
+        s[&self.bp] = bp;
+        s[&self.hp] = v0 - bp - v2;
+        s[&self.notch] = v0 - bp;
+    }
+}
+
+/// Biquad with the RBJ cookbook responses, realised as a state variable filter with mixed
+/// outputs: same frequency response, but stable under modulation. `gain` is used by the
+/// peaking and shelving types.
+#[derive(Clone, GadgetParameters)]
+#[gadget(name = "BIQ")]
+pub struct BiquadGadget {
+    inp: Parameter,
+    #[param(choices = BIQUAD_TYPES)]
+    response: Parameter,
+    #[param(default = 1000.0, range = 20.0..=20000.0, units = "Hz")]
+    cutoff: Parameter,
+    #[param(default = 0.707, range = 0.1..=20.0)]
+    q: Parameter,
+    #[param(range = -24.0..=24.0, units = "dB")]
+    gain: Parameter,
+    #[param(output)]
+    out: Parameter,
+    #[init(1.0 / DEFAULT_SAMPLE_RATE as f32)]
+    dt: f32,
+    settings: [f32; 4],
+    /// Weights of the input, bandpass and lowpass in the output.
+    mix: [f32; 3],
+    svf: Svf,
+    instance_name: String,
+}
+
+impl BiquadGadget {
+    fn update(&mut self) {
+        let [response, cutoff, q, gain] = self.settings;
+        let g = prewarp(cutoff, self.dt);
+        let k = 1.0 / q.max(0.05);
+        let a = 10f32.powf(gain / 40.0);
+        let (g, k, mix) = match response.round() as i32 {
+            1 => (g, k, [1.0, -k, -1.0]),
+            2 => (g, k, [0.0, k, 0.0]),
+            3 => (g, k, [1.0, -k, 0.0]),
+            4 => (g, k, [1.0, -2.0 * k, 0.0]),
+            5 => {
+                let k = k / a;
+                (g, k, [1.0, k * (a * a - 1.0), 0.0])
+            }
+            6 => (g / a.sqrt(), k, [1.0, k * (a - 1.0), a * a - 1.0]),
+            7 => (g * a.sqrt(), k, [a * a, k * (1.0 - a) * a, 1.0 - a * a]),
+            _ => (g, k, [0.0, 0.0, 1.0]),
+        };
+        self.svf.set(g, k);
+        self.mix = mix;
+    }
+}
+
+impl Gadget for BiquadGadget {
+    fn prepare(&mut self, sample_rate: u32) {
+        self.dt = 1.0 / sample_rate as f32;
+        self.settings = [-1.0; 4];
+        self.svf = Svf::default();
+    }
+    fn run(&mut self, s: &mut Signals) {
+        let settings = [
+            s[&self.response],
+            s[&self.cutoff],
+            s[&self.q],
+            s[&self.gain],
+        ];
+        if settings != self.settings {
+            self.settings = settings;
+            self.update();
+        }
+        let v0 = s[&self.inp];
+        let (v1, v2) = self.svf.tick(v0);
+        s[&self.out] = self.mix[0] * v0 + self.mix[1] * v1 + self.mix[2] * v2;
+    }
+}
+
+/// Ladder lowpass of four trapezoidal one pole stages with the feedback solved without delay
+/// and saturated by tanh. It self-oscillates at the cutoff for `resonance` near 1.
+/// The input is multiplied by `drive` before the saturation and the output divided by it.
+#[derive(Clone, GadgetParameters)]
+#[gadget(name = "LADDER")]
+pub struct LadderGadget {
+    inp: Parameter,
+    #[param(default = 1000.0, range = 20.0..=20000.0, units = "Hz")]
+    cutoff: Parameter,
+    #[param(range = 0.0..=1.1)]
+    resonance: Parameter,
+    #[param(default = 1.0, range = 0.1..=10.0)]
+    drive: Parameter,
+    #[param(output)]
+    out: Parameter,
+    #[init(1.0 / DEFAULT_SAMPLE_RATE as f32)]
+    dt: f32,
+    cutoff_value: f32,
+    /// Gain G = g / (1 + g) of the one pole stages.
+    gain: f32,
+    stages: [f32; 4],
+    instance_name: String,
+}
+
+impl Gadget for LadderGadget {
+    fn prepare(&mut self, sample_rate: u32) {
+        self.dt = 1.0 / sample_rate as f32;
+        self.cutoff_value = -1.0;
+        self.stages = [0.0; 4];
+    }
+    fn run(&mut self, s: &mut Signals) {
+        let cutoff = s[&self.cutoff];
+        if cutoff != self.cutoff_value {
+            self.cutoff_value = cutoff;
+            let g = prewarp(cutoff, self.dt);
+            self.gain = g / (1.0 + g);
+        }
+        let gain = self.gain;
+        let k = 4.0 * s[&self.resonance].max(0.0);
+        let drive = s[&self.drive].max(0.01);
+        // The output is G^4 u + sum, sum collects the contributions of the stage states.
+        let sum = self
+            .stages
+            .iter()
+            .fold(0.0, |sum, state| gain * sum + (1.0 - gain) * state);
+        let g4 = gain * gain * gain * gain;
+        let x = drive * s[&self.inp];
+        let linear = (x - k * sum) / (1.0 + k * g4);
+        let mut y = (x - k * (g4 * linear + sum)).tanh();
+        for state in self.stages.iter_mut() {
+            let v = (y - *state) * gain;
+            y = v + *state;
+            *state = y + v;
+        }
+        s[&self.out] = y / drive;
+    }
+}
@@ -1,8 +1,12 @@
-use std::ops::{Deref, DerefMut};
+use crate::midi::MidiBinding;
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use std::ops::{Index, IndexMut};
 use egui::{Ui};
+use std::sync::Mutex;
 
-pub const SAMPLERATE: u32 = 48000;
-pub const DT: f32 = 1.0 / (SAMPLERATE as f32);
+/// Sample rate used by engines and gadgets unless configured otherwise.
+pub const DEFAULT_SAMPLE_RATE: u32 = 48000;
 
 
 #[derive(Debug, Clone)]
@@ -11,22 +15,82 @@ pub enum Link {
     Link(String),
 }
 
+/// Error raised when a parameter is accessed before it was bound to a slot in [`Signals`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct UnboundParameter(pub &'static str);
+
+impl fmt::Display for UnboundParameter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Parameter {} is not bound to a signal slot (was Engine::bind called?)",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for UnboundParameter {}
+
+/// Static parameter name for gadgets with a configurable set of parameters.
+/// Every distinct name is leaked once and reused afterwards.
+pub fn intern(name: &str) -> &'static str {
+    static NAMES: Mutex<BTreeSet<&'static str>> = Mutex::new(BTreeSet::new());
+    let mut names = NAMES.lock().unwrap_or_else(|e| e.into_inner());
+    match names.get(name) {
+        Some(&interned) => interned,
+        None => {
+            let interned: &'static str = Box::leak(name.to_owned().into_boxed_str());
+            names.insert(interned);
+            interned
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct Parameter {
-    pub value: *mut f32,
+    pub slot: Option<usize>,
     pub name: &'static str,
     pub link: Link,
+    /// Range offered by the GUI, values outside can still be linked in.
+    pub range: Option<(f32, f32)>,
+    pub units: &'static str,
+    /// Written by the gadget (as opposed to only read); decides the evaluation order of linked gadgets.
+    pub output: bool,
+    pub midi: Option<MidiBinding>,
+    /// Names of the options of a discrete parameter, the value is the index of the selected option.
+    pub choices: &'static [&'static str],
 }
 
-pub const ZERO: *mut f32 = std::ptr::null_mut();
-
 impl Parameter {
     pub fn new(name: &'static str, value:f32) -> Parameter {
         Parameter {
-            value: ZERO,
-            name: name,
+            slot: None,
+            name,
             link: Link::Value(value),
+            range: None,
+            units: "",
+            output: false,
+            midi: None,
+            choices: &[],
         }
     }
+    pub fn with_range(mut self, min: f32, max: f32) -> Parameter {
+        self.range = Some((min, max));
+        self
+    }
+    pub fn with_units(mut self, units: &'static str) -> Parameter {
+        self.units = units;
+        self
+    }
+    pub fn with_choices(mut self, choices: &'static [&'static str]) -> Parameter {
+        self.choices = choices;
+        self.range = Some((0.0, choices.len().saturating_sub(1) as f32));
+        self
+    }
+    pub fn as_output(mut self) -> Parameter {
+        self.output = true;
+        self
+    }
     pub fn set_value(&mut self, value: f32) {
         self.link = Link::Value(value)
     }
@@ -34,8 +98,7 @@ impl Parameter {
         self.link = Link::Link(value.to_owned())
     }
     pub fn is_unbound(&self) -> bool {
-        //println!("value: {:?} ZERO: {:?}",self.value, ZERO);
-        std::ptr::eq(self.value, ZERO)
+        self.slot.is_none()
     }
     pub fn is_free(&self) -> bool {
         match self.link {
@@ -43,37 +106,146 @@ impl Parameter {
             Link::Link(_) => false,
         }
     }
-    pub fn bind(&mut self, buffer: *mut f32) {
-        self.value = buffer;
+    pub fn bind(&mut self, slot: usize) {
+        self.slot = Some(slot);
     }
     pub fn unbind(&mut self) {
-        self.value = ZERO;
+        self.slot = None;
+    }
+    pub fn slot(&self) -> Result<usize, UnboundParameter> {
+        self.slot.ok_or(UnboundParameter(self.name))
+    }
+}
+
+/// Signal arena owned by the engine.
+/// Every free parameter owns one slot, linked parameters share the slot of the parameter they link to.
+#[derive(Debug, Clone, Default)]
+pub struct Signals {
+    values: Vec<f32>,
+}
+
+impl Signals {
+    pub fn new() -> Self {
+        Signals { values: Vec::new() }
+    }
+    pub fn with_len(len: usize) -> Self {
+        Signals {
+            values: vec![0.0; len],
+        }
+    }
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+    /// Appends a new slot and returns its index.
+    pub fn push(&mut self, value: f32) -> usize {
+        self.values.push(value);
+        self.values.len() - 1
+    }
+    pub fn get(&self, parameter: &Parameter) -> Result<f32, UnboundParameter> {
+        Ok(self.values[parameter.slot()?])
+    }
+    pub fn set(&mut self, parameter: &Parameter, value: f32) -> Result<(), UnboundParameter> {
+        self.values[parameter.slot()?] = value;
+        Ok(())
     }
 }
 
-impl Deref for Parameter {
-    type Target = f32;
+impl Index<usize> for Signals {
+    type Output = f32;
     #[inline]
-    fn deref(&self) -> &Self::Target {
-        unsafe { &*self.value }
+    fn index(&self, slot: usize) -> &Self::Output {
+        &self.values[slot]
     }
 }
 
-impl DerefMut for Parameter {
+impl IndexMut<usize> for Signals {
     #[inline]
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        unsafe { &mut *self.value }
+    fn index_mut(&mut self, slot: usize) -> &mut Self::Output {
+        &mut self.values[slot]
     }
 }
 
-pub trait Gadget {
+impl Index<&Parameter> for Signals {
+    type Output = f32;
+    #[inline]
+    fn index(&self, parameter: &Parameter) -> &Self::Output {
+        match parameter.slot {
+            Some(slot) => &self.values[slot],
+            None => panic!("{}", UnboundParameter(parameter.name)),
+        }
+    }
+}
+
+impl IndexMut<&Parameter> for Signals {
+    #[inline]
+    fn index_mut(&mut self, parameter: &Parameter) -> &mut Self::Output {
+        match parameter.slot {
+            Some(slot) => &mut self.values[slot],
+            None => panic!("{}", UnboundParameter(parameter.name)),
+        }
+    }
+}
+
+/// Access to the parameters of a gadget, usually generated with `#[derive(GadgetParameters)]`.
+pub trait GadgetParameters {
     fn name(&self) -> &'static str;
     fn get_instance_name(&self) -> String;
     fn par(&self, i: usize) -> &Parameter;
     fn par_mut(&mut self, i: usize) -> &mut Parameter;
     fn parameter_count(&self) -> usize;
-    fn run(&mut self);
+}
+
+/// Link that closes a feedback loop and is therefore read with a delay of one sample.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Feedback {
+    /// Index of the delayed (reading) parameter.
+    pub parameter: usize,
+    pub from: String,
+    pub to: String,
+}
+
+impl fmt::Display for Feedback {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} -> {} (1 sample delay)", self.from, self.to)
+    }
+}
 
+pub trait Gadget: GadgetParameters {
+    /// Called by the engine before running, whenever the sample rate is (re)configured.
+    /// When called from `Engine::bind` the parameters are already bound to their slots.
+    fn prepare(&mut self, _sample_rate: u32) {}
+    /// Decides the evaluation order of sub-gadgets, returns the links that need a one sample delay.
+    fn schedule(&mut self) -> Vec<Feedback> {
+        Vec::new()
+    }
+    fn run(&mut self, signals: &mut Signals);
+
+    /// Settings that are not parameters (like a topology), saved with the patch.
+    fn config(&self) -> Option<serde_json::Value> {
+        None
+    }
+    /// Restores settings returned by `config`, this may change the parameters of the gadget.
+    fn set_config(&mut self, _config: &serde_json::Value) -> Result<(), String> {
+        Err(format!("{} has no settings", self.name()))
+    }
+    /// Gadgets wrapped by this gadget, their parameters come first in the parameter list.
+    fn children(&self) -> Option<&GadgetContainer> {
+        None
+    }
+    fn children_mut(&mut self) -> Option<&mut GadgetContainer> {
+        None
+    }
+    /// Parameters sent to the audio channels, provided by the output gadget of a patch.
+    fn output_channels(&self) -> Vec<String> {
+        Vec::new()
+    }
+    /// Instance names of this gadget and all gadgets it contains.
+    fn instance_names(&self) -> Vec<String> {
+        vec![self.get_instance_name()]
+    }
     fn parameter_names(&self) -> Vec<String> {
         let instance_name = self.get_instance_name();
         let mut p = Vec::with_capacity(self.parameter_count());
@@ -105,85 +277,173 @@ pub trait Gadget {
     }
 }
 
+/// GUI state shared by the parameter rows of all gadgets.
+#[derive(Debug, Clone, Default)]
+pub struct GuiState {
+    /// Parameter selected as link target.
+    pub link: Option<String>,
+    /// Parameter waiting to be bound to the next incoming MIDI controller.
+    pub learn: Option<String>,
+}
+
 pub trait GadgetUI {
-    fn gui(&mut self, link: &mut Option<String>, ui: &mut Ui);
-}
-
-pub fn gadget_gui<G: Gadget>(gadget: &mut G, link: &mut Option<String>, ui: &mut Ui) {
-    ui.collapsing(gadget.get_instance_name(), |ui| {
-        let pnames = gadget.parameter_names();
-        egui::Grid::new(format!("_Grid_{}", gadget.get_instance_name())).show(ui, |ui| {
-            //ui.colored_label(egui::Color32::LIGHT_BLUE, gadget.get_instance_name());
-            //ui.end_row();
-
-            for i in 0..gadget.parameter_count() {
-                let p = gadget.par_mut(i);
-                ui.label(p.name);
-                match p.link.clone() {
-                    Link::Link(link) => {
-                        ui.label(link);
-                        if ui.button("Unlink").clicked() {
-                            p.set_value(0.0);
-                        }
-                    }
-                    Link::Value(x) => {
-                        let mut value = x;
-                        ui.add(egui::widgets::DragValue::new(&mut value));
-                        p.link = Link::Value(value);
-                        if ui.button("Select").clicked() {
-                            *link = Some(pnames[i].to_owned());
-                        }
-                    }
-                }
-                if let Some(name) = link {
-                    if ui.button(name.to_owned()).clicked() {
-                        p.set_link(name);
+    fn gui(&mut self, state: &mut GuiState, ui: &mut Ui);
+}
+
+pub fn gadget_gui<G: Gadget>(gadget: &mut G, state: &mut GuiState, ui: &mut Ui) {
+    ui.collapsing(gadget.get_instance_name(), |ui| parameter_grid(gadget, state, ui));
+}
+
+/// Grid with one row per parameter of the gadget, for gadgets adding their own sections below.
+pub fn parameter_grid<G: Gadget + ?Sized>(gadget: &mut G, state: &mut GuiState, ui: &mut Ui) {
+    let pnames = gadget.parameter_names();
+    egui::Grid::new(format!("_Grid_{}", gadget.get_instance_name())).show(ui, |ui| {
+        for (i, pname) in pnames.iter().enumerate() {
+            parameter_row(gadget.par_mut(i), pname, state, ui);
+        }
+    });
+}
+
+/// One grid row editing a parameter; `pname` is its full name (instance: parameter).
+pub fn parameter_row(p: &mut Parameter, pname: &str, state: &mut GuiState, ui: &mut Ui) {
+    ui.label(p.name);
+    match p.link.clone() {
+        Link::Link(link) => {
+            ui.label(link);
+            if ui.button("Unlink").clicked() {
+                p.set_value(0.0);
+            }
+        }
+        Link::Value(x) if !p.choices.is_empty() => {
+            let mut choice = x.round().max(0.0) as usize;
+            egui::ComboBox::from_id_source(pname)
+                .selected_text(p.choices.get(choice).copied().unwrap_or("?"))
+                .show_ui(ui, |ui| {
+                    for (i, &name) in p.choices.iter().enumerate() {
+                        ui.selectable_value(&mut choice, i, name);
                     }
-                }
-                ui.end_row();
+                });
+            p.link = Link::Value(choice as f32);
+            if ui.button("Select").clicked() {
+                state.link = Some(pname.to_owned());
             }
-        })
-    });
+        }
+        Link::Value(x) => {
+            let mut value = x;
+            let mut drag = egui::widgets::DragValue::new(&mut value);
+            if !p.units.is_empty() {
+                drag = drag.suffix(format!(" {}", p.units));
+            }
+            if let Some((min, max)) = p.range {
+                drag = drag.clamp_range(min..=max);
+            }
+            ui.add(drag);
+            p.link = Link::Value(value);
+            if ui.button("Select").clicked() {
+                state.link = Some(pname.to_owned());
+            }
+        }
+    }
+    match p.midi {
+        Some(binding) => {
+            if ui
+                .button(binding.source.to_string())
+                .on_hover_text("Forget the MIDI binding")
+                .clicked()
+            {
+                p.midi = None;
+            }
+        }
+        None if matches!(p.link, Link::Link(_)) => {
+            ui.label("");
+        }
+        None if state.learn.as_deref() == Some(pname) => {
+            if ui.button("Learning...").clicked() {
+                state.learn = None;
+            }
+        }
+        None => {
+            if ui
+                .button("Learn")
+                .on_hover_text("Bind the next MIDI controller to this parameter")
+                .clicked()
+            {
+                state.learn = Some(pname.to_owned());
+            }
+        }
+    }
+    if let Some(name) = &state.link {
+        if ui.button(name.to_owned()).clicked() {
+            p.set_link(name);
+        }
+    }
+    ui.end_row();
+}
+
+/// Clones a gadget behind a trait object, so that a whole patch can be copied to the audio thread.
+pub trait GadgetClone {
+    fn clone_gadget(&self) -> Box<dyn GadgetWithUI>;
 }
 
-pub trait GadgetWithUI: Gadget + GadgetUI {}
+impl<T: 'static + GadgetWithUI + Clone> GadgetClone for T {
+    fn clone_gadget(&self) -> Box<dyn GadgetWithUI> {
+        Box::new(self.clone())
+    }
+}
+
+impl Clone for Box<dyn GadgetWithUI> {
+    fn clone(&self) -> Self {
+        self.clone_gadget()
+    }
+}
+
+pub trait GadgetWithUI: Gadget + GadgetUI + GadgetClone + Send {}
+#[derive(Default, Clone)]
 pub struct GadgetContainer {
     pub container: Vec<Box<dyn GadgetWithUI>>,
+    /// Evaluation order computed by `schedule`, insertion order is used while it is stale.
+    order: Vec<usize>,
 }
 impl GadgetContainer {
     pub fn new() -> Self {
         GadgetContainer {
             container: Vec::new(),
+            order: Vec::new(),
+        }
+    }
+    /// Current evaluation order of the gadgets.
+    pub fn order(&self) -> Vec<usize> {
+        if self.order.len() == self.container.len() {
+            self.order.clone()
+        } else {
+            (0..self.container.len()).collect()
         }
     }
+    /// True if a gadget with the name exists in this container or in a nested one.
+    pub fn has_instance(&self, instance_name: &str) -> bool {
+        self.container
+            .iter()
+            .any(|g| g.instance_names().iter().any(|name| name == instance_name))
+    }
 }
 impl GadgetUI for GadgetContainer {
-    fn gui(&mut self, link: &mut Option<String>, ui: &mut Ui) {
+    fn gui(&mut self, state: &mut GuiState, ui: &mut Ui) {
         egui::Grid::new("GadgetContainer").show(ui, |ui| {
             for gadget in self.container.iter_mut() {
-                gadget.gui(link, ui);
+                gadget.gui(state, ui);
                 ui.end_row();
             }
         });
     }
 }
 
-impl Gadget for GadgetContainer {
+impl GadgetParameters for GadgetContainer {
     fn name(&self) -> &'static str {
         "container"
     }
     fn get_instance_name(&self) -> String {
         self.name().to_string()
     }
-    fn parameter_names(&self) -> Vec<String> {
-        let mut p = Vec::with_capacity(self.parameter_count());
-        for g in &self.container {
-            for n in g.parameter_names() {
-                p.push(n);
-            }
-        }
-        p
-    }
     fn par(&self, i: usize) -> &Parameter {
         let mut count = 0;
         for gadget in self.container.iter() {
@@ -211,9 +471,155 @@ impl Gadget for GadgetContainer {
     fn parameter_count(&self) -> usize {
         self.container.iter().map(|x| x.parameter_count()).sum()
     }
-    fn run(&mut self) {
+}
+
+impl Gadget for GadgetContainer {
+    fn instance_names(&self) -> Vec<String> {
+        self.container
+            .iter()
+            .flat_map(|g| g.instance_names())
+            .collect()
+    }
+    fn parameter_names(&self) -> Vec<String> {
+        let mut p = Vec::with_capacity(self.parameter_count());
+        for g in &self.container {
+            for n in g.parameter_names() {
+                p.push(n);
+            }
+        }
+        p
+    }
+    fn output_channels(&self) -> Vec<String> {
+        self.container
+            .iter()
+            .map(|g| g.output_channels())
+            .find(|channels| !channels.is_empty())
+            .unwrap_or_default()
+    }
+    fn prepare(&mut self, sample_rate: u32) {
         for gadget in self.container.iter_mut() {
-            gadget.run();
+            gadget.prepare(sample_rate);
+        }
+    }
+    fn schedule(&mut self) -> Vec<Feedback> {
+        let names = self.parameter_names();
+        let index: HashMap<&str, usize> = names
+            .iter()
+            .enumerate()
+            .map(|(i, name)| (name.as_str(), i))
+            .collect();
+        let mut owner = Vec::with_capacity(names.len());
+        let mut feedback = Vec::new();
+        for (g, gadget) in self.container.iter_mut().enumerate() {
+            let offset = owner.len();
+            for mut f in gadget.schedule() {
+                f.parameter += offset;
+                feedback.push(f);
+            }
+            owner.resize(offset + gadget.parameter_count(), g);
+        }
+        let root = |mut i: usize| {
+            // Link cycles are reported by Engine::bind, here we only need to terminate.
+            for _ in 0..names.len() {
+                match self.par(i).link {
+                    Link::Link(ref target) => match index.get(target.as_str()) {
+                        Some(&j) => i = j,
+                        None => break,
+                    },
+                    Link::Value(_) => break,
+                }
+            }
+            i
+        };
+
+        // Data flows from the gadgets writing a slot to the gadgets reading it.
+        let mut groups: HashMap<usize, Vec<usize>> = HashMap::new();
+        for i in 0..names.len() {
+            groups.entry(root(i)).or_default().push(i);
+        }
+        let mut edges: Vec<(usize, usize, usize, usize)> = Vec::new();
+        for group in groups.values() {
+            for &w in group.iter().filter(|&&i| self.par(i).output) {
+                for &r in group.iter().filter(|&&i| !self.par(i).output) {
+                    if owner[w] != owner[r] {
+                        edges.push((owner[w], owner[r], w, r));
+                    }
+                }
+            }
+        }
+
+        // Kahn's algorithm, ties are broken by instance name so that the result
+        // does not depend on the order in which gadgets were added.
+        let n = self.container.len();
+        let instance_names: Vec<String> =
+            self.container.iter().map(|g| g.get_instance_name()).collect();
+        let mut active = vec![true; edges.len()];
+        let mut remaining = vec![true; n];
+        let mut order = Vec::with_capacity(n);
+        while order.len() < n {
+            let blocked = |g: usize, active: &[bool]| {
+                edges
+                    .iter()
+                    .zip(active.iter())
+                    .any(|(&(from, to, _, _), &a)| a && to == g && remaining[from])
+            };
+            let first = |candidates: &mut dyn Iterator<Item = usize>| {
+                candidates.min_by(|&a, &b| instance_names[a].cmp(&instance_names[b]))
+            };
+            let ready = first(&mut (0..n).filter(|&g| remaining[g] && !blocked(g, &active)));
+            let g = match ready {
+                Some(g) => g,
+                None => {
+                    // Only cycles and the gadgets downstream of them are left. Pick a gadget
+                    // whose remaining inputs all come from a cycle through it and delay them.
+                    let closes_cycle = |g: usize| {
+                        let mut seen = vec![false; n];
+                        let mut stack = vec![g];
+                        while let Some(h) = stack.pop() {
+                            for (&(from, to, _, _), &a) in edges.iter().zip(active.iter()) {
+                                if a && from == h && remaining[to] && !seen[to] {
+                                    seen[to] = true;
+                                    stack.push(to);
+                                }
+                            }
+                        }
+                        seen[g]
+                            && edges.iter().zip(active.iter()).all(|(&(from, to, _, _), &a)| {
+                                !(a && to == g && remaining[from]) || seen[from]
+                            })
+                    };
+                    let g = first(&mut (0..n).filter(|&g| remaining[g] && closes_cycle(g)))
+                        .expect("a blocked gadget closes a cycle");
+                    for (e, &(from, to, w, r)) in edges.iter().enumerate() {
+                        if active[e] && to == g && remaining[from] {
+                            active[e] = false;
+                            if !feedback.iter().any(|f: &Feedback| f.parameter == r) {
+                                feedback.push(Feedback {
+                                    parameter: r,
+                                    from: names[w].clone(),
+                                    to: names[r].clone(),
+                                });
+                            }
+                        }
+                    }
+                    g
+                }
+            };
+            remaining[g] = false;
+            order.push(g);
+        }
+        self.order = order;
+        feedback
+    }
+    fn run(&mut self, signals: &mut Signals) {
+        if self.order.len() == self.container.len() {
+            for &i in self.order.iter() {
+                self.container[i].run(signals);
+            }
+        } else {
+            for gadget in self.container.iter_mut() {
+                gadget.run(signals);
+            }
         }
     }
 }
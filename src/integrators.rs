@@ -0,0 +1,215 @@
+//! Numerical integrators shared by the physical gadgets.
+//!
+//! A gadget describes its equations of motion with [`Dynamics`] and keeps a [`Workspace`]
+//! sized for its state, the integrator itself is usually selected by a parameter
+//! declared with `choices = INTEGRATOR_NAMES`.
+
+/// Names of the integrators, in the order of their parameter values.
+pub const INTEGRATOR_NAMES: &[&str] = &["Symplectic Euler", "Velocity Verlet", "RK4", "Exact"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Integrator {
+    /// First order, updates positions then momenta; cheap and keeps the energy bounded.
+    SymplecticEuler,
+    /// Second order kick-drift-kick leapfrog.
+    VelocityVerlet,
+    /// Classic fourth order Runge-Kutta.
+    RungeKutta4,
+    /// Closed form update of linear gadgets, the others fall back to RK4.
+    Exact,
+}
+
+impl Integrator {
+    /// Integrator selected by a parameter value (index into [`INTEGRATOR_NAMES`]).
+    pub fn from_value(value: f32) -> Integrator {
+        match value.round() as i32 {
+            1 => Integrator::VelocityVerlet,
+            2 => Integrator::RungeKutta4,
+            3 => Integrator::Exact,
+            _ => Integrator::SymplecticEuler,
+        }
+    }
+    pub fn value(self) -> f32 {
+        self as usize as f32
+    }
+}
+
+/// Partitioned first order system dq/dt = f(q, p), dp/dt = g(q, p)
+/// with positions `q` and momenta (or velocities) `p` of the same dimension.
+pub trait Dynamics {
+    fn derivative(&self, q: &[f32], p: &[f32], dq: &mut [f32], dp: &mut [f32]);
+}
+
+/// Scratch buffers of the integrators, allocated once for a fixed dimension so that steps never allocate.
+#[derive(Debug, Clone, Default)]
+pub struct Workspace {
+    dq: Vec<f32>,
+    dp: Vec<f32>,
+    sum_q: Vec<f32>,
+    sum_p: Vec<f32>,
+    q: Vec<f32>,
+    p: Vec<f32>,
+}
+
+/// x += a * y
+#[inline]
+fn axpy(x: &mut [f32], a: f32, y: &[f32]) {
+    for (x, y) in x.iter_mut().zip(y.iter()) {
+        *x += a * y;
+    }
+}
+
+/// x = y + a * z
+#[inline]
+fn offset(x: &mut [f32], y: &[f32], a: f32, z: &[f32]) {
+    for ((x, y), z) in x.iter_mut().zip(y.iter()).zip(z.iter()) {
+        *x = y + a * z;
+    }
+}
+
+impl Workspace {
+    pub fn new(dimension: usize) -> Self {
+        Workspace {
+            dq: vec![0.0; dimension],
+            dp: vec![0.0; dimension],
+            sum_q: vec![0.0; dimension],
+            sum_p: vec![0.0; dimension],
+            q: vec![0.0; dimension],
+            p: vec![0.0; dimension],
+        }
+    }
+    pub fn dimension(&self) -> usize {
+        self.dq.len()
+    }
+
+    /// Advances the state (`q`, `p`) by `dt`.
+    pub fn step<D: Dynamics>(
+        &mut self,
+        integrator: Integrator,
+        system: &D,
+        q: &mut [f32],
+        p: &mut [f32],
+        dt: f32,
+    ) {
+        debug_assert!(q.len() == self.dimension() && p.len() == self.dimension());
+        match integrator {
+            Integrator::SymplecticEuler => {
+                system.derivative(q, p, &mut self.dq, &mut self.dp);
+                axpy(q, dt, &self.dq);
+                system.derivative(q, p, &mut self.dq, &mut self.dp);
+                axpy(p, dt, &self.dp);
+            }
+            Integrator::VelocityVerlet => {
+                system.derivative(q, p, &mut self.dq, &mut self.dp);
+                axpy(p, 0.5 * dt, &self.dp);
+                system.derivative(q, p, &mut self.dq, &mut self.dp);
+                axpy(q, dt, &self.dq);
+                system.derivative(q, p, &mut self.dq, &mut self.dp);
+                axpy(p, 0.5 * dt, &self.dp);
+            }
+            Integrator::RungeKutta4 | Integrator::Exact => {
+                system.derivative(q, p, &mut self.dq, &mut self.dp);
+                self.sum_q.copy_from_slice(&self.dq);
+                self.sum_p.copy_from_slice(&self.dp);
+                for &(h, weight) in [(0.5, 2.0), (0.5, 2.0), (1.0, 1.0)].iter() {
+                    offset(&mut self.q, q, h * dt, &self.dq);
+                    offset(&mut self.p, p, h * dt, &self.dp);
+                    system.derivative(&self.q, &self.p, &mut self.dq, &mut self.dp);
+                    axpy(&mut self.sum_q, weight, &self.dq);
+                    axpy(&mut self.sum_p, weight, &self.dp);
+                }
+                axpy(q, dt / 6.0, &self.sum_q);
+                axpy(p, dt / 6.0, &self.sum_p);
+            }
+        }
+    }
+}
+
+type Matrix2 = [[f64; 2]; 2];
+
+fn mul2(a: &Matrix2, b: &Matrix2) -> Matrix2 {
+    let mut c = [[0.0; 2]; 2];
+    for i in 0..2 {
+        for j in 0..2 {
+            c[i][j] = a[i][0] * b[0][j] + a[i][1] * b[1][j];
+        }
+    }
+    c
+}
+
+/// Exact update of the linear system dz/dt = A z + b over a time step,
+/// z(t + dt) = exp(A dt) z + (integral of exp(A s) for s from 0 to dt) b.
+/// The matrices are recomputed only when A or dt change.
+#[derive(Debug, Clone, Default)]
+pub struct LinearPropagator {
+    key: Option<([[f32; 2]; 2], f32)>,
+    exp: Matrix2,
+    integral: Matrix2,
+}
+
+impl LinearPropagator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn update(&mut self, a: [[f32; 2]; 2], dt: f32) {
+        let h = dt as f64;
+        let m = [
+            [a[0][0] as f64 * h, a[0][1] as f64 * h],
+            [a[1][0] as f64 * h, a[1][1] as f64 * h],
+        ];
+        let det = m[0][0] * m[1][1] - m[0][1] * m[1][0];
+        // M = mu I + N with N^2 = q I, so exp(M) = exp(mu) (c I + s N).
+        let mu = 0.5 * (m[0][0] + m[1][1]);
+        let q = mu * mu - det;
+        let (c, s) = if q > 1e-12 {
+            let r = q.sqrt();
+            (r.cosh(), r.sinh() / r)
+        } else if q < -1e-12 {
+            let r = (-q).sqrt();
+            (r.cos(), r.sin() / r)
+        } else {
+            (1.0, 1.0)
+        };
+        let e = mu.exp();
+        self.exp = [
+            [e * (c + s * (m[0][0] - mu)), e * s * m[0][1]],
+            [e * s * m[1][0], e * (c + s * (m[1][1] - mu))],
+        ];
+        self.integral = if det.abs() > 1e-8 {
+            // M^-1 (exp(M) - I) dt
+            let inv = [[m[1][1] / det, -m[0][1] / det], [-m[1][0] / det, m[0][0] / det]];
+            let d = [
+                [self.exp[0][0] - 1.0, self.exp[0][1]],
+                [self.exp[1][0], self.exp[1][1] - 1.0],
+            ];
+            let r = mul2(&inv, &d);
+            [[r[0][0] * h, r[0][1] * h], [r[1][0] * h, r[1][1] * h]]
+        } else {
+            // Series I + M/2! + M^2/3! + ... for (nearly) singular M.
+            let mut sum = [[1.0, 0.0], [0.0, 1.0]];
+            let mut term = [[1.0, 0.0], [0.0, 1.0]];
+            for k in 2..8 {
+                term = mul2(&term, &m);
+                for i in 0..2 {
+                    for j in 0..2 {
+                        term[i][j] /= k as f64;
+                        sum[i][j] += term[i][j];
+                    }
+                }
+            }
+            [[sum[0][0] * h, sum[0][1] * h], [sum[1][0] * h, sum[1][1] * h]]
+        };
+        self.key = Some((a, dt));
+    }
+
+    pub fn step(&mut self, a: [[f32; 2]; 2], b: [f32; 2], z: &mut [f32; 2], dt: f32) {
+        if self.key != Some((a, dt)) {
+            self.update(a, dt);
+        }
+        let (x, y) = (z[0] as f64, z[1] as f64);
+        let (bx, by) = (b[0] as f64, b[1] as f64);
+        z[0] = (self.exp[0][0] * x + self.exp[0][1] * y + self.integral[0][0] * bx + self.integral[0][1] * by) as f32;
+        z[1] = (self.exp[1][0] * x + self.exp[1][1] * y + self.integral[1][0] * bx + self.integral[1][1] * by) as f32;
+    }
+}
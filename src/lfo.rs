@@ -0,0 +1,133 @@
+use crate::gadget::*;
+use crate::registry::*;
+use physynth_derive::GadgetParameters;
+use std::f32::consts::PI;
+
+pub fn register(registry: &mut GadgetRegistry) {
+    registry.register(GadgetType {
+        id: "LFO",
+        display_name: "LFO",
+        category: "Modulation",
+        description: "Low frequency sine, triangle, saw, square or random control signal",
+        prefix: "LFO",
+        constructor: |name| Box::new(LfoGadget::new(name)),
+    });
+}
+
+/// Names of the LFO waveforms, in the order of their parameter values.
+pub const LFO_SHAPES: &[&str] = &[
+    "Sine",
+    "Triangle",
+    "Saw",
+    "Square",
+    "Sample & hold",
+    "Smooth random",
+];
+
+/// Initial state of the noise generator of the random shapes.
+const NOISE_SEED: u32 = 0x2545_f491;
+
+/// Waveform between -1 and 1 at position `p` in [0, 1) of the cycle.
+/// The periodic shapes start at 0 and rise like a sine, the square starts high.
+pub fn lfo_wave(shape: usize, p: f32) -> f32 {
+    match shape {
+        1 => 4.0 * ((p - 0.25).rem_euclid(1.0) - 0.5).abs() - 1.0,
+        2 => 2.0 * (p + 0.5).rem_euclid(1.0) - 1.0,
+        3 => {
+            if p < 0.5 {
+                1.0
+            } else {
+                -1.0
+            }
+        }
+        _ => (2.0 * PI * p).sin(),
+    }
+}
+
+/// Low frequency oscillator, `out` = `offset` + `depth` · waveform.
+///
+/// `phase` shifts the waveform in cycles. A rising edge on `sync` restarts the cycle.
+/// The random shapes draw a new value in [-1, 1] at each cycle, sample & hold jumps to it
+/// while smooth random glides there along a half cosine.
+#[derive(Clone, GadgetParameters)]
+#[gadget(name = "LFO")]
+pub struct LfoGadget {
+    #[param(choices = LFO_SHAPES)]
+    shape: Parameter,
+    #[param(default = 1.0, range = 0.0..=50.0, units = "Hz")]
+    rate: Parameter,
+    #[param(range = 0.0..=1.0)]
+    phase: Parameter,
+    #[param(default = 1.0)]
+    depth: Parameter,
+    offset: Parameter,
+    sync: Parameter,
+    #[param(output)]
+    out: Parameter,
+    #[init(1.0 / DEFAULT_SAMPLE_RATE as f32)]
+    dt: f32,
+    /// Position in the cycle without phase shift.
+    cycle: f32,
+    /// Previous position including the phase shift, to detect the start of a cycle.
+    last: f32,
+    last_sync: f32,
+    /// Previous and next values of the random shapes.
+    random: (f32, f32),
+    #[init(NOISE_SEED)]
+    noise: u32,
+    instance_name: String,
+}
+
+impl LfoGadget {
+    /// Uniform noise between -1 and 1 (xorshift).
+    fn noise(&mut self) -> f32 {
+        self.noise ^= self.noise << 13;
+        self.noise ^= self.noise >> 17;
+        self.noise ^= self.noise << 5;
+        self.noise as f32 / u32::MAX as f32 * 2.0 - 1.0
+    }
+
+    fn draw(&mut self) {
+        let next = self.noise();
+        self.random = (self.random.1, next);
+    }
+}
+
+impl Gadget for LfoGadget {
+    fn prepare(&mut self, sample_rate: u32) {
+        self.dt = 1.0 / sample_rate as f32;
+        self.cycle = 0.0;
+        self.last = 0.0;
+        self.last_sync = 0.0;
+        self.noise = NOISE_SEED;
+        self.draw();
+        self.draw();
+    }
+    fn run(&mut self, s: &mut Signals) {
+        let sync = s[&self.sync];
+        if sync > 0.0 && self.last_sync <= 0.0 {
+            self.cycle = 0.0;
+            // The restart is not a wrap of the cycle, which would draw again.
+            self.last = s[&self.phase].rem_euclid(1.0);
+            self.draw();
+        }
+        self.last_sync = sync;
+        let p = (self.cycle + s[&self.phase]).rem_euclid(1.0);
+        // Wraps in either direction, so negative rates and phase sweeps work too.
+        if (p - self.last).abs() > 0.5 {
+            self.draw();
+        }
+        self.last = p;
+        let shape = s[&self.shape]
+            .round()
+            .clamp(0.0, (LFO_SHAPES.len() - 1) as f32) as usize;
+        let (previous, next) = self.random;
+        let value = match shape {
+            4 => next,
+            5 => previous + (next - previous) * 0.5 * (1.0 - (PI * p).cos()),
+            _ => lfo_wave(shape, p),
+        };
+        s[&self.out] = s[&self.offset] + s[&self.depth] * value;
+        self.cycle = (self.cycle + s[&self.rate] * self.dt).rem_euclid(1.0);
+    }
+}
@@ -0,0 +1,23 @@
+// Lets the code generated by physynth-derive refer to `::physynth` from inside this crate as well.
+extern crate self as physynth;
+
+pub mod engine;
+pub mod envelopes;
+pub mod exciters;
+pub mod filters;
+pub mod gadget;
+pub mod integrators;
+pub mod lfo;
+pub mod mass_spring;
+pub mod mesh;
+pub mod midi;
+pub mod modal;
+pub mod oscillators;
+pub mod oversampling;
+pub mod patch;
+pub mod registry;
+pub mod render;
+pub mod stream;
+pub mod transformations;
+pub mod voices;
+pub mod waveguides;
@@ -4,17 +4,19 @@ extern crate midir;
 use std::error::Error;
 
 use midir::{Ignore, MidiInput, MidiOutput};
+use rodio::cpal::traits::{DeviceTrait, HostTrait};
 use rodio::OutputStream;
 
-pub mod engine;
-pub mod gadget;
-pub mod oscillators;
-pub mod transformations;
 use egui::plot::{Line, Plot, Value, Values};
-use engine::*;
-use gadget::*;
-use oscillators::*;
-use transformations::*;
+use physynth::engine::*;
+use physynth::gadget::*;
+use physynth::midi::*;
+use physynth::oversampling::*;
+use physynth::patch::*;
+use physynth::registry::*;
+use physynth::render::*;
+use physynth::stream::*;
+use physynth::voices::*;
 
 fn window_conf() -> Conf {
     Conf {
@@ -31,31 +33,132 @@ fn check_midi() -> Result<String, Box<dyn Error>> {
 
     let mut res = String::new();
 
-    res = res + &format!("Available input ports:\n");
+    res += "Available input ports:\n";
     for (i, p) in midi_in.ports().iter().enumerate() {
         res = res + &format!("{}: {}\n", i, midi_in.port_name(p)?);
     }
-    res = res + &format!("\nAvailable output ports:\n");
+    res += "\nAvailable output ports:\n";
     for (i, p) in midi_out.ports().iter().enumerate() {
         res = res + &format!("{}: {}\n", i, midi_out.port_name(p)?);
     }
     Ok(res)
 }
 
+/// Sends one voice per polyphony slot, all cloned from the bound engine, to the audio thread.
+/// Without `notes` the engine plays at once as a single voice.
+fn send_voices(
+    audio: &mut EngineHandle<VoiceManager<GadgetContainer>>,
+    engine: &Engine<GadgetContainer>,
+    settings: &VoiceSettings,
+    notes: bool,
+) -> Result<(), Box<dyn Error>> {
+    let voices = if notes {
+        VoiceManager::new(engine, settings)?
+    } else {
+        VoiceManager::new(engine, &VoiceSettings::default())?
+    };
+    audio.replace(Box::new(voices), engine)?;
+    Ok(())
+}
+
+/// Text field for an optional parameter name, an empty field means none.
+fn parameter_field(ui: &mut egui::Ui, label: &str, value: &mut Option<String>) {
+    let mut text = value.clone().unwrap_or_default();
+    ui.label(label);
+    ui.text_edit_singleline(&mut text);
+    ui.end_row();
+    *value = if text.trim().is_empty() {
+        None
+    } else {
+        Some(text)
+    };
+}
+
+/// Channel count of the default audio device, patches with other layouts are mapped onto it.
+fn output_channels() -> usize {
+    rodio::cpal::default_host()
+        .default_output_device()
+        .and_then(|device| device.default_output_config().ok())
+        .map(|config| config.channels() as usize)
+        .unwrap_or(2)
+}
+
+/// Wraps top level gadgets in an oversampler or unwraps them again, returns the changed instance.
+fn oversample_menu(
+    ui: &mut egui::Ui,
+    registry: &GadgetRegistry,
+    container: &mut GadgetContainer,
+) -> Option<String> {
+    for i in 0..container.container.len() {
+        let name = container.container[i].get_instance_name();
+        if let Some(children) = container.container[i].children_mut() {
+            if ui.button(format!("Unwrap {}", name)).clicked() {
+                let inner = std::mem::take(&mut children.container);
+                container.container.splice(i..=i, inner);
+                return Some(name);
+            }
+        } else if ui.button(format!("Oversample {}", name)).clicked() {
+            let wrapper = registry.unique_instance_name("OS", container)?;
+            let gadget = container.container.remove(i);
+            container
+                .container
+                .insert(i, Box::new(OversampledGadget::wrap(&wrapper, gadget)));
+            return Some(name);
+        }
+    }
+    None
+}
+
+/// Range, curve and removal of the MIDI bindings of all parameters.
+fn midi_mappings_gui<G: Gadget>(ui: &mut egui::Ui, gadget: &mut G) {
+    let names = gadget.parameter_names();
+    egui::Grid::new("MIDI mappings").show(ui, |ui| {
+        for (i, name) in names.iter().enumerate() {
+            let p = gadget.par_mut(i);
+            if let Some(binding) = p.midi.as_mut() {
+                ui.label(name);
+                ui.label(binding.source.to_string());
+                ui.add(egui::widgets::DragValue::new(&mut binding.min).prefix("min "));
+                ui.add(egui::widgets::DragValue::new(&mut binding.max).prefix("max "));
+                egui::ComboBox::from_id_source(name)
+                    .selected_text(format!("{:?}", binding.curve))
+                    .show_ui(ui, |ui| {
+                        for curve in [MidiCurve::Linear, MidiCurve::Exponential, MidiCurve::Toggle] {
+                            ui.selectable_value(&mut binding.curve, curve, format!("{:?}", curve));
+                        }
+                    });
+                if ui.button("Forget").clicked() {
+                    p.midi = None;
+                }
+                ui.end_row();
+            }
+        }
+    });
+}
+
 #[macroquad::main(window_conf)]
 async fn main() {
     let (_stream, stream_handle) = OutputStream::try_default().unwrap();
     //let source = DampedOscillator::new(440.0);
     //    stream_handle.play_raw(source).unwrap();
-    let mut container = GadgetContainer::new();
-    container.container.push(Box::new(OutputGadget::new()));
-    container
-        .container
-        .push(Box::new(DampedOscillatorGadget::new("Osc")));
-    let mut engine = Engine::new(container);
-    engine.bind();
-    let mut link: Option<String> = None;
-    let mut buffer = Vec::with_capacity(100000);
+    let registry = GadgetRegistry::standard();
+    let mut engine = Engine::new(default_patch());
+    engine.bind().expect("the default patch should bind");
+    let (mut audio, source) = engine_stream(1024, engine.sample_rate, output_channels());
+    stream_handle.play_raw(source).unwrap();
+    let mut gui_state = GuiState::default();
+    let mut buffer = Vec::with_capacity(5000);
+    let mut status = String::new();
+    let mut patch_path = "patch.json".to_owned();
+    let mut voice_settings = VoiceSettings {
+        frequency: Some("Osc: frequency".to_owned()),
+        trigger: Some("Osc: x".to_owned()),
+        ..Default::default()
+    };
+    let mut play_notes = false;
+    let mut midi_ports = input_port_names().unwrap_or_default();
+    let mut midi_port = 0;
+    let mut midi_connection = None;
 
     loop {
         clear_background(BLACK);
@@ -66,74 +169,165 @@ async fn main() {
                 ui.label(check_midi().unwrap());
             });
             egui::Window::new("Synth").show(egui_ctx, |ui| {
-                if ui.button("Play").clicked() {
-                    (&mut engine).bind();
-                    (&mut buffer).clear();
-                    for x in (&mut engine).take(100000) {
-                        (&mut buffer).push(x);
+                ui.horizontal(|ui| {
+                    if ui.button("Play").clicked() {
+                        status = match engine.bind() {
+                            Ok(_) => {
+                                buffer.clear();
+                                buffer.extend(engine.clone().step_by(engine.channels()).take(5000));
+                                match send_voices(
+                                    &mut audio,
+                                    &engine,
+                                    &voice_settings,
+                                    play_notes,
+                                ) {
+                                    Ok(_) => match audio.start() {
+                                        Ok(_) => String::new(),
+                                        Err(e) => e.to_string(),
+                                    },
+                                    Err(e) => e.to_string(),
+                                }
+                            }
+                            Err(e) => e.to_string(),
+                        };
                     }
-                    let source = rodio::buffer::SamplesBuffer::new(1, 44100, buffer.as_slice());
-                    stream_handle.play_raw(source).unwrap();
-                }
-                if let Some(text) = &link {
+                    if ui.button("Stop").clicked() {
+                        if let Err(e) = audio.stop() {
+                            status = e.to_string();
+                        }
+                    }
+                    ui.label(&status);
+                });
+                ui.horizontal(|ui| {
+                    ui.label("Patch");
+                    ui.text_edit_singleline(&mut patch_path);
+                    if ui.button("Save").clicked() {
+                        status = match Patch::from_container(&engine.gadget).save(&patch_path) {
+                            Ok(_) => format!("Saved {}", patch_path),
+                            Err(e) => e.to_string(),
+                        };
+                    }
+                    if ui.button("Load").clicked() {
+                        status = match Patch::load(&patch_path)
+                            .and_then(|patch| patch.to_engine(&registry, engine.sample_rate))
+                        {
+                            Ok(loaded) => {
+                                engine = loaded;
+                                gui_state = GuiState::default();
+                                buffer.clear();
+                                match send_voices(
+                                    &mut audio,
+                                    &engine,
+                                    &voice_settings,
+                                    play_notes,
+                                ) {
+                                    Ok(_) => format!("Loaded {}", patch_path),
+                                    Err(e) => e.to_string(),
+                                }
+                            }
+                            Err(e) => e.to_string(),
+                        };
+                    }
+                });
+                if let Some(text) = &gui_state.link {
                     ui.label(format!("Link: {}", text));
                 } else {
                     ui.label("No link");
                 }
-                engine.gadget.gui(&mut link, ui);
-                if ui.button("Add Osc").clicked() {
-                    engine
-                        .gadget
-                        .container
-                        .push(Box::new(DampedOscillatorGadget::new(&format!(
-                            "Osc{}",
-                            engine.gadget.container.len() - 1
-                        ))));
+                if let Some(name) = &gui_state.learn {
+                    ui.label(format!("Move a MIDI controller to bind {}", name));
                 }
-                if ui.button("Add PwOsc").clicked() {
-                    engine
-                        .gadget
-                        .container
-                        .push(Box::new(PowerOscillatorGadget::new(&format!(
-                            "PwOsc{}",
-                            engine.gadget.container.len() - 1
-                        ))));
-                }
-                if ui.button("Add Abs").clicked() {
-                    engine
-                        .gadget
-                        .container
-                        .push(Box::new(AbsGadget::new(&format!(
-                            "ABS{}",
-                            engine.gadget.container.len() - 1
-                        ))));
-                }
-                if ui.button("Add DAbs").clicked() {
-                    engine
-                        .gadget
-                        .container
-                        .push(Box::new(DoubleAbsGadget::new(&format!(
-                            "DABS{}",
-                            engine.gadget.container.len() - 1
-                        ))));
-                }
-                if ui.button("Add A/P").clicked() {
-                    engine
-                        .gadget
-                        .container
-                        .push(Box::new(AmplitudePhaseGadget::new(&format!(
-                            "AP{}",
-                            engine.gadget.container.len() - 1
-                        ))));
+                if !engine.feedback.is_empty() {
+                    ui.collapsing("Feedback loops", |ui| {
+                        for f in engine.feedback.iter() {
+                            ui.colored_label(egui::Color32::YELLOW, f.to_string());
+                        }
+                    });
                 }
+                engine.gadget.gui(&mut gui_state, ui);
+                ui.menu_button("Add", |ui| {
+                    for category in registry.categories() {
+                        ui.label(category);
+                        for t in registry.types_in_category(category) {
+                            if ui
+                                .button(t.display_name)
+                                .on_hover_text(t.description)
+                                .clicked()
+                            {
+                                if registry.add(t.id, &mut engine.gadget).is_none() {
+                                    status = format!("Cannot add another {}", t.display_name);
+                                }
+                                ui.close_menu();
+                            }
+                        }
+                        ui.separator();
+                    }
+                });
+                ui.menu_button("Oversample", |ui| {
+                    if let Some(name) = oversample_menu(ui, &registry, &mut engine.gadget) {
+                        status = format!("Changed {}, press Play to apply", name);
+                        ui.close_menu();
+                    }
+                });
+            });
+            egui::Window::new("MIDI").show(egui_ctx, |ui| {
+                ui.horizontal(|ui| {
+                    egui::ComboBox::from_label("Input")
+                        .selected_text(midi_ports.get(midi_port).cloned().unwrap_or_default())
+                        .show_ui(ui, |ui| {
+                            for (i, name) in midi_ports.iter().enumerate() {
+                                ui.selectable_value(&mut midi_port, i, name);
+                            }
+                        });
+                    if ui.button("Refresh").clicked() {
+                        midi_ports = input_port_names().unwrap_or_default();
+                    }
+                    if midi_connection.is_none() {
+                        if ui.button("Connect").clicked() {
+                            match connect_input(midi_port, audio.midi_sender()) {
+                                Ok(connection) => midi_connection = Some(connection),
+                                Err(e) => status = e.to_string(),
+                            }
+                        }
+                    } else if ui.button("Disconnect").clicked() {
+                        if let Some(connection) = midi_connection.take() {
+                            connection.close();
+                        }
+                    }
+                });
+                egui::Grid::new("Voices").show(ui, |ui| {
+                    ui.label("Play notes");
+                    ui.checkbox(&mut play_notes, "");
+                    ui.end_row();
+                    ui.label("Polyphony");
+                    ui.add(
+                        egui::widgets::DragValue::new(&mut voice_settings.polyphony)
+                            .clamp_range(1..=64),
+                    );
+                    ui.end_row();
+                    parameter_field(ui, "Frequency", &mut voice_settings.frequency);
+                    parameter_field(ui, "Velocity", &mut voice_settings.velocity);
+                    parameter_field(ui, "Gate", &mut voice_settings.gate);
+                    parameter_field(ui, "Trigger", &mut voice_settings.trigger);
+                    ui.label("Bend range");
+                    ui.add(
+                        egui::widgets::DragValue::new(&mut voice_settings.bend_range)
+                            .suffix(" st"),
+                    );
+                    ui.end_row();
+                });
+                ui.label("Voice settings are applied on Play");
+                ui.collapsing("MIDI mappings", |ui| {
+                    midi_mappings_gui(ui, &mut engine.gadget);
+                });
             });
             egui::Window::new("Plot").show(egui_ctx, |ui| {
-                if !(&buffer).is_empty() {
+                if !buffer.is_empty() {
                     let line = Line::new(Values::from_values_iter(
-                        (&buffer)
-                            .iter().take(5000)
+                        buffer
+                            .iter()
                             .enumerate()
-                            .map(|(i, &x)| Value::new((i as f64) * (DT as f64), x as f64)),
+                            .map(|(i, &x)| Value::new((i as f64) * (engine.dt() as f64), x as f64)),
                     ));
                     
                     Plot::new("my_plot")
@@ -144,6 +338,23 @@ async fn main() {
             });
             
         });
+        while let Some(message) = audio.poll_midi() {
+            if let Some(name) = gui_state.learn.clone() {
+                if let Some(source) = message.event().as_ref().and_then(MidiSource::learn) {
+                    if let Some(p) = engine.gadget.parameter_mut(&name) {
+                        if let Link::Value(_) = p.link {
+                            let (min, max) = p.range.unwrap_or((0.0, 1.0));
+                            p.midi = Some(MidiBinding::new(source, min, max));
+                        }
+                    }
+                    gui_state.learn = None;
+                }
+            }
+        }
+        if let Err(e) = audio.sync(&engine) {
+            status = e.to_string();
+        }
+        audio.collect_garbage();
         egui_macroquad::draw();
         next_frame().await
     }
@@ -152,6 +363,17 @@ async fn main() {
 #[cfg(test)]
 mod test {
     use super::*;
+    use physynth::envelopes::*;
+    use physynth::exciters::*;
+    use physynth::filters::*;
+    use physynth::integrators::*;
+    use physynth::lfo::*;
+    use physynth::mass_spring::*;
+    use physynth::mesh::*;
+    use physynth::modal::*;
+    use physynth::oscillators::*;
+    use physynth::transformations::*;
+    use rodio::source::Source;
 
     #[test]
     fn test_unbound() {
@@ -160,18 +382,1345 @@ mod test {
     }
     #[test]
     fn test_bind_parameter() {
-        let mut buffer: f32 = 123.0;
+        let mut signals = Signals::with_len(2);
+        signals[1] = 123.0;
         let mut p = Parameter::new("test", 0.0);
-        p.bind(&mut buffer);
-        assert_eq!(*p, 123.0);
-        *p = 456.0;
-        assert_eq!(buffer, 456.0);
+        p.bind(1);
+        assert_eq!(signals[&p], 123.0);
+        signals[&p] = 456.0;
+        assert_eq!(signals[1], 456.0);
+    }
+    #[test]
+    fn test_unbound_access() {
+        let signals = Signals::with_len(1);
+        let p = Parameter::new("test", 0.0);
+        assert_eq!(signals.get(&p), Err(UnboundParameter("test")));
     }
 
     #[test]
     fn test_dpo() {
         let mut engine = Engine::new(DampedOscillatorGadget::new("Osc"));
-        engine.bind();
-        engine.gadget.run();
+        engine.bind().unwrap();
+        engine.run();
+    }
+    #[test]
+    fn test_bind_linked_container() {
+        let mut container = GadgetContainer::new();
+        container.container.push(Box::new(OutputGadget::new()));
+        container
+            .container
+            .push(Box::new(DampedOscillatorGadget::new("Osc")));
+        container.parameter_mut("OUT").unwrap().set_link("Osc: x");
+        let mut engine = Engine::new(container);
+        engine.bind().unwrap();
+        assert_eq!(engine.signals.len(), 9);
+        assert_eq!(engine.out(), 1.0);
+        engine.run();
+        assert_eq!(
+            engine.out(),
+            engine.signals[engine.gadget.parameter("Osc: x").unwrap()]
+        );
+    }
+    #[test]
+    fn test_stream_commands() {
+        let mut container = GadgetContainer::new();
+        container.container.push(Box::new(OutputGadget::new()));
+        container.container.push(Box::new(AbsGadget::new("Abs")));
+        container.parameter_mut("OUT").unwrap().set_link("Abs: out");
+        let mut engine = Engine::new(container);
+        engine.bind().unwrap();
+        let (mut handle, mut source) = engine_stream(16, DEFAULT_SAMPLE_RATE, 1);
+        assert_eq!(source.next(), Some(0.0));
+        handle.rebind(&engine).unwrap();
+        handle.start().unwrap();
+        let mut last = 0.0;
+        for x in (&mut source).take(2 * CONTROL_BLOCK + FADE_LENGTH) {
+            last = x;
+        }
+        assert_eq!(last, 0.0);
+        engine.gadget.parameter_mut("Abs: inp").unwrap().set_value(-0.5);
+        handle.sync(&engine).unwrap();
+        for x in (&mut source).take(CONTROL_BLOCK) {
+            last = x;
+        }
+        assert_eq!(last, 0.5);
+        handle.stop().unwrap();
+        for x in (&mut source).take(CONTROL_BLOCK + FADE_LENGTH) {
+            last = x;
+        }
+        assert_eq!(last, 0.0);
+        // A new topology moves the parameters of the following gadgets, their values are
+        // not sent to the slots of the old layout.
+        let registry = GadgetRegistry::standard();
+        let mut container = GadgetContainer::new();
+        container.container.push(Box::new(OutputGadget::new()));
+        registry.add("MSN", &mut container).unwrap();
+        registry.add("DO", &mut container).unwrap();
+        container.parameter_mut("OUT").unwrap().set_link("Osc1: xs");
+        let mut engine = Engine::new(container);
+        engine.bind().unwrap();
+        handle.rebind(&engine).unwrap();
+        handle.start().unwrap();
+        let topology = Topology {
+            masses: vec![Mass { mass: 0.001, fixed: false, port: true }],
+            springs: vec![Spring { a: 0, b: None, stiffness: 1579.14, damping: 0.0 }],
+        };
+        let config = serde_json::to_value(topology).unwrap();
+        engine.gadget.container[1].set_config(&config).unwrap();
+        handle.sync(&engine).unwrap();
+        for x in (&mut source).take(2 * CONTROL_BLOCK + FADE_LENGTH) {
+            last = x;
+        }
+        assert_eq!(last, 0.0);
+    }
+    #[test]
+    fn test_sample_rate() {
+        let mut container = GadgetContainer::new();
+        container.container.push(Box::new(OutputGadget::new()));
+        container
+            .container
+            .push(Box::new(DampedOscillatorGadget::new("Osc")));
+        container.parameter_mut("OUT").unwrap().set_link("Osc: x");
+        container.parameter_mut("Osc: damp").unwrap().set_value(0.0);
+        container.parameter_mut("Osc: frequency").unwrap().set_value(100.0);
+        for &sample_rate in [22050, 44100, 96000].iter() {
+            let mut engine = Engine::with_sample_rate(container.clone(), sample_rate);
+            engine.bind().unwrap();
+            assert_eq!(Source::sample_rate(&engine), sample_rate);
+            // Half a period later the oscillator should be close to its negative extreme.
+            let x = engine.by_ref().take(sample_rate as usize / 200).last().unwrap();
+            assert!(x < -0.95, "sample rate {}: {}", sample_rate, x);
+        }
+    }
+    #[test]
+    fn test_render_wav() {
+        let options = RenderOptions {
+            samples: 1000,
+            normalize: true,
+            outputs: vec!["Osc: y".to_owned()],
+            ..Default::default()
+        };
+        let data = render(&default_patch(), &options).unwrap();
+        assert_eq!(data.channels, 1);
+        let data = data.samples;
+        assert_eq!(data.len(), 1000);
+        let peak = data.iter().fold(0.0f32, |m, x| m.max(x.abs()));
+        assert!((peak - 1.0).abs() < 1e-6);
+
+        let path = std::env::temp_dir().join("physynth_test_render.wav");
+        for &format in [WavFormat::Int16, WavFormat::Int24, WavFormat::Float32].iter() {
+            write_wav(&path, &data, 1, options.sample_rate, format).unwrap();
+            let reader = hound::WavReader::open(&path).unwrap();
+            assert_eq!(reader.spec().sample_rate, options.sample_rate);
+            assert_eq!(reader.len(), 1000);
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+    #[test]
+    fn test_patch_roundtrip() {
+        let mut container = default_patch();
+        container
+            .container
+            .push(Box::new(AmplitudePhaseGadget::new("AP1")));
+        container.parameter_mut("AP1: x").unwrap().set_link("Osc: x");
+        container.parameter_mut("Osc: frequency").unwrap().set_value(220.0);
+        let patch = Patch::from_container(&container);
+        let loaded = Patch::from_json(&patch.to_json().unwrap()).unwrap();
+        assert_eq!(loaded, patch);
+        let engine = loaded.to_engine(&GadgetRegistry::standard(), DEFAULT_SAMPLE_RATE).unwrap();
+        assert_eq!(engine.gadget.parameter_names(), container.parameter_names());
+        assert_eq!(engine.gadget.container[2].name(), "AP");
+        assert_eq!(
+            Patch::from_container(&engine.gadget),
+            Patch::from_container(&container)
+        );
+    }
+    #[test]
+    fn test_patch_errors() {
+        let registry = GadgetRegistry::standard();
+        let text = r#"{"version": 1, "gadgets": [{"type": "Nope", "name": "N"}]}"#;
+        assert!(matches!(
+            Patch::from_json(text).unwrap().to_container(&registry),
+            Err(PatchError::UnknownGadgetType { .. })
+        ));
+        let text = r#"{"version": 1, "gadgets": [
+            {"type": "ABS", "name": "A", "parameters": {"inp": {"link": "B: out"}}}
+        ]}"#;
+        assert!(matches!(
+            Patch::from_json(text).unwrap().to_container(&registry),
+            Err(PatchError::DanglingLink { .. })
+        ));
+        let text = r#"{"version": 1, "gadgets": [
+            {"type": "ABS", "name": "A", "parameters": {"foo": 1.0}}
+        ]}"#;
+        assert!(matches!(
+            Patch::from_json(text).unwrap().to_container(&registry),
+            Err(PatchError::UnknownParameter { .. })
+        ));
+    }
+    #[test]
+    fn test_registry() {
+        let registry = GadgetRegistry::standard();
+        for t in registry.types() {
+            let gadget = registry.create(t.id, "X").unwrap();
+            assert_eq!(gadget.name(), t.id);
+        }
+        let mut container = default_patch();
+        assert_eq!(registry.add("DO", &mut container), Some("Osc1".to_owned()));
+        assert_eq!(registry.add("DO", &mut container), Some("Osc2".to_owned()));
+        assert_eq!(registry.add("AP", &mut container), Some("AP1".to_owned()));
+        assert_eq!(registry.add("Output", &mut container), None);
+        assert_eq!(registry.add("Nope", &mut container), None);
+        assert_eq!(container.container.len(), 5);
+    }
+    #[test]
+    fn test_derived_parameters() {
+        let osc = DampedOscillatorGadget::new("Osc");
+        assert_eq!(osc.name(), "DO");
+        assert_eq!(
+            osc.parameter_names(),
+            vec![
+                "Osc: frequency",
+                "Osc: x",
+                "Osc: xs",
+                "Osc: y",
+                "Osc: ys",
+                "Osc: damp",
+                "Osc: integrator",
+                "Osc: v",
+                "Osc: force"
+            ]
+        );
+        let frequency = osc.par(0);
+        assert!(matches!(frequency.link, Link::Value(x) if x == 440.0));
+        assert_eq!(frequency.range, Some((0.0, 20000.0)));
+        assert_eq!(frequency.units, "Hz");
+        assert!(matches!(osc.par(2).link, Link::Value(x) if x == 0.0));
+        assert_eq!(osc.par(6).choices, INTEGRATOR_NAMES);
+        assert_eq!(osc.par(6).range, Some((0.0, 3.0)));
+        assert_eq!(AmplitudePhaseGadget::new("AP").parameter_count(), 4);
+    }
+    #[test]
+    fn test_evaluation_order() {
+        let registry = GadgetRegistry::standard();
+        let build = |reverse: bool| {
+            let mut container = GadgetContainer::new();
+            let mut ids = vec!["ABS", "DO"];
+            if reverse {
+                ids.reverse();
+            }
+            for id in ids {
+                registry.add(id, &mut container);
+            }
+            container.parameter_mut("ABS1: inp").unwrap().set_link("Osc1: x");
+            let mut engine = Engine::new(container);
+            engine.bind().unwrap();
+            engine
+        };
+        let mut a = build(false);
+        let mut b = build(true);
+        assert!(a.feedback.is_empty());
+        for _ in 0..100 {
+            a.run();
+            b.run();
+            let abs_a = a.signals[a.gadget.parameter("ABS1: out").unwrap()];
+            let abs_b = b.signals[b.gadget.parameter("ABS1: out").unwrap()];
+            let x = a.signals[a.gadget.parameter("Osc1: x").unwrap()];
+            assert_eq!(abs_a, abs_b);
+            assert_eq!(abs_a, x.abs());
+        }
+    }
+    #[test]
+    fn test_feedback_delay() {
+        let registry = GadgetRegistry::standard();
+        let mut container = GadgetContainer::new();
+        registry.add("DO", &mut container);
+        registry.add("ABS", &mut container);
+        container.parameter_mut("ABS1: inp").unwrap().set_link("Osc1: x");
+        container.parameter_mut("ABS1: out").unwrap().set_link("Osc1: xs");
+        let mut engine = Engine::new(container);
+        engine.bind().unwrap();
+        assert_eq!(engine.feedback.len(), 1);
+        assert_eq!(engine.feedback[0].from, "Osc1: x");
+        assert_eq!(engine.feedback[0].to, "ABS1: inp");
+        assert_eq!(engine.gadget.order(), vec![1, 0]);
+        for _ in 0..100 {
+            // ABS1 runs first and sees the oscillator position of the previous sample.
+            let previous = engine.signals[engine.gadget.parameter("Osc1: x").unwrap()];
+            engine.run();
+            let out = engine.signals[engine.gadget.parameter("ABS1: out").unwrap()];
+            assert_eq!(out, previous.abs());
+        }
+    }
+    #[test]
+    fn test_feedback_downstream() {
+        // ABS1 sorts first but only reads the Osc1 <-> Osc2 cycle, its link is not delayed.
+        let registry = GadgetRegistry::standard();
+        let mut container = GadgetContainer::new();
+        registry.add("DO", &mut container);
+        registry.add("DO", &mut container);
+        registry.add("ABS", &mut container);
+        container.parameter_mut("Osc1: xs").unwrap().set_link("Osc2: x");
+        container.parameter_mut("Osc2: xs").unwrap().set_link("Osc1: x");
+        container.parameter_mut("ABS1: inp").unwrap().set_link("Osc1: x");
+        let mut engine = Engine::new(container);
+        engine.bind().unwrap();
+        assert_eq!(engine.feedback.len(), 1);
+        assert!(engine.feedback.iter().all(|f| f.to != "ABS1: inp"));
+        let order = engine.gadget.order();
+        let position = |g| order.iter().position(|&i| i == g).unwrap();
+        assert!(position(0) < position(2));
+        for _ in 0..100 {
+            engine.run();
+            let out = engine.signals[engine.gadget.parameter("ABS1: out").unwrap()];
+            let x = engine.signals[engine.gadget.parameter("Osc1: x").unwrap()];
+            assert_eq!(out, x.abs());
+        }
+    }
+    #[test]
+    fn test_bind_errors() {
+        let registry = GadgetRegistry::standard();
+        let mut container = GadgetContainer::new();
+        registry.add("ABS", &mut container);
+        registry.add("ABS", &mut container);
+        container.parameter_mut("ABS1: inp").unwrap().set_link("ABS2: inp");
+        container.parameter_mut("ABS2: inp").unwrap().set_link("ABS1: inp");
+        let mut engine = Engine::new(container.clone());
+        assert_eq!(
+            engine.bind(),
+            Err(BindError::LinkCycle(vec![
+                "ABS1: inp".to_owned(),
+                "ABS2: inp".to_owned()
+            ]))
+        );
+
+        container.parameter_mut("ABS2: inp").unwrap().set_link("Gone: out");
+        let mut engine = Engine::new(container.clone());
+        assert_eq!(
+            engine.bind(),
+            Err(BindError::UnknownTarget {
+                parameter: "ABS2: inp".to_owned(),
+                target: "Gone: out".to_owned()
+            })
+        );
+
+        container.parameter_mut("ABS2: inp").unwrap().set_value(0.0);
+        container.container.push(Box::new(AbsGadget::new("ABS1")));
+        let mut engine = Engine::new(container);
+        assert_eq!(
+            engine.bind(),
+            Err(BindError::DuplicateInstanceName("ABS1".to_owned()))
+        );
+    }
+    #[test]
+    fn test_voices() {
+        let mut engine = Engine::new(default_patch());
+        engine.bind().unwrap();
+        let settings = VoiceSettings {
+            polyphony: 2,
+            frequency: Some("Osc: frequency".to_owned()),
+            gate: Some("Osc: xs".to_owned()),
+            ..Default::default()
+        };
+        let mut voices = VoiceManager::new(&engine, &settings).unwrap();
+        voices.midi(&MidiMessage::new(&[0x90, 60, 100]));
+        voices.note_on(64, 100);
+        assert_eq!(voices.notes(), vec![Some(60), Some(64)]);
+        // The oldest note is stolen.
+        voices.note_on(67, 100);
+        assert_eq!(voices.notes(), vec![Some(67), Some(64)]);
+        voices.midi(&MidiMessage::new(&[0x80, 64, 0]));
+        assert_eq!(voices.notes(), vec![Some(67), None]);
+        // A released voice is reused before a held one.
+        voices.note_on(69, 100);
+        assert_eq!(voices.notes(), vec![Some(67), Some(69)]);
+        assert!(voices.next_sample().is_finite());
+
+        // The template oscillator starts at x = 1, idle voices stay silent until played.
+        let settings = VoiceSettings {
+            polyphony: 4,
+            frequency: Some("Osc: frequency".to_owned()),
+            ..Default::default()
+        };
+        let mut voices = VoiceManager::new(&engine, &settings).unwrap();
+        assert!((0..100).all(|_| voices.next_sample() == 0.0));
+        // A stolen voice starts like a fresh one.
+        let settings = VoiceSettings {
+            frequency: Some("Osc: frequency".to_owned()),
+            ..Default::default()
+        };
+        let mut fresh = VoiceManager::new(&engine, &settings).unwrap();
+        fresh.note_on(62, 100);
+        let mut stolen = VoiceManager::new(&engine, &settings).unwrap();
+        stolen.note_on(60, 100);
+        (0..1000).for_each(|_| {
+            stolen.next_sample();
+        });
+        stolen.note_on(62, 100);
+        assert!((0..100).all(|_| stolen.next_sample() == fresh.next_sample()));
+
+        // The trigger is a one sample pulse, every note strikes the hammer again.
+        let mut container = GadgetContainer::new();
+        GadgetRegistry::standard().add("HAMMER", &mut container).unwrap();
+        let mut hammer = Engine::new(container);
+        hammer.bind().unwrap();
+        assert!(hammer.bind_output("Hammer1: force"));
+        let settings = VoiceSettings {
+            trigger: Some("Hammer1: trigger".to_owned()),
+            ..Default::default()
+        };
+        let mut voices = VoiceManager::new(&hammer, &settings).unwrap();
+        for _ in 0..2 {
+            voices.note_on(60, 127);
+            let force: Vec<f32> = (0..2400).map(|_| voices.next_sample()).collect();
+            assert!(force.iter().any(|&f| f > 1.0));
+            assert_eq!(force[2000..].iter().sum::<f32>(), 0.0);
+            voices.note_off(60);
+        }
+
+        // Without voice parameters the template plays like a single engine.
+        let mut mono = VoiceManager::new(&engine, &VoiceSettings::default()).unwrap();
+        assert_eq!(mono.polyphony(), 1);
+        let mut single = engine.clone();
+        assert!((0..100).all(|_| mono.next_sample() == single.next_sample()));
+        assert!((0..100).any(|_| mono.next_sample() != 0.0));
+
+        let settings = VoiceSettings {
+            frequency: Some("Nope: frequency".to_owned()),
+            ..Default::default()
+        };
+        assert!(matches!(
+            VoiceManager::new(&engine, &settings),
+            Err(VoiceError::UnknownParameter(_))
+        ));
+        assert_eq!(
+            MidiEvent::parse(&[0xe1, 0x00, 0x40]),
+            Some(MidiEvent::PitchBend {
+                channel: 1,
+                value: 0
+            })
+        );
+        assert!((note_frequency(69, 0.0) - 440.0).abs() < 1e-3);
+    }
+    #[test]
+    fn test_midi_learn() {
+        let cc = MidiEvent::parse(&[0xb0, 7, 127]).unwrap();
+        let source = MidiSource::learn(&cc).unwrap();
+        assert_eq!(
+            source,
+            MidiSource::ControlChange {
+                channel: 0,
+                controller: 7
+            }
+        );
+        assert_eq!(MidiSource::learn(&MidiEvent::parse(&[0x90, 60, 1]).unwrap()), None);
+
+        let mut container = default_patch();
+        container.parameter_mut("Osc: frequency").unwrap().midi =
+            Some(MidiBinding::new(source, 100.0, 1000.0));
+        let patch = Patch::from_container(&container);
+        let loaded = Patch::from_json(&patch.to_json().unwrap()).unwrap();
+        assert_eq!(loaded, patch);
+        let mut engine = loaded.to_engine(&GadgetRegistry::standard(), 48000).unwrap();
+        let slot = engine.gadget.parameter("Osc: frequency").unwrap().slot.unwrap();
+        engine.midi(&MidiMessage::new(&[0xb0, 7, 127]));
+        assert_eq!(engine.signals[slot], 1000.0);
+        // Other controllers and channels are ignored.
+        engine.midi(&MidiMessage::new(&[0xb1, 7, 0]));
+        assert_eq!(engine.signals[slot], 1000.0);
+
+        let exponential = MidiBinding {
+            curve: MidiCurve::Exponential,
+            ..MidiBinding::new(source, 100.0, 10000.0)
+        };
+        Player::map_midi(&mut engine, slot, Some(exponential));
+        engine.midi(&MidiMessage::new(&[0xb0, 7, 64]));
+        assert!((engine.signals[slot] - 100.0 * 100.0f32.powf(64.0 / 127.0)).abs() < 1e-2);
+
+        let mut toggle = MidiMapping::new(
+            slot,
+            MidiBinding {
+                curve: MidiCurve::Toggle,
+                ..MidiBinding::new(source, 0.0, 1.0)
+            },
+        );
+        assert_eq!(toggle.apply(&cc), Some(1.0));
+        // A held button repeating its value does not toggle again.
+        assert_eq!(toggle.apply(&cc), None);
+        assert_eq!(toggle.apply(&MidiEvent::parse(&[0xb0, 7, 0]).unwrap()), None);
+        assert_eq!(toggle.apply(&cc), Some(0.0));
+
+        // Engines sent to the audio thread are copies, MIDI learn still works on them.
+        let damp = engine.gadget.parameter("Osc: damp").unwrap().slot.unwrap();
+        let mut copy = engine.clone();
+        let volume = MidiSource::learn(&MidiEvent::parse(&[0xb0, 8, 0]).unwrap()).unwrap();
+        Player::map_midi(&mut copy, damp, Some(MidiBinding::new(volume, 0.0, 10.0)));
+        copy.midi(&MidiMessage::new(&[0xb0, 8, 127]));
+        assert_eq!(copy.signals[damp], 10.0);
+
+        // Bindings of linked parameters are ignored, they would overwrite the target slot.
+        let mut container = default_patch();
+        let p = container.parameter_mut("Osc: xs").unwrap();
+        p.set_link("Osc: x");
+        p.midi = Some(MidiBinding::new(source, 5.0, 5.0));
+        let mut engine = Engine::new(container);
+        engine.bind().unwrap();
+        let x = engine.gadget.parameter("Osc: x").unwrap().slot.unwrap();
+        engine.midi(&MidiMessage::new(&[0xb0, 7, 127]));
+        assert_eq!(engine.signals[x], 1.0);
+    }
+    #[test]
+    fn test_integrators() {
+        // Undamped oscillator, x(t) = cos(omega t).
+        let samples = 4800;
+        let error = |integrator: Integrator, frequency: f32| {
+            let mut osc = DampedOscillatorGadget::new("Osc");
+            osc.parameter_mut("Osc: frequency").unwrap().set_value(frequency);
+            osc.parameter_mut("Osc: damp").unwrap().set_value(0.0);
+            osc.parameter_mut("Osc: integrator")
+                .unwrap()
+                .set_value(integrator.value());
+            let mut engine = Engine::new(osc);
+            engine.bind().unwrap();
+            engine.bind_output("Osc: x");
+            let x = engine.by_ref().take(samples).last().unwrap() as f64;
+            let t = samples as f64 / engine.sample_rate as f64;
+            (x - (2.0 * std::f64::consts::PI * frequency as f64 * t).cos()).abs()
+        };
+        assert!(error(Integrator::Exact, 5000.0) < 1e-3);
+        assert!(error(Integrator::RungeKutta4, 1000.0) < 1e-2);
+        // The symplectic schemes drift in phase, but not in amplitude.
+        assert!(error(Integrator::VelocityVerlet, 1000.0) < 0.2);
+        assert!(error(Integrator::SymplecticEuler, 1000.0) < 0.2);
+
+        let mut propagator = LinearPropagator::new();
+        // Constant force on a free particle: x = x0 + v t + t^2 / 2.
+        let mut z = [1.0, 2.0];
+        propagator.step([[0.0, 1.0], [0.0, 0.0]], [0.0, 1.0], &mut z, 0.5);
+        assert!((z[0] - 2.125).abs() < 1e-6 && (z[1] - 2.5).abs() < 1e-6);
+        assert_eq!(Integrator::from_value(2.0), Integrator::RungeKutta4);
+    }
+    #[test]
+    fn test_oversampling() {
+        let registry = GadgetRegistry::standard();
+        let mut container = default_patch();
+        let osc = container.container.remove(1);
+        let mut wrapper = OversampledGadget::wrap("OS1", osc);
+        wrapper.parameter_mut("OS1: factor").unwrap().set_value(1.0);
+        container.container.push(Box::new(wrapper));
+        container.parameter_mut("Osc: damp").unwrap().set_value(0.0);
+        container.parameter_mut("Osc: frequency").unwrap().set_value(1000.0);
+        assert!(container.has_instance("Osc"));
+        assert_eq!(registry.unique_instance_name("OS", &container), Some("OS2".to_owned()));
+
+        let patch = Patch::from_container(&container);
+        let loaded = Patch::from_json(&patch.to_json().unwrap()).unwrap();
+        assert_eq!(loaded, patch);
+        let mut engine = loaded.to_engine(&registry, 48000).unwrap();
+        assert_eq!(
+            engine.gadget.parameter_names().last().unwrap(),
+            "OS1: factor"
+        );
+        // A 1 kHz sine passes the filters unchanged, apart from the latency.
+        let samples: Vec<f32> = engine.by_ref().take(4800).collect();
+        let peak = samples[2400..].iter().fold(0.0f32, |a, x| a.max(x.abs()));
+        assert!((peak - 1.0).abs() < 0.01, "peak {}", peak);
+
+        // Constant inputs stay constant after interpolation.
+        let mut engine = Engine::new(container);
+        engine.bind().unwrap();
+        engine.run();
+        assert_eq!(engine.signals[engine.gadget.parameter("Osc: frequency").unwrap()], 1000.0);
+        // A new factor waits for the next prepare instead of clearing the string while it rings.
+        let mut container = GadgetContainer::new();
+        let mut wrapper = OversampledGadget::new("OS1");
+        registry.add("WG", &mut wrapper.inner).unwrap();
+        container.container.push(Box::new(wrapper));
+        container.parameter_mut("String1: pluck").unwrap().set_value(1.0);
+        let mut engine = Engine::new(container);
+        engine.bind().unwrap();
+        assert!(engine.bind_output("String1: out"));
+        engine.by_ref().take(1000).for_each(drop);
+        let factor = engine.gadget.parameter("OS1: factor").unwrap().slot.unwrap();
+        engine.signals[factor] = 2.0;
+        let samples: Vec<f32> = engine.by_ref().take(1000).collect();
+        assert!(samples.iter().any(|x| x.abs() > 0.01));
+
+        let text = r#"{"version": 1, "gadgets": [{"type": "ABS", "name": "A", "gadgets": [
+            {"type": "ABS", "name": "B"}]}]}"#;
+        assert!(matches!(
+            Patch::from_json(text).unwrap().to_container(&registry),
+            Err(PatchError::NotAContainer(_))
+        ));
+    }
+    #[test]
+    fn test_stereo_output() {
+        let registry = GadgetRegistry::standard();
+        let mut container = GadgetContainer::new();
+        registry.add("Output2", &mut container).unwrap();
+        registry.add("DO", &mut container).unwrap();
+        registry.add("PAN", &mut container).unwrap();
+        assert_eq!(container.output_channels(), vec!["L", "R"]);
+        container.parameter_mut("Pan1: inp").unwrap().set_link("Osc1: x");
+        container.parameter_mut("Pan1: pan").unwrap().set_value(1.0);
+        container.parameter_mut("L").unwrap().set_link("Pan1: left");
+        container.parameter_mut("R").unwrap().set_link("Pan1: right");
+        let mut engine = Engine::new(container.clone());
+        engine.bind().unwrap();
+        assert_eq!(engine.channels(), 2);
+        assert_eq!(Source::channels(&engine), 2);
+        let frame: Vec<f32> = engine.by_ref().take(2).collect();
+        assert!(frame[0].abs() < 1e-6);
+        assert_eq!(frame[1], engine.signals[engine.gadget.parameter("Osc1: x").unwrap()]);
+
+        let options = RenderOptions {
+            samples: 100,
+            ..Default::default()
+        };
+        let rendering = render(&container, &options).unwrap();
+        assert_eq!(rendering.channels, 2);
+        assert_eq!(rendering.samples.len(), 200);
+
+        // A mono player is copied to all channels of the stream.
+        let mut engine = Engine::new(default_patch());
+        engine.bind().unwrap();
+        let (mut handle, mut source) = engine_stream(16, DEFAULT_SAMPLE_RATE, 2);
+        handle.rebind(&engine).unwrap();
+        handle.start().unwrap();
+        let samples: Vec<f32> = (&mut source).take(2 * FADE_LENGTH).collect();
+        assert!(samples[2 * FADE_LENGTH - 1] != 0.0);
+        assert!(samples.chunks(2).all(|frame| frame[0] == frame[1]));
+    }
+    /// Lag with the highest autocorrelation between `min` and `max`.
+    fn period(samples: &[f32], min: usize, max: usize) -> usize {
+        let correlation = |lag: usize| -> f32 {
+            samples
+                .iter()
+                .zip(samples[lag..].iter())
+                .map(|(a, b)| a * b)
+                .sum()
+        };
+        (min..max)
+            .max_by(|&a, &b| correlation(a).partial_cmp(&correlation(b)).unwrap())
+            .unwrap()
+    }
+    #[test]
+    fn test_strings() {
+        let registry = GadgetRegistry::standard();
+        for (id, out) in [("WG", "String1: out"), ("KS", "KS1: out")].iter() {
+            let mut container = GadgetContainer::new();
+            let name = registry.add(id, &mut container).unwrap();
+            container
+                .parameter_mut(&format!("{}: pluck", name))
+                .unwrap()
+                .set_value(1.0);
+            let mut engine = Engine::new(container);
+            engine.bind().unwrap();
+            assert!(engine.bind_output(out));
+            let samples: Vec<f32> = engine.by_ref().take(4800).collect();
+            // 48000 / 220 = 218.2 samples
+            let lag = period(&samples[1000..], 150, 300);
+            assert!((217..=219).contains(&lag), "{} period {}", id, lag);
+            let energy = |x: &[f32]| x.iter().map(|x| x * x).sum::<f32>();
+            assert!(energy(&samples[3800..]) < energy(&samples[..1000]));
+            assert!(samples.iter().all(|x| x.is_finite()));
+        }
+    }
+    #[test]
+    fn test_mass_spring() {
+        let registry = GadgetRegistry::standard();
+        let mut container = GadgetContainer::new();
+        registry.add("MSN", &mut container).unwrap();
+        let mut network = MassSpringGadget::new("Net1");
+        assert_eq!(network.parameter_count(), 9);
+        // A single mass of 1 g on a spring of 1579 N/m oscillates at 200 Hz.
+        let topology = Topology {
+            masses: vec![Mass { mass: 0.001, fixed: false, port: true }],
+            springs: vec![Spring { a: 0, b: None, stiffness: 1579.14, damping: 0.0 }],
+        };
+        network.set_topology(topology.clone()).unwrap();
+        assert_eq!(
+            network.parameter_names(),
+            vec!["Net1: integrator", "Net1: tension", "Net1: damping", "Net1: F1", "Net1: x1", "Net1: v1"]
+        );
+        network.parameter_mut("Net1: F1").unwrap().set_value(1.0);
+        container.container[0] = Box::new(network);
+
+        let patch = Patch::from_container(&container);
+        let loaded = Patch::from_json(&patch.to_json().unwrap()).unwrap();
+        assert_eq!(loaded, patch);
+        let mut engine = loaded.to_engine(&registry, 48000).unwrap();
+        assert!(engine.bind_output("Net1: v1"));
+        let samples: Vec<f32> = engine.by_ref().take(4800).collect();
+        let lag = period(&samples, 200, 300);
+        assert!((239..=241).contains(&lag), "period {}", lag);
+        // Symplectic Euler keeps the energy bounded: the force pulls the mass to 1/k and back to 0.
+        let x = engine.signals[engine.gadget.parameter("Net1: x1").unwrap()];
+        assert!(x > -1e-6 && x < 2.02 / 1579.14, "x {}", x);
+        assert!(samples.iter().all(|x| x.is_finite()));
+
+        let mut invalid = patch.clone();
+        invalid.gadgets[0].config = Some(serde_json::json!({"masses": [], "springs": []}));
+        assert!(matches!(invalid.to_container(&registry), Err(PatchError::InvalidConfig { .. })));
+    }
+    #[test]
+    fn test_modal_bank() {
+        let registry = GadgetRegistry::standard();
+        for preset in MODE_PRESETS.iter() {
+            assert!(mode_preset(preset).unwrap().validate().is_ok(), "{}", preset);
+        }
+        let csv = "# analysed partials\nfrequency,decay,gain\n440, 0.5, 1\n880,0.25\n\n1320\n";
+        let table = parse_modes_csv("test", csv).unwrap();
+        assert_eq!(
+            table.modes.iter().map(|m| (m.ratio, m.decay, m.gain)).collect::<Vec<_>>(),
+            vec![(1.0, 0.5, 1.0), (2.0, 0.25, 1.0), (3.0, 1.0, 1.0)]
+        );
+        assert!(parse_modes_csv("test", "440\nabc\n").is_err());
+        assert!(parse_modes_csv("test", "# nothing\n").is_err());
+
+        let mut container = GadgetContainer::new();
+        let mut bank = ModalBankGadget::new("Modes1");
+        bank.set_table(parse_modes_csv("single", "200,0.5").unwrap()).unwrap();
+        bank.parameter_mut("Modes1: frequency").unwrap().set_value(200.0);
+        container.container.push(Box::new(bank));
+        let patch = Patch::from_container(&container);
+        let loaded = Patch::from_json(&patch.to_json().unwrap()).unwrap();
+        assert_eq!(loaded, patch);
+
+        let mut engine = loaded.to_engine(&registry, 48000).unwrap();
+        assert!(engine.bind_output("Modes1: out"));
+        let inp = engine.gadget.parameter("Modes1: inp").unwrap().slot.unwrap();
+        engine.signals[inp] = 1.0;
+        engine.run();
+        engine.signals[inp] = 0.0;
+        let samples: Vec<f32> = engine.by_ref().take(24000).collect();
+        let lag = period(&samples[..4800], 200, 300);
+        assert!((239..=241).contains(&lag), "period {}", lag);
+        // The mode decays by 60 dB in 0.5 s.
+        let peak = |x: &[f32]| x.iter().fold(0.0f32, |a, x| a.max(x.abs()));
+        let ratio = peak(&samples[23760..]) / peak(&samples[..240]);
+        assert!((ratio - 0.001).abs() < 0.0002, "ratio {}", ratio);
+    }
+    #[test]
+    fn test_mesh() {
+        let registry = GadgetRegistry::standard();
+        let mut container = GadgetContainer::new();
+        let mut mesh = MeshGadget::new("Mesh1");
+        mesh.parameter_mut("Mesh1: strike_x").unwrap().set_value(0.5);
+        mesh.parameter_mut("Mesh1: strike_y").unwrap().set_value(0.5);
+        mesh.parameter_mut("Mesh1: pickup_x").unwrap().set_value(0.5);
+        mesh.parameter_mut("Mesh1: pickup_y").unwrap().set_value(0.5);
+        mesh.parameter_mut("Mesh1: damping").unwrap().set_value(0.0);
+        assert!(mesh.courant() < 0.5);
+        container.container.push(Box::new(mesh));
+        let patch = Patch::from_container(&container);
+        let loaded = Patch::from_json(&patch.to_json().unwrap()).unwrap();
+        assert_eq!(loaded, patch);
+
+        // Fundamental of a square membrane: sqrt(2) c / (2 width) = 148.5 Hz, 323 samples.
+        let mut engine = loaded.to_engine(&registry, 48000).unwrap();
+        assert!(engine.bind_output("Mesh1: out"));
+        let strike = engine.gadget.parameter("Mesh1: strike").unwrap().slot.unwrap();
+        engine.signals[strike] = 1.0;
+        engine.run();
+        engine.signals[strike] = 0.0;
+        let samples: Vec<f32> = engine.by_ref().take(9600).collect();
+        // The next modes are above 200 Hz, the strongest line between 100 and 200 Hz is the fundamental.
+        let magnitude = |f: f32| {
+            let w = 2.0 * std::f32::consts::PI * f / 48000.0;
+            let (re, im) = samples.iter().enumerate().fold((0.0, 0.0), |(re, im), (n, x)| {
+                (re + x * (w * n as f32).cos(), im + x * (w * n as f32).sin())
+            });
+            re * re + im * im
+        };
+        let peak = (100..200).max_by(|&a, &b| magnitude(a as f32).partial_cmp(&magnitude(b as f32)).unwrap());
+        assert!((145..=151).contains(&peak.unwrap()), "fundamental {:?}", peak);
+
+        // Resizing the grid shown by the GUI leaves the running copy alone.
+        let mut running = engine.clone();
+        let settings = MeshSettings { columns: 20, rows: 12, ..Default::default() };
+        let config = serde_json::to_value(settings).unwrap();
+        engine.gadget.container[0].set_config(&config).unwrap();
+        assert!(running.by_ref().take(4800).all(|x| x.is_finite()));
+
+        // Too much tension for the grid is limited to the stability bound.
+        let mut mesh = MeshGadget::new("Mesh1");
+        mesh.set_settings(MeshSettings {
+            shape: MeshShape::Circle,
+            columns: 64,
+            rows: 64,
+            ..Default::default()
+        })
+        .unwrap();
+        mesh.parameter_mut("Mesh1: tension").unwrap().set_value(1e6);
+        assert!(mesh.courant() > 0.5);
+        let mut container = GadgetContainer::new();
+        container.container.push(Box::new(mesh));
+        let mut engine = Engine::new(container);
+        engine.bind().unwrap();
+        assert!(engine.bind_output("Mesh1: out"));
+        let strike = engine.gadget.parameter("Mesh1: strike").unwrap().slot.unwrap();
+        engine.signals[strike] = 1.0;
+        let samples: Vec<f32> = engine.by_ref().take(4800).collect();
+        assert!(samples.iter().all(|x| x.is_finite() && x.abs() < 1.0));
+        assert!(samples.iter().any(|&x| x != 0.0));
+        assert!(MeshSettings { columns: 2, ..Default::default() }.validate().is_err());
+    }
+    #[test]
+    fn test_bow() {
+        for curve in 0..BOW_CURVES.len() {
+            let c = curve as f32;
+            assert_eq!(friction(c, 0.0, 0.1), 0.0);
+            assert_eq!(friction(c, -0.3, 0.1), -friction(c, 0.3, 0.1));
+            assert!(friction(c, 0.1, 0.1) > friction(c, 1.0, 0.1));
+        }
+        // Slip phases of the last 4800 samples, entered when the slip exceeds the curve width.
+        let slips = |slip: &[f32]| {
+            slip[43200..]
+                .windows(2)
+                .filter(|w| w[0].abs() <= 0.05 && w[1].abs() > 0.05)
+                .count()
+        };
+        // A bowed mass on a spring (200 Hz) keeps oscillating with every friction curve.
+        let run = |pressure: f32, curve: usize| {
+            let registry = GadgetRegistry::standard();
+            let mut container = GadgetContainer::new();
+            let mut network = MassSpringGadget::new("Net1");
+            network
+                .set_topology(Topology {
+                    masses: vec![Mass { mass: 0.001, fixed: false, port: true }],
+                    springs: vec![Spring { a: 0, b: None, stiffness: 1579.14, damping: 0.002 }],
+                })
+                .unwrap();
+            network.parameter_mut("Net1: F1").unwrap().set_link("Bow1: force");
+            container.container.push(Box::new(network));
+            registry.add("BOW", &mut container).unwrap();
+            container.parameter_mut("Bow1: v").unwrap().set_link("Net1: v1");
+            container.parameter_mut("Bow1: pressure").unwrap().set_value(pressure);
+            container.parameter_mut("Bow1: curve").unwrap().set_value(curve as f32);
+            let mut engine = Engine::new(container);
+            engine.bind().unwrap();
+            assert!(engine.bind_output("Net1: v1"));
+            let slip = engine.gadget.parameter("Bow1: slip").unwrap().slot.unwrap();
+            let mut samples = vec![];
+            let mut slip_samples = vec![];
+            for _ in 0..48000 {
+                samples.push(engine.next().unwrap());
+                slip_samples.push(engine.signals[slip]);
+            }
+            (samples, slips(&slip_samples))
+        };
+        let energy = |x: &[f32]| x.iter().map(|x| x * x).sum::<f32>();
+        let silent = run(0.0, 0).0;
+        for curve in 0..BOW_CURVES.len() {
+            let (bowed, slip_count) = run(0.3, curve);
+            assert!(bowed.iter().all(|x| x.is_finite()));
+            // Sticking lengthens the period of the free oscillation (240 samples).
+            let lag = period(&bowed[43200..], 200, 600);
+            assert!((240..=360).contains(&lag), "curve {} period {}", curve, lag);
+            assert!(slip_count <= 4800 / lag + 1, "curve {} slips {}", curve, slip_count);
+            assert!(energy(&bowed[43200..]) > 0.5 * energy(&bowed[19200..24000]));
+            assert!(energy(&bowed[43200..]) > 100.0 * energy(&silent[43200..]));
+        }
+
+        // A bowed string (218 samples) settles into one slip per period with every curve when
+        // the bow solves the slip with the admittance of the string, 1 / (2 impedance).
+        let bow_string = |pressure: f32, curve: usize, admittance: f32| {
+            let registry = GadgetRegistry::standard();
+            let mut container = GadgetContainer::new();
+            registry.add("WG", &mut container).unwrap();
+            registry.add("BOW", &mut container).unwrap();
+            container.parameter_mut("String1: frequency").unwrap().set_value(220.0);
+            container.parameter_mut("String1: force").unwrap().set_link("Bow1: force");
+            container.parameter_mut("Bow1: v").unwrap().set_link("String1: velocity");
+            container.parameter_mut("Bow1: pressure").unwrap().set_value(pressure);
+            container.parameter_mut("Bow1: curve").unwrap().set_value(curve as f32);
+            container.parameter_mut("Bow1: admittance").unwrap().set_value(admittance);
+            let mut engine = Engine::new(container);
+            engine.bind().unwrap();
+            assert!(engine.bind_output("String1: out"));
+            let slip = engine.gadget.parameter("Bow1: slip").unwrap().slot.unwrap();
+            let mut samples = vec![];
+            let mut slip_samples = vec![];
+            for _ in 0..48000 {
+                samples.push(engine.next().unwrap());
+                slip_samples.push(engine.signals[slip]);
+            }
+            let mean = samples[43200..].iter().sum::<f32>() / 4800.0;
+            let tail: Vec<f32> = samples[43200..].iter().map(|x| x - mean).collect();
+            (tail, slips(&slip_samples))
+        };
+        assert_eq!(energy(&bow_string(0.0, 0, 1.0).0), 0.0);
+        for curve in 0..BOW_CURVES.len() {
+            let (bowed, slip_count) = bow_string(0.5, curve, 1.0);
+            assert!(bowed.iter().all(|x| x.is_finite()));
+            let lag = period(&bowed, 150, 300);
+            assert!((216..=220).contains(&lag), "curve {} period {}", curve, lag);
+            assert!((22..=44).contains(&slip_count), "curve {} slips {}", curve, slip_count);
+            assert!(energy(&bowed) > 1e-4, "curve {} energy {}", curve, energy(&bowed));
+        }
+        // With the one sample delay alone the sticking branch of the steep curves chatters.
+        let (_, chatter) = bow_string(0.2, 2, 0.0);
+        assert!(chatter > 88, "slips {}", chatter);
+    }
+    #[test]
+    fn test_reed() {
+        let registry = GadgetRegistry::standard();
+        let energy = |x: &[f32]| x.iter().map(|x| x * x).sum::<f32>();
+        // Reed and tube play at the frequency of the tube (326.5 samples) above the threshold pressure.
+        let play = |pressure: f32| {
+            let mut container = GadgetContainer::new();
+            registry.add("WIND", &mut container).unwrap();
+            container.parameter_mut("Wind1: pressure").unwrap().set_value(pressure);
+            let mut engine = Engine::new(container);
+            engine.bind().unwrap();
+            assert!(engine.bind_output("Wind1: mouthpiece"));
+            let samples: Vec<f32> = engine.by_ref().take(24000).collect();
+            let mean = samples[19200..].iter().sum::<f32>() / 4800.0;
+            samples[19200..].iter().map(|x| x - mean).collect::<Vec<f32>>()
+        };
+        let blown = play(0.6);
+        assert!(blown.iter().all(|x| x.is_finite()));
+        let lag = period(&blown, 250, 400);
+        assert!((320..=333).contains(&lag), "period {}", lag);
+        assert!(energy(&blown) > 1000.0 * energy(&play(0.1)));
+
+        // The standalone reed drives a damped oscillator (218 samples) through links.
+        let drive = |pressure: f32| {
+            let mut container = GadgetContainer::new();
+            registry.add("REED", &mut container).unwrap();
+            registry.add("DO", &mut container).unwrap();
+            container.parameter_mut("Reed1: pressure").unwrap().set_value(pressure);
+            container.parameter_mut("Osc1: frequency").unwrap().set_value(220.0);
+            container.parameter_mut("Reed1: aperture").unwrap().set_value(2.0);
+            container.parameter_mut("Osc1: damp").unwrap().set_value(0.0002);
+            container.parameter_mut("Osc1: ys").unwrap().set_link("Reed1: flow");
+            container.parameter_mut("Reed1: bore").unwrap().set_link("Osc1: x");
+            let mut engine = Engine::new(container);
+            engine.bind().unwrap();
+            assert!(engine.bind_output("Osc1: x"));
+            engine.by_ref().take(96000).collect::<Vec<f32>>()
+        };
+        let samples = drive(0.8);
+        assert!(samples.iter().all(|x| x.is_finite()));
+        let lag = period(&samples[91200..], 150, 300);
+        assert!((216..=220).contains(&lag), "period {}", lag);
+        assert!(energy(&samples[91200..]) > 0.5 * energy(&samples[43200..48000]));
+        assert!(energy(&samples[91200..]) > 1000.0 * energy(&drive(0.0)[91200..]));
+    }
+    #[test]
+    fn test_contact() {
+        assert_eq!(contact_force(-0.1, 1.0, 1e5, 1.5, 0.5), 0.0);
+        assert_eq!(contact_force(0.01, -3.0, 1e5, 1.5, 0.5), 0.0);
+        assert!((contact_force(0.01, 0.0, 1e5, 1.5, 0.5) - 100.0).abs() < 1e-3);
+        assert!((contact_force(0.01, 1.0, 1e5, 1.5, 0.5) - 150.0).abs() < 1e-3);
+
+        // The force input of an oscillator acts on 1 kg: 1 N displaces a 100 Hz one by 1/omega^2.
+        let mut osc = DampedOscillatorGadget::new("Osc");
+        for (p, v) in [("Osc: frequency", 100.0), ("Osc: x", 0.0), ("Osc: force", 1.0)] {
+            osc.parameter_mut(p).unwrap().set_value(v);
+        }
+        let mut engine = Engine::new(osc);
+        engine.bind().unwrap();
+        assert!(engine.bind_output("Osc: x"));
+        let x: Vec<f32> = engine.by_ref().take(4800).collect();
+        let omega = 2.0 * std::f32::consts::PI * 100.0;
+        assert!((x[4799] * omega * omega - 1.0).abs() < 1e-3);
+        let v = engine.signals[engine.gadget.parameter("Osc: v").unwrap()];
+        assert!(v.abs() < 1e-6);
+
+        // An oscillator swinging with amplitude 1 mm (2.8 m/s) hits a second one at rest 0.5 mm away.
+        let registry = GadgetRegistry::standard();
+        let mut container = GadgetContainer::new();
+        registry.add("DO", &mut container).unwrap();
+        registry.add("DO", &mut container).unwrap();
+        registry.add("CONTACT", &mut container).unwrap();
+        for (p, v) in [
+            ("Osc1: damp", 0.0),
+            ("Osc1: x", 0.0),
+            ("Osc1: y", 0.001),
+            ("Osc2: damp", 0.0),
+            ("Osc2: frequency", 150.0),
+            ("Osc2: x", 0.0),
+            ("Contact1: gap", 0.0005),
+            ("Contact1: stiffness", 1e10),
+        ]
+        .iter()
+        {
+            container.parameter_mut(p).unwrap().set_value(*v);
+        }
+        for (p, target) in [
+            ("Contact1: x1", "Osc1: x"),
+            ("Contact1: v1", "Osc1: v"),
+            ("Contact1: x2", "Osc2: x"),
+            ("Contact1: v2", "Osc2: v"),
+            ("Osc1: force", "Contact1: f1"),
+            ("Osc2: force", "Contact1: f2"),
+        ]
+        .iter()
+        {
+            container.parameter_mut(p).unwrap().set_link(target);
+        }
+        let mut engine = Engine::new(container);
+        engine.bind().unwrap();
+        let slot = |name: &str| engine.gadget.parameter(name).unwrap().slot.unwrap();
+        let (x1, x2, depth) = (slot("Osc1: x"), slot("Osc2: x"), slot("Contact1: depth"));
+        let (mut max_depth, mut max_x2) = (0.0f32, 0.0f32);
+        for _ in 0..48000 {
+            engine.run();
+            assert!(engine.signals[x1].is_finite());
+            max_depth = max_depth.max(engine.signals[depth]);
+            max_x2 = max_x2.max(engine.signals[x2].abs());
+        }
+        assert!(max_depth > 0.0 && max_depth < 0.0003, "depth {}", max_depth);
+        assert!(max_x2 > 0.0001, "x2 {}", max_x2);
+        assert!(max_x2 < 0.002, "x2 {}", max_x2);
+    }
+    #[test]
+    fn test_hammer() {
+        let registry = GadgetRegistry::standard();
+        // Force pulse of a hammer striking a rigid surface.
+        let strike = |trigger: f32| {
+            let mut container = GadgetContainer::new();
+            registry.add("HAMMER", &mut container).unwrap();
+            container.parameter_mut("Hammer1: trigger").unwrap().set_value(trigger);
+            let mut engine = Engine::new(container);
+            engine.bind().unwrap();
+            assert!(engine.bind_output("Hammer1: force"));
+            let mut force: Vec<f32> = engine.by_ref().take(4800).collect();
+            // The held trigger strikes once, the next edge strikes again.
+            let slot = engine.gadget.parameter("Hammer1: trigger").unwrap().slot.unwrap();
+            assert_eq!(engine.signals[slot], trigger);
+            engine.signals[slot] = 0.0;
+            engine.next();
+            engine.signals[slot] = trigger;
+            force.extend(engine.by_ref().take(4800));
+            force
+        };
+        let peak = |x: &[f32]| x.iter().fold(0.0f32, |a, x| a.max(*x));
+        let hard = strike(1.0);
+        let soft = strike(0.5);
+        assert!(hard.iter().chain(soft.iter()).all(|f| f.is_finite() && *f >= 0.0));
+        // A 10 g hammer at 3 m/s on a felt of 1e7 N/m^2.5 touches for about 5 ms with a peak near 25 N.
+        let contact = hard[..4800].iter().filter(|f| **f > 0.0).count();
+        assert!((192..=336).contains(&contact), "contact {} samples", contact);
+        assert!((15.0..=40.0).contains(&peak(&hard)), "peak {}", peak(&hard));
+        assert_eq!(peak(&hard[2400..4800]), 0.0);
+        assert_eq!(peak(&hard[4800..]), peak(&hard[..4800]));
+        // Harder strikes are louder and shorter, hence brighter.
+        assert!(peak(&hard) > 2.2 * peak(&soft));
+        assert!(contact < soft[..4800].iter().filter(|f| **f > 0.0).count());
+
+        // Struck modal bank rings at its frequency.
+        let mut container = GadgetContainer::new();
+        registry.add("HAMMER", &mut container).unwrap();
+        registry.add("MB", &mut container).unwrap();
+        container.parameter_mut("Modes1: inp").unwrap().set_link("Hammer1: force");
+        container.parameter_mut("Hammer1: trigger").unwrap().set_value(1.0);
+        let mut engine = Engine::new(container);
+        engine.bind().unwrap();
+        assert!(engine.bind_output("Modes1: out"));
+        let samples: Vec<f32> = engine.by_ref().take(9600).collect();
+        assert!(samples[4800..].iter().any(|x| x.abs() > 1.0));
+    }
+
+    #[test]
+    fn test_envelopes() {
+        let registry = GadgetRegistry::standard();
+        let mut container = GadgetContainer::new();
+        registry.add("ADSR", &mut container).unwrap();
+        let mut engine = Engine::new(container);
+        engine.bind().unwrap();
+        assert!(engine.bind_output("ADSR1: out"));
+        let gate = engine.gadget.parameter("ADSR1: gate").unwrap().slot.unwrap();
+        let trigger = engine.gadget.parameter("ADSR1: trigger").unwrap().slot.unwrap();
+        let mode = engine.gadget.parameter("ADSR1: mode").unwrap().slot.unwrap();
+        // 10 ms attack, 200 ms decay to 0.5 and 500 ms release at 48 kHz.
+        engine.signals[gate] = 1.0;
+        let held: Vec<f32> = engine.by_ref().take(24000).collect();
+        assert!((held[239] - 0.5).abs() < 0.01);
+        assert!((held[479] - 1.0).abs() < 1e-4);
+        assert!((held[479 + 4800] - 0.75).abs() < 0.01);
+        assert!(held[10080..].iter().all(|x| (x - 0.5).abs() < 1e-4));
+        // Legato ignores triggers while the gate is on, retrigger restarts the attack.
+        engine.signals[mode] = 1.0;
+        engine.signals[trigger] = 1.0;
+        let legato: Vec<f32> = engine.by_ref().take(240).collect();
+        assert_eq!(engine.signals[trigger], 1.0);
+        assert!(legato.iter().all(|x| (x - 0.5).abs() < 1e-4));
+        engine.signals[mode] = 0.0;
+        engine.signals[trigger] = 0.0;
+        engine.next();
+        engine.signals[trigger] = 1.0;
+        let retrigger: Vec<f32> = engine.by_ref().take(240).collect();
+        // The attack restarts from the current level, halfway after half the attack time.
+        assert!((retrigger[239] - 0.75).abs() < 0.01);
+        engine.signals[gate] = 0.0;
+        let released: Vec<f32> = engine.by_ref().take(48000).collect();
+        assert!(released.windows(2).all(|w| w[1] <= w[0]));
+        // The release starts from the level of the interrupted attack.
+        assert!((released[11999] - 0.375).abs() < 0.01);
+        assert!(released[24100..].iter().all(|x| *x == 0.0));
+
+        // A trigger without gate plays the whole AR envelope.
+        let mut container = GadgetContainer::new();
+        registry.add("AR", &mut container).unwrap();
+        container.parameter_mut("AR1: trigger").unwrap().set_value(0.8);
+        let mut engine = Engine::new(container);
+        engine.bind().unwrap();
+        assert!(engine.bind_output("AR1: out"));
+        let samples: Vec<f32> = engine.by_ref().take(48000).collect();
+        assert!((samples[239] - 1.0).abs() < 1e-4);
+        // The exponential release falls fast at first.
+        assert!(samples[240 + 4800] < 0.4);
+        assert!(samples[240 + 24100..].iter().all(|x| *x == 0.0));
+
+        let mut envelope = MultiSegmentGadget::new("Env1");
+        let segment = |level, time| Segment {
+            level,
+            time,
+            exponential: false,
+        };
+        let too_late = EnvelopeShape {
+            segments: vec![segment(1.0, 0.1)],
+            sustain: Some(1),
+        };
+        assert!(envelope.set_shape(too_late).is_err());
+        envelope
+            .set_shape(EnvelopeShape {
+                segments: vec![segment(1.0, 0.1), segment(-1.0, 0.1), segment(0.0, 0.1)],
+                sustain: None,
+            })
+            .unwrap();
+        let mut container = GadgetContainer::new();
+        container.container.push(Box::new(envelope));
+        let patch = Patch::from_container(&container);
+        let loaded = Patch::from_json(&patch.to_json().unwrap()).unwrap();
+        assert_eq!(loaded, patch);
+        let mut engine = loaded.to_engine(&registry, 48000).unwrap();
+        assert!(engine.bind_output("Env1: out"));
+        let gate = engine.gadget.parameter("Env1: gate").unwrap().slot.unwrap();
+        engine.signals[gate] = 1.0;
+        let samples: Vec<f32> = engine.by_ref().take(19200).collect();
+        assert!((samples[4799] - 1.0).abs() < 1e-4);
+        assert!((samples[9599] + 1.0).abs() < 0.01);
+        // Without sustain point the envelope runs to its end with the gate on.
+        assert!(samples[14500..].iter().all(|x| *x == 0.0));
+
+        // Triggers are not consumed, one trigger starts an envelope and strikes a hammer.
+        let mut container = GadgetContainer::new();
+        registry.add("AR", &mut container).unwrap();
+        registry.add("HAMMER", &mut container).unwrap();
+        container.parameter_mut("Hammer1: trigger").unwrap().set_link("AR1: trigger");
+        container.parameter_mut("AR1: trigger").unwrap().set_value(1.0);
+        let mut engine = Engine::new(container);
+        engine.bind().unwrap();
+        assert!(engine.bind_output("AR1: out"));
+        let slot = engine.gadget.parameter("Hammer1: force").unwrap().slot.unwrap();
+        let mut force = 0.0f32;
+        let envelope: Vec<f32> = (0..480)
+            .map(|_| {
+                let x = engine.next().unwrap();
+                force = force.max(engine.signals[slot]);
+                x
+            })
+            .collect();
+        assert!((envelope[239] - 1.0).abs() < 1e-4);
+        assert!(force > 1.0);
+    }
+
+    #[test]
+    fn test_lfo() {
+        let registry = GadgetRegistry::standard();
+        let run = |shape: f32, settings: &[(&str, f32)], n: usize| {
+            let mut container = GadgetContainer::new();
+            registry.add("LFO", &mut container).unwrap();
+            container.parameter_mut("LFO1: shape").unwrap().set_value(shape);
+            container.parameter_mut("LFO1: rate").unwrap().set_value(10.0);
+            for (name, value) in settings {
+                container.parameter_mut(name).unwrap().set_value(*value);
+            }
+            let mut engine = Engine::new(container);
+            engine.bind().unwrap();
+            assert!(engine.bind_output("LFO1: out"));
+            engine.by_ref().take(n).collect::<Vec<f32>>()
+        };
+        // 10 Hz waveforms repeat every 4800 samples.
+        for (shape, name) in LFO_SHAPES[..4].iter().enumerate() {
+            let samples = run(shape as f32, &[("LFO1: depth", 0.25), ("LFO1: offset", 0.5)], 9600);
+            let (min, max) = samples.iter().fold((1.0f32, 0.0f32), |(a, b), x| (a.min(*x), b.max(*x)));
+            assert!((min - 0.25).abs() < 0.01 && (max - 0.75).abs() < 0.01, "{}", name);
+            assert!((samples[1300] - samples[6100]).abs() < 0.01, "{}", name);
+        }
+        // The phase shifts the sine by a quarter cycle to a cosine.
+        let cosine = run(0.0, &[("LFO1: phase", 0.25)], 10);
+        assert!((cosine[0] - 1.0).abs() < 1e-4);
+        // Sample & hold keeps a value per cycle, smooth random glides between them.
+        let held = run(4.0, &[], 48000);
+        let values: Vec<f32> = held.chunks(4800).map(|c| c[100]).collect();
+        assert!(held.chunks(4800).all(|c| c[1..4790].iter().all(|x| *x == c[100])));
+        assert!(values.windows(2).all(|w| w[0] != w[1]));
+        assert!(values.iter().all(|x| (-1.0..=1.0).contains(x)));
+        let smooth = run(5.0, &[], 48000);
+        assert!(smooth.windows(2).all(|w| (w[1] - w[0]).abs() < 0.01));
+        assert!(smooth.iter().any(|x| (x - smooth[0]).abs() > 0.1));
+        // A sync late in the cycle draws one new value like a wrap. Preparing again restarts the
+        // random sequence and forgets the sync, which then restarts the cycle once more.
+        let mut container = GadgetContainer::new();
+        registry.add("LFO", &mut container).unwrap();
+        container.parameter_mut("LFO1: shape").unwrap().set_value(4.0);
+        container.parameter_mut("LFO1: rate").unwrap().set_value(10.0);
+        let mut engine = Engine::new(container);
+        engine.bind().unwrap();
+        assert!(engine.bind_output("LFO1: out"));
+        let sync = engine.gadget.parameter("LFO1: sync").unwrap().slot.unwrap();
+        assert_eq!(engine.by_ref().nth(3600), Some(values[0]));
+        engine.signals[sync] = 1.0;
+        assert_eq!(engine.next(), Some(values[1]));
+        assert_eq!(engine.next(), Some(values[1]));
+        engine.set_sample_rate(48000);
+        assert_eq!(engine.next(), Some(values[1]));
+
+        // Sync restarts the cycle, a sine LFO sweeps the frequency of an oscillator.
+        let mut container = GadgetContainer::new();
+        registry.add("LFO", &mut container).unwrap();
+        registry.add("DO", &mut container).unwrap();
+        container.parameter_mut("LFO1: offset").unwrap().set_value(440.0);
+        container.parameter_mut("LFO1: depth").unwrap().set_value(20.0);
+        container.parameter_mut("Osc1: frequency").unwrap().set_link("LFO1: out");
+        let mut engine = Engine::new(container);
+        engine.bind().unwrap();
+        assert!(engine.bind_output("Osc1: x"));
+        let frequency = engine.gadget.parameter("Osc1: frequency").unwrap().slot.unwrap();
+        let sync = engine.gadget.parameter("LFO1: sync").unwrap().slot.unwrap();
+        let mut sweep = vec![];
+        for _ in 0..24000 {
+            engine.run();
+            sweep.push(engine.signals[frequency]);
+        }
+        assert!(sweep.iter().all(|f| (420.0..=460.0).contains(f)));
+        assert!((sweep[12000] - 460.0).abs() < 0.01);
+        engine.signals[sync] = 1.0;
+        engine.run();
+        assert!((engine.signals[frequency] - 440.0).abs() < 0.01);
+        // A held sync restarts once, the next edge restarts again.
+        (0..11999).for_each(|_| engine.run());
+        assert!((engine.signals[frequency] - 460.0).abs() < 0.01);
+        engine.signals[sync] = 0.0;
+        engine.run();
+        engine.signals[sync] = 1.0;
+        engine.run();
+        assert!((engine.signals[frequency] - 440.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_filters() {
+        let registry = GadgetRegistry::standard();
+        // Amplitude of the outputs for a sine input once the transient is gone, from the RMS.
+        let response = |id: &str, settings: &[(&str, f32)], outputs: &[&str], frequency: f32| {
+            let mut container = GadgetContainer::new();
+            let name = registry.add(id, &mut container).unwrap();
+            for (pname, value) in settings {
+                let pname = format!("{}: {}", name, pname);
+                container.parameter_mut(&pname).unwrap().set_value(*value);
+            }
+            let mut engine = Engine::new(container);
+            engine.bind().unwrap();
+            let slot = |pname: &str| {
+                let pname = format!("{}: {}", name, pname);
+                engine.gadget.parameter(&pname).unwrap().slot.unwrap()
+            };
+            let inp = slot("inp");
+            let outputs: Vec<usize> = outputs.iter().map(|o| slot(o)).collect();
+            let mut power = vec![0.0f32; outputs.len()];
+            for i in 0..19200 {
+                engine.signals[inp] = (2.0 * std::f32::consts::PI * frequency * i as f32 / 48000.0).sin();
+                engine.run();
+                if i >= 9600 {
+                    for (power, out) in power.iter_mut().zip(outputs.iter()) {
+                        *power += engine.signals[*out].powi(2);
+                    }
+                }
+            }
+            power.iter().map(|p| (p / 4800.0).sqrt()).collect::<Vec<f32>>()
+        };
+        let close = |a: f32, b: f32, tolerance: f32| (a - b).abs() < tolerance;
+        let outputs = ["lp", "hp", "bp", "notch"];
+        let low = response("SVF", &[], &outputs, 100.0);
+        assert!(close(low[0], 1.0, 0.01) && low[1] < 0.02, "{:?}", low);
+        let high = response("SVF", &[], &outputs, 10000.0);
+        assert!(high[0] < 0.02 && close(high[1], 1.0, 0.02), "{:?}", high);
+        let centre = response("SVF", &[], &outputs, 1000.0);
+        assert!(close(centre[2], 1.0, 0.01) && centre[3] < 0.01, "{:?}", centre);
+        // Resonance raises the lowpass to Q at the cutoff.
+        let resonant = response("SVF", &[("resonance", 0.9)], &outputs, 1000.0);
+        assert!(close(resonant[0], 1.0 / 0.218, 0.1), "{:?}", resonant);
+
+        let response_of = |name| BIQUAD_TYPES.iter().position(|t| *t == name).unwrap() as f32;
+        let biquad = |settings: &[(&str, f32)], frequency| response("BIQ", settings, &["out"], frequency)[0];
+        assert!(close(biquad(&[], 1000.0), 0.707, 0.01));
+        assert!(biquad(&[("cutoff", 100.0)], 1000.0) < 0.011);
+        assert!(close(biquad(&[("response", response_of("Allpass"))], 3000.0), 1.0, 0.01));
+        assert!(close(biquad(&[("response", response_of("Peaking")), ("gain", 12.0)], 1000.0), 3.98, 0.05));
+        assert!(close(biquad(&[("response", response_of("Low shelf")), ("gain", 6.0), ("cutoff", 200.0)], 30.0), 2.0, 0.05));
+        assert!(close(biquad(&[("response", response_of("High shelf")), ("gain", -6.0)], 10000.0), 0.5, 0.02));
+
+        let ladder = |settings: &[(&str, f32)], frequency| response("LADDER", settings, &["out"], frequency)[0];
+        assert!(close(ladder(&[("drive", 0.1)], 50.0), 1.0, 0.01));
+        // Four poles are down by about 48 dB two octaves above the cutoff.
+        assert!(ladder(&[("drive", 0.1)], 4000.0) < 0.005);
+        // The saturation limits the output of a driven ladder.
+        assert!(ladder(&[("drive", 10.0)], 100.0) < 0.2);
+
+        // Self-oscillation at the cutoff after a kick.
+        let mut container = GadgetContainer::new();
+        registry.add("LADDER", &mut container).unwrap();
+        container.parameter_mut("Ladder1: resonance").unwrap().set_value(1.1);
+        container.parameter_mut("Ladder1: inp").unwrap().set_value(1.0);
+        let mut engine = Engine::new(container);
+        engine.bind().unwrap();
+        assert!(engine.bind_output("Ladder1: out"));
+        engine.run();
+        let inp = engine.gadget.parameter("Ladder1: inp").unwrap().slot.unwrap();
+        engine.signals[inp] = 0.0;
+        let samples: Vec<f32> = engine.by_ref().take(48000).collect();
+        assert!(samples[43200..].iter().any(|x| x.abs() > 0.1));
+        let lag = period(&samples[43200..], 30, 70);
+        assert!((46..=50).contains(&lag), "period {}", lag);
+
+        // Cutoffs swept at audio rate by an LFO through the whole range stay bounded.
+        for (id, settings) in [
+            ("SVF", ("resonance", 1.0)),
+            ("BIQ", ("q", 20.0)),
+            ("LADDER", ("resonance", 1.1)),
+        ] {
+            let mut container = GadgetContainer::new();
+            registry.add("LFO", &mut container).unwrap();
+            registry.add("LFO", &mut container).unwrap();
+            let name = registry.add(id, &mut container).unwrap();
+            let lfos = [
+                ("LFO1: rate", 3000.0),
+                ("LFO1: offset", 10000.0),
+                ("LFO1: depth", 10000.0),
+                ("LFO2: rate", 440.0),
+                ("LFO2: shape", 2.0),
+            ];
+            for (pname, value) in lfos {
+                container.parameter_mut(pname).unwrap().set_value(value);
+            }
+            for (pname, link) in [("cutoff", "LFO1: out"), ("inp", "LFO2: out")] {
+                let pname = format!("{}: {}", name, pname);
+                container.parameter_mut(&pname).unwrap().set_link(link);
+            }
+            let pname = format!("{}: {}", name, settings.0);
+            container.parameter_mut(&pname).unwrap().set_value(settings.1);
+            let mut engine = Engine::new(container);
+            engine.bind().unwrap();
+            let out = if id == "SVF" { "lp" } else { "out" };
+            assert!(engine.bind_output(&format!("{}: {}", name, out)));
+            let samples: Vec<f32> = engine.by_ref().take(48000).collect();
+            let peak = samples.iter().fold(0.0f32, |a, x| a.max(x.abs()));
+            assert!(peak.is_finite() && peak < 50.0, "{} peak {}", id, peak);
+        }
     }
 }
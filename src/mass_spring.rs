@@ -0,0 +1,402 @@
+use crate::gadget::*;
+use crate::integrators::*;
+use crate::registry::*;
+use egui::Ui;
+use serde::{Deserialize, Serialize};
+
+pub fn register(registry: &mut GadgetRegistry) {
+    registry.register(GadgetType {
+        id: "MSN",
+        display_name: "Mass-spring network",
+        category: "Resonators",
+        description:
+            "Point masses connected by springs and dampers, with force inputs and motion outputs",
+        prefix: "Net",
+        constructor: |name| Box::new(MassSpringGadget::new(name)),
+    });
+}
+
+/// Largest number of masses in a network.
+pub const MAX_MASSES: usize = 64;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Mass {
+    /// Mass in kg.
+    pub mass: f32,
+    /// Fixed masses do not move, springs attached to them act as anchors.
+    #[serde(default)]
+    pub fixed: bool,
+    /// Exposes the force input and the displacement and velocity outputs of the mass.
+    #[serde(default)]
+    pub port: bool,
+}
+
+/// Spring with a parallel damper between two masses, or between a mass and the ground.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Spring {
+    pub a: usize,
+    /// None anchors the spring to the ground.
+    pub b: Option<usize>,
+    /// Stiffness in N/m.
+    pub stiffness: f32,
+    /// Damping in N s/m.
+    pub damping: f32,
+}
+
+/// Masses and springs of a network, all masses move along one axis around their rest position.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Topology {
+    pub masses: Vec<Mass>,
+    pub springs: Vec<Spring>,
+}
+
+impl Topology {
+    /// Chain of `n` masses between two anchors, with ports on the first and the last mass.
+    pub fn chain(n: usize, mass: f32, stiffness: f32, damping: f32) -> Topology {
+        let masses = (0..n)
+            .map(|i| Mass {
+                mass,
+                fixed: false,
+                port: i == 0 || i + 1 == n,
+            })
+            .collect();
+        let spring = |a, b| Spring {
+            a,
+            b,
+            stiffness,
+            damping,
+        };
+        let mut springs = vec![spring(0, None)];
+        springs.extend((1..n).map(|i| spring(i - 1, Some(i))));
+        springs.push(spring(n - 1, None));
+        Topology { masses, springs }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.masses.is_empty() || self.masses.len() > MAX_MASSES {
+            return Err(format!("A network has 1 to {} masses", MAX_MASSES));
+        }
+        for (i, m) in self.masses.iter().enumerate() {
+            if m.mass.is_nan() || m.mass <= 0.0 {
+                return Err(format!("Mass {} must be positive", i + 1));
+            }
+        }
+        for s in self.springs.iter() {
+            let n = self.masses.len();
+            if s.a >= n || s.b.map(|b| b >= n || b == s.a).unwrap_or(false) {
+                return Err(format!("Spring {:?} connects unknown masses", s));
+            }
+        }
+        Ok(())
+    }
+
+    /// Upper bound of the angular frequencies of the network (Gershgorin), in rad/s.
+    pub fn max_omega(&self, tension: f32) -> f32 {
+        let mut stiffness = vec![0.0f32; self.masses.len()];
+        for s in self.springs.iter() {
+            stiffness[s.a] += 2.0 * s.stiffness.abs();
+            if let Some(b) = s.b {
+                stiffness[b] += 2.0 * s.stiffness.abs();
+            }
+        }
+        self.masses
+            .iter()
+            .zip(stiffness.iter())
+            .filter(|(m, _)| !m.fixed)
+            .map(|(m, k)| (k * tension.abs() / m.mass).sqrt())
+            .fold(0.0, f32::max)
+    }
+}
+
+/// Equations of motion: q are the displacements, p the velocities of the masses.
+struct Network<'a> {
+    topology: &'a Topology,
+    external: &'a [f32],
+    tension: f32,
+    damping: f32,
+}
+
+impl<'a> Dynamics for Network<'a> {
+    fn derivative(&self, q: &[f32], p: &[f32], dq: &mut [f32], dp: &mut [f32]) {
+        dq.copy_from_slice(p);
+        dp.copy_from_slice(self.external);
+        for s in self.topology.springs.iter() {
+            let (x, v) = match s.b {
+                Some(b) => (q[b] - q[s.a], p[b] - p[s.a]),
+                None => (-q[s.a], -p[s.a]),
+            };
+            let f = self.tension * s.stiffness * x + self.damping * s.damping * v;
+            dp[s.a] += f;
+            if let Some(b) = s.b {
+                dp[b] -= f;
+            }
+        }
+        for (i, m) in self.topology.masses.iter().enumerate() {
+            if m.fixed {
+                dq[i] = 0.0;
+                dp[i] = 0.0;
+            } else {
+                dp[i] /= m.mass;
+            }
+        }
+    }
+}
+
+/// Network of point masses connected by springs and dampers.
+///
+/// Every mass with a port has a force input `F<n>` (in N) and the outputs `x<n>` (displacement in m)
+/// and `v<n>` (velocity in m/s), masses are numbered from 1.
+/// `tension` and `damping` scale all springs and dampers and can be modulated while playing;
+/// changes of the topology take effect when the patch is bound again.
+#[derive(Clone)]
+pub struct MassSpringGadget {
+    topology: Topology,
+    integrator: Parameter,
+    tension: Parameter,
+    damping: Parameter,
+    /// Force, displacement and velocity of every port, in the order of the masses.
+    ports: Vec<(usize, [Parameter; 3])>,
+    dt: f32,
+    x: Vec<f32>,
+    v: Vec<f32>,
+    external: Vec<f32>,
+    workspace: Workspace,
+    instance_name: String,
+}
+
+const FIXED_PARAMETERS: usize = 3;
+
+impl MassSpringGadget {
+    pub fn new(instance_name: &str) -> Self {
+        let mut gadget = MassSpringGadget {
+            topology: Topology::chain(4, 0.001, 10000.0, 0.01),
+            integrator: Parameter::new("integrator", 0.0).with_choices(INTEGRATOR_NAMES),
+            tension: Parameter::new("tension", 1.0).with_range(0.0, 10.0),
+            damping: Parameter::new("damping", 1.0).with_range(0.0, 10.0),
+            ports: Vec::new(),
+            dt: 1.0 / DEFAULT_SAMPLE_RATE as f32,
+            x: Vec::new(),
+            v: Vec::new(),
+            external: Vec::new(),
+            workspace: Workspace::default(),
+            instance_name: instance_name.to_owned(),
+        };
+        gadget.update_ports();
+        gadget
+    }
+    pub fn topology(&self) -> &Topology {
+        &self.topology
+    }
+    /// Replaces the topology, parameters of ports that still exist keep their values and links.
+    pub fn set_topology(&mut self, topology: Topology) -> Result<(), String> {
+        topology.validate()?;
+        self.topology = topology;
+        self.update_ports();
+        Ok(())
+    }
+
+    fn update_ports(&mut self) {
+        let mut old = std::mem::take(&mut self.ports);
+        for (i, m) in self.topology.masses.iter().enumerate() {
+            if !m.port {
+                continue;
+            }
+            let parameters = match old.iter().position(|(j, _)| *j == i) {
+                Some(k) => old.swap_remove(k).1,
+                None => {
+                    let n = i + 1;
+                    [
+                        Parameter::new(intern(&format!("F{}", n)), 0.0).with_units("N"),
+                        Parameter::new(intern(&format!("x{}", n)), 0.0)
+                            .with_units("m")
+                            .as_output(),
+                        Parameter::new(intern(&format!("v{}", n)), 0.0)
+                            .with_units("m/s")
+                            .as_output(),
+                    ]
+                }
+            };
+            self.ports.push((i, parameters));
+        }
+        let n = self.topology.masses.len();
+        self.x = vec![0.0; n];
+        self.v = vec![0.0; n];
+        self.external = vec![0.0; n];
+        self.workspace = Workspace::new(n);
+    }
+
+    fn topology_gui(&mut self, ui: &mut Ui) {
+        let mut topology = self.topology.clone();
+        let n = topology.masses.len();
+        ui.label("Masses");
+        let mut remove_mass = None;
+        egui::Grid::new(format!("_Masses_{}", self.instance_name)).show(ui, |ui| {
+            for (i, m) in topology.masses.iter_mut().enumerate() {
+                ui.label(format!("{}", i + 1));
+                ui.add(
+                    egui::widgets::DragValue::new(&mut m.mass)
+                        .speed(0.0001)
+                        .clamp_range(1e-6..=100.0)
+                        .suffix(" kg"),
+                );
+                ui.checkbox(&mut m.fixed, "fixed");
+                ui.checkbox(&mut m.port, "port");
+                if n > 1 && ui.button("Remove").clicked() {
+                    remove_mass = Some(i);
+                }
+                ui.end_row();
+            }
+        });
+        if n < MAX_MASSES && ui.button("Add mass").clicked() {
+            let mass = topology.masses.last().map(|m| m.mass).unwrap_or(0.001);
+            topology.masses.push(Mass {
+                mass,
+                fixed: false,
+                port: false,
+            });
+        }
+        ui.label("Springs (mass 0 is the ground)");
+        let mut remove_spring = None;
+        egui::Grid::new(format!("_Springs_{}", self.instance_name)).show(ui, |ui| {
+            for (i, s) in topology.springs.iter_mut().enumerate() {
+                let mut a = s.a + 1;
+                let mut b = s.b.map(|b| b + 1).unwrap_or(0);
+                ui.add(egui::widgets::DragValue::new(&mut a).clamp_range(1..=n));
+                ui.add(egui::widgets::DragValue::new(&mut b).clamp_range(0..=n));
+                s.a = a - 1;
+                s.b = if b == 0 { None } else { Some(b - 1) };
+                ui.add(
+                    egui::widgets::DragValue::new(&mut s.stiffness)
+                        .speed(10.0)
+                        .suffix(" N/m"),
+                );
+                ui.add(
+                    egui::widgets::DragValue::new(&mut s.damping)
+                        .speed(0.001)
+                        .suffix(" Ns/m"),
+                );
+                if ui.button("Remove").clicked() {
+                    remove_spring = Some(i);
+                }
+                ui.end_row();
+            }
+        });
+        if ui.button("Add spring").clicked() {
+            let spring = topology.springs.last().cloned().unwrap_or(Spring {
+                a: 0,
+                b: None,
+                stiffness: 10000.0,
+                damping: 0.01,
+            });
+            topology.springs.push(spring);
+        }
+        if let Some(i) = remove_spring {
+            topology.springs.remove(i);
+        }
+        if let Some(i) = remove_mass {
+            topology.masses.remove(i);
+            topology.springs.retain(|s| s.a != i && s.b != Some(i));
+            for s in topology.springs.iter_mut() {
+                if s.a > i {
+                    s.a -= 1;
+                }
+                s.b = s.b.map(|b| if b > i { b - 1 } else { b });
+            }
+        }
+        if topology != self.topology {
+            match self.set_topology(topology) {
+                Ok(_) => {}
+                Err(e) => {
+                    ui.colored_label(egui::Color32::RED, e);
+                }
+            }
+        }
+        let tension = match self.tension.link {
+            Link::Value(x) => x,
+            Link::Link(_) => 1.0,
+        };
+        if self.topology.max_omega(tension) * self.dt > 2.0 {
+            ui.colored_label(
+                egui::Color32::YELLOW,
+                "The network may be too stiff for the sample rate (try RK4 or oversampling)",
+            );
+        }
+    }
+}
+
+impl GadgetParameters for MassSpringGadget {
+    fn name(&self) -> &'static str {
+        "MSN"
+    }
+    fn get_instance_name(&self) -> String {
+        self.instance_name.clone()
+    }
+    fn par(&self, i: usize) -> &Parameter {
+        match i {
+            0 => &self.integrator,
+            1 => &self.tension,
+            2 => &self.damping,
+            i => match self.ports.get((i - FIXED_PARAMETERS) / 3) {
+                Some((_, p)) => &p[(i - FIXED_PARAMETERS) % 3],
+                None => panic!("Invalid parameter number {} in {}", i, self.instance_name),
+            },
+        }
+    }
+    fn par_mut(&mut self, i: usize) -> &mut Parameter {
+        match i {
+            0 => &mut self.integrator,
+            1 => &mut self.tension,
+            2 => &mut self.damping,
+            i => match self.ports.get_mut((i - FIXED_PARAMETERS) / 3) {
+                Some((_, p)) => &mut p[(i - FIXED_PARAMETERS) % 3],
+                None => panic!("Invalid parameter number {} in {}", i, self.instance_name),
+            },
+        }
+    }
+    fn parameter_count(&self) -> usize {
+        FIXED_PARAMETERS + 3 * self.ports.len()
+    }
+}
+
+impl Gadget for MassSpringGadget {
+    fn config(&self) -> Option<serde_json::Value> {
+        serde_json::to_value(&self.topology).ok()
+    }
+    fn set_config(&mut self, config: &serde_json::Value) -> Result<(), String> {
+        let topology = serde_json::from_value(config.clone()).map_err(|e| e.to_string())?;
+        self.set_topology(topology)
+    }
+    fn prepare(&mut self, sample_rate: u32) {
+        self.dt = 1.0 / sample_rate as f32;
+    }
+    fn run(&mut self, s: &mut Signals) {
+        for (i, p) in self.ports.iter() {
+            self.external[*i] = s[&p[0]];
+        }
+        let system = Network {
+            topology: &self.topology,
+            external: &self.external,
+            tension: s[&self.tension],
+            damping: s[&self.damping],
+        };
+        let integrator = Integrator::from_value(s[&self.integrator]);
+        self.workspace
+            .step(integrator, &system, &mut self.x, &mut self.v, self.dt);
+        for (i, p) in self.ports.iter() {
+            s[&p[1]] = self.x[*i];
+            s[&p[2]] = self.v[*i];
+        }
+    }
+}
+
+impl GadgetUI for MassSpringGadget {
+    fn gui(&mut self, state: &mut GuiState, ui: &mut Ui) {
+        let name = self.instance_name.clone();
+        ui.collapsing(&name, |ui| {
+            parameter_grid(self, state, ui);
+            ui.collapsing("Topology", |ui| self.topology_gui(ui));
+        });
+    }
+}
+
+impl GadgetWithUI for MassSpringGadget {}
@@ -0,0 +1,376 @@
+use crate::gadget::*;
+use crate::registry::*;
+use egui::Ui;
+use physynth_derive::GadgetParameters;
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex};
+
+pub fn register(registry: &mut GadgetRegistry) {
+    registry.register(GadgetType {
+        id: "MESH",
+        display_name: "Membrane / plate",
+        category: "Resonators",
+        description: "Finite difference 2D mesh with rectangular or circular boundary, struck and picked up at a position",
+        prefix: "Mesh",
+        constructor: |name| Box::new(MeshGadget::new(name)),
+    });
+}
+
+/// Grid sizes allowed for each side of the mesh, in points.
+pub const MESH_POINTS: std::ops::RangeInclusive<usize> = 4..=128;
+/// Number of points outside the shape on every side, they stay at rest.
+const BORDER: usize = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MeshShape {
+    Rectangle,
+    /// Ellipse inscribed in the grid, a circle on a square grid.
+    Circle,
+}
+
+/// Geometry and material of the mesh, saved with the patch.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MeshSettings {
+    pub shape: MeshShape,
+    pub columns: usize,
+    pub rows: usize,
+    /// Width of the mesh in m, the grid spacing is `width / (columns - 1)`.
+    pub width: f32,
+    /// Surface density in kg/m^2.
+    pub density: f32,
+}
+
+impl Default for MeshSettings {
+    fn default() -> Self {
+        MeshSettings {
+            shape: MeshShape::Rectangle,
+            columns: 32,
+            rows: 32,
+            width: 0.3,
+            density: 0.25,
+        }
+    }
+}
+
+impl MeshSettings {
+    pub fn validate(&self) -> Result<(), String> {
+        if !MESH_POINTS.contains(&self.columns) || !MESH_POINTS.contains(&self.rows) {
+            return Err(format!(
+                "A mesh has {} to {} points per side",
+                MESH_POINTS.start(),
+                MESH_POINTS.end()
+            ));
+        }
+        if !(self.width.is_finite()
+            && self.width > 0.0
+            && self.density.is_finite()
+            && self.density > 0.0)
+        {
+            return Err("Width and density must be positive".to_owned());
+        }
+        Ok(())
+    }
+    /// Grid spacing in m.
+    pub fn spacing(&self) -> f32 {
+        self.width / (self.columns - 1) as f32
+    }
+    /// Whether the grid point (column, row) belongs to the vibrating surface, the edges are clamped.
+    pub fn inside(&self, column: usize, row: usize) -> bool {
+        if column == 0 || row == 0 || column + 1 >= self.columns || row + 1 >= self.rows {
+            return false;
+        }
+        match self.shape {
+            MeshShape::Rectangle => true,
+            MeshShape::Circle => {
+                let x = 2.0 * column as f32 / (self.columns - 1) as f32 - 1.0;
+                let y = 2.0 * row as f32 / (self.rows - 1) as f32 - 1.0;
+                x * x + y * y < 1.0
+            }
+        }
+    }
+}
+
+/// Squared Courant number of the scheme, it is stable when this does not exceed 1/2.
+///
+/// With wave speed `c` (m/s), stiffness `kappa` (m^2/s), spacing `h` and time step `dt`:
+/// (c dt / h)^2 + 8 (kappa dt / h^2)^2 <= 1/2.
+pub fn courant(c: f32, kappa: f32, h: f32, dt: f32) -> f32 {
+    let lambda = c * dt / h;
+    let mu = kappa * dt / (h * h);
+    lambda * lambda + 8.0 * mu * mu
+}
+
+/// Finite difference membrane, or plate when `stiffness` is positive.
+///
+/// The displacement follows u_tt = (tension / density) lap(u) - stiffness^2 lap(lap(u)) - 2 damping u_t
+/// plus the force `strike` (in N) applied at (`strike_x`, `strike_y`). `out` is the displacement
+/// at (`pickup_x`, `pickup_y`); positions are relative to the width and height of the mesh.
+/// When the settings violate the stability condition (see [`courant`]) the wave speed and
+/// stiffness are scaled down to the stability limit, which lowers the pitch.
+/// Changes of the grid take effect when the patch is bound again.
+#[derive(Clone, GadgetParameters)]
+#[gadget(name = "MESH", custom_ui)]
+pub struct MeshGadget {
+    settings: MeshSettings,
+    #[param(default = 1000.0, range = 0.0..=10000.0, units = "N/m")]
+    tension: Parameter,
+    #[param(default = 2.0, range = 0.0..=100.0, units = "1/s")]
+    damping: Parameter,
+    #[param(range = 0.0..=10.0, units = "m^2/s")]
+    stiffness: Parameter,
+    #[param(units = "N")]
+    strike: Parameter,
+    #[param(default = 0.4, range = 0.0..=1.0)]
+    strike_x: Parameter,
+    #[param(default = 0.4, range = 0.0..=1.0)]
+    strike_y: Parameter,
+    #[param(default = 0.7, range = 0.0..=1.0)]
+    pickup_x: Parameter,
+    #[param(default = 0.6, range = 0.0..=1.0)]
+    pickup_y: Parameter,
+    #[param(output)]
+    out: Parameter,
+    #[init(1.0 / DEFAULT_SAMPLE_RATE as f32)]
+    dt: f32,
+    #[init(DEFAULT_SAMPLE_RATE)]
+    sample_rate: u32,
+    /// Points per padded row.
+    stride: usize,
+    /// Indices of the points inside the shape, the grids are allocated by `prepare`.
+    interior: Vec<usize>,
+    u: Vec<f32>,
+    previous: Vec<f32>,
+    next: Vec<f32>,
+    laplacian: Vec<f32>,
+    /// Displacement shown by the GUI, written by the running copies every few milliseconds.
+    snapshot: Arc<Mutex<Vec<f32>>>,
+    countdown: u32,
+    instance_name: String,
+}
+
+impl MeshGadget {
+    pub fn settings(&self) -> &MeshSettings {
+        &self.settings
+    }
+    pub fn set_settings(&mut self, settings: MeshSettings) -> Result<(), String> {
+        settings.validate()?;
+        self.settings = settings;
+        self.allocate();
+        Ok(())
+    }
+    /// Squared Courant number for the current parameter values, see [`courant`].
+    pub fn courant(&self) -> f32 {
+        let value = |p: &Parameter| match p.link {
+            Link::Value(x) => x,
+            Link::Link(_) => 0.0,
+        };
+        let c = (value(&self.tension).max(0.0) / self.settings.density).sqrt();
+        courant(c, value(&self.stiffness), self.settings.spacing(), self.dt)
+    }
+
+    fn allocate(&mut self) {
+        let (columns, rows) = (self.settings.columns, self.settings.rows);
+        self.stride = columns + 2 * BORDER;
+        let len = self.stride * (rows + 2 * BORDER);
+        self.interior = (0..rows)
+            .flat_map(|r| (0..columns).map(move |c| (c, r)))
+            .filter(|&(c, r)| self.settings.inside(c, r))
+            .map(|(c, r)| (r + BORDER) * self.stride + c + BORDER)
+            .collect();
+        self.u = vec![0.0; len];
+        self.previous = vec![0.0; len];
+        self.next = vec![0.0; len];
+        self.laplacian = vec![0.0; len];
+        // Running copies keep their snapshot, a resized grid gets its own.
+        let points = columns * rows;
+        if self.snapshot.lock().map_or(true, |s| s.len() != points) {
+            self.snapshot = Arc::new(Mutex::new(vec![0.0; points]));
+        }
+    }
+    /// Padded index of the grid point nearest to a relative position.
+    fn point(&self, x: f32, y: f32) -> usize {
+        let (columns, rows) = (self.settings.columns, self.settings.rows);
+        let c = (x.clamp(0.0, 1.0) * (columns - 1) as f32).round() as usize;
+        let r = (y.clamp(0.0, 1.0) * (rows - 1) as f32).round() as usize;
+        (r + BORDER) * self.stride + c + BORDER
+    }
+    fn store_snapshot(&self) {
+        if let Ok(mut snapshot) = self.snapshot.try_lock() {
+            let columns = self.settings.columns;
+            if snapshot.len() != columns * self.settings.rows {
+                return;
+            }
+            for (r, row) in snapshot.chunks_mut(columns).enumerate() {
+                let start = (r + BORDER) * self.stride + BORDER;
+                row.copy_from_slice(&self.u[start..start + columns]);
+            }
+        }
+    }
+
+    fn settings_gui(&mut self, ui: &mut Ui) {
+        let mut settings = self.settings.clone();
+        egui::Grid::new(format!("_Mesh_{}", self.instance_name)).show(ui, |ui| {
+            ui.label("shape");
+            egui::ComboBox::from_id_source(format!("_Shape_{}", self.instance_name))
+                .selected_text(format!("{:?}", settings.shape))
+                .show_ui(ui, |ui| {
+                    ui.selectable_value(&mut settings.shape, MeshShape::Rectangle, "Rectangle");
+                    ui.selectable_value(&mut settings.shape, MeshShape::Circle, "Circle");
+                });
+            ui.end_row();
+            ui.label("grid");
+            ui.horizontal(|ui| {
+                ui.add(
+                    egui::widgets::DragValue::new(&mut settings.columns).clamp_range(MESH_POINTS),
+                );
+                ui.label("x");
+                ui.add(egui::widgets::DragValue::new(&mut settings.rows).clamp_range(MESH_POINTS));
+            });
+            ui.end_row();
+            ui.label("width");
+            ui.add(
+                egui::widgets::DragValue::new(&mut settings.width)
+                    .speed(0.01)
+                    .clamp_range(0.01..=10.0)
+                    .suffix(" m"),
+            );
+            ui.end_row();
+            ui.label("density");
+            ui.add(
+                egui::widgets::DragValue::new(&mut settings.density)
+                    .speed(0.01)
+                    .clamp_range(0.001..=100.0)
+                    .suffix(" kg/m^2"),
+            );
+            ui.end_row();
+        });
+        if settings != self.settings {
+            if let Err(e) = self.set_settings(settings) {
+                ui.colored_label(egui::Color32::RED, e);
+            }
+        }
+        let courant = self.courant();
+        if courant > 0.5 {
+            ui.colored_label(
+                egui::Color32::YELLOW,
+                format!(
+                    "Unstable: Courant number {:.2} > 0.5, use a coarser grid, lower tension or stiffness",
+                    courant
+                ),
+            );
+        } else {
+            ui.label(format!("Courant number {:.2} (stable up to 0.5)", courant));
+        }
+    }
+
+    fn heat_map(&self, ui: &mut Ui) {
+        let snapshot = self
+            .snapshot
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone();
+        let (columns, rows) = (self.settings.columns, self.settings.rows);
+        if snapshot.len() != columns * rows {
+            return;
+        }
+        let cell = (240.0 / columns.max(rows) as f32).max(1.0);
+        let size = egui::vec2(cell * columns as f32, cell * rows as f32);
+        let (rect, _) = ui.allocate_exact_size(size, egui::Sense::hover());
+        let painter = ui.painter_at(rect);
+        let scale = snapshot.iter().fold(1e-9f32, |a, x| a.max(x.abs()));
+        for r in 0..rows {
+            for c in 0..columns {
+                let color = if self.settings.inside(c, r) {
+                    let v = snapshot[r * columns + c] / scale;
+                    let level = (255.0 * v.abs().min(1.0)) as u8;
+                    if v >= 0.0 {
+                        egui::Color32::from_rgb(level, 0, 0)
+                    } else {
+                        egui::Color32::from_rgb(0, 0, level)
+                    }
+                } else {
+                    egui::Color32::from_gray(60)
+                };
+                let min = rect.min + egui::vec2(c as f32 * cell, r as f32 * cell);
+                painter.rect_filled(
+                    egui::Rect::from_min_size(min, egui::vec2(cell, cell)),
+                    0.0,
+                    color,
+                );
+            }
+        }
+    }
+}
+
+impl Gadget for MeshGadget {
+    fn config(&self) -> Option<serde_json::Value> {
+        serde_json::to_value(&self.settings).ok()
+    }
+    fn set_config(&mut self, config: &serde_json::Value) -> Result<(), String> {
+        let settings = serde_json::from_value(config.clone()).map_err(|e| e.to_string())?;
+        self.set_settings(settings)
+    }
+    fn prepare(&mut self, sample_rate: u32) {
+        self.sample_rate = sample_rate;
+        self.dt = 1.0 / sample_rate as f32;
+        self.allocate();
+    }
+    fn run(&mut self, s: &mut Signals) {
+        let dt = self.dt;
+        let h = self.settings.spacing();
+        let mut c = (s[&self.tension].max(0.0) / self.settings.density).sqrt();
+        let mut kappa = s[&self.stiffness].max(0.0);
+        let number = courant(c, kappa, h, dt);
+        if number > 0.5 {
+            let scale = (0.5 / number).sqrt();
+            c *= scale;
+            kappa *= scale;
+        }
+        let lambda2 = (c * dt / h).powi(2);
+        let mu2 = (kappa * dt / (h * h)).powi(2);
+        let loss = s[&self.damping].max(0.0) * dt;
+        let stride = self.stride;
+
+        let u = &self.u;
+        for &i in self.interior.iter() {
+            self.laplacian[i] = u[i - 1] + u[i + 1] + u[i - stride] + u[i + stride] - 4.0 * u[i];
+        }
+        let lap = &self.laplacian;
+        for &i in self.interior.iter() {
+            let mut accel = lambda2 * lap[i];
+            if mu2 > 0.0 {
+                accel -= mu2
+                    * (lap[i - 1] + lap[i + 1] + lap[i - stride] + lap[i + stride] - 4.0 * lap[i]);
+            }
+            self.next[i] = (2.0 * u[i] - (1.0 - loss) * self.previous[i] + accel) / (1.0 + loss);
+        }
+        let strike = self.point(s[&self.strike_x], s[&self.strike_y]);
+        if self.interior.binary_search(&strike).is_ok() {
+            // Force spread over one grid cell.
+            self.next[strike] +=
+                dt * dt * s[&self.strike] / (self.settings.density * h * h) / (1.0 + loss);
+        }
+        std::mem::swap(&mut self.previous, &mut self.u);
+        std::mem::swap(&mut self.u, &mut self.next);
+
+        s[&self.out] = self.u[self.point(s[&self.pickup_x], s[&self.pickup_y])];
+        if self.countdown == 0 {
+            self.store_snapshot();
+            self.countdown = (self.sample_rate / 60).max(1);
+        }
+        self.countdown -= 1;
+    }
+}
+
+impl GadgetUI for MeshGadget {
+    fn gui(&mut self, state: &mut GuiState, ui: &mut Ui) {
+        let name = self.instance_name.clone();
+        ui.collapsing(&name, |ui| {
+            parameter_grid(self, state, ui);
+            ui.collapsing("Mesh", |ui| self.settings_gui(ui));
+            self.heat_map(ui);
+        });
+    }
+}
+
+impl GadgetWithUI for MeshGadget {}
@@ -0,0 +1,290 @@
+use midir::{Ignore, MidiInput, MidiInputConnection};
+use rtrb::{Consumer, Producer, RingBuffer};
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+/// Maximum number of MIDI mappings per engine, the table is allocated once when binding.
+pub const MAX_MIDI_MAPPINGS: usize = 128;
+
+/// Raw MIDI channel message (up to three bytes), small enough to be passed through lock-free queues.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct MidiMessage {
+    pub data: [u8; 3],
+    pub len: u8,
+}
+
+impl MidiMessage {
+    pub fn new(bytes: &[u8]) -> Self {
+        let mut data = [0; 3];
+        let len = bytes.len().min(3);
+        data[..len].copy_from_slice(&bytes[..len]);
+        MidiMessage {
+            data,
+            len: len as u8,
+        }
+    }
+    pub fn event(&self) -> Option<MidiEvent> {
+        MidiEvent::parse(&self.data[..self.len as usize])
+    }
+}
+
+/// Decoded channel voice message.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MidiEvent {
+    NoteOn { channel: u8, note: u8, velocity: u8 },
+    NoteOff { channel: u8, note: u8 },
+    ControlChange { channel: u8, controller: u8, value: u8 },
+    /// Bend from -8192 to 8191.
+    PitchBend { channel: u8, value: i16 },
+    ChannelPressure { channel: u8, value: u8 },
+    PolyPressure { channel: u8, note: u8, value: u8 },
+}
+
+impl MidiEvent {
+    pub fn parse(bytes: &[u8]) -> Option<MidiEvent> {
+        let status = *bytes.first()?;
+        let channel = status & 0x0f;
+        let data = |i: usize| bytes.get(i).map(|x| x & 0x7f);
+        match status & 0xf0 {
+            0x80 => Some(MidiEvent::NoteOff {
+                channel,
+                note: data(1)?,
+            }),
+            0x90 => match data(2)? {
+                0 => Some(MidiEvent::NoteOff {
+                    channel,
+                    note: data(1)?,
+                }),
+                velocity => Some(MidiEvent::NoteOn {
+                    channel,
+                    note: data(1)?,
+                    velocity,
+                }),
+            },
+            0xa0 => Some(MidiEvent::PolyPressure {
+                channel,
+                note: data(1)?,
+                value: data(2)?,
+            }),
+            0xb0 => Some(MidiEvent::ControlChange {
+                channel,
+                controller: data(1)?,
+                value: data(2)?,
+            }),
+            0xd0 => Some(MidiEvent::ChannelPressure {
+                channel,
+                value: data(1)?,
+            }),
+            0xe0 => Some(MidiEvent::PitchBend {
+                channel,
+                value: ((data(2)? as i16) << 7 | data(1)? as i16) - 8192,
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// Controller that can be bound to a parameter with MIDI learn.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum MidiSource {
+    ControlChange { channel: u8, controller: u8 },
+    PitchBend { channel: u8 },
+    Aftertouch { channel: u8 },
+}
+
+impl MidiSource {
+    /// Source of a learnable event, notes are not learnable.
+    pub fn learn(event: &MidiEvent) -> Option<MidiSource> {
+        match *event {
+            MidiEvent::ControlChange {
+                channel,
+                controller,
+                ..
+            } => Some(MidiSource::ControlChange {
+                channel,
+                controller,
+            }),
+            MidiEvent::PitchBend { channel, .. } => Some(MidiSource::PitchBend { channel }),
+            MidiEvent::ChannelPressure { channel, .. } => {
+                Some(MidiSource::Aftertouch { channel })
+            }
+            _ => None,
+        }
+    }
+    /// Controller position between 0 and 1 if the event comes from this source.
+    pub fn position(&self, event: &MidiEvent) -> Option<f32> {
+        match (*self, *event) {
+            (
+                MidiSource::ControlChange {
+                    channel,
+                    controller,
+                },
+                MidiEvent::ControlChange {
+                    channel: c,
+                    controller: n,
+                    value,
+                },
+            ) if channel == c && controller == n => Some(value as f32 / 127.0),
+            (MidiSource::PitchBend { channel }, MidiEvent::PitchBend { channel: c, value })
+                if channel == c =>
+            {
+                Some((value as f32 + 8192.0) / 16383.0)
+            }
+            (
+                MidiSource::Aftertouch { channel },
+                MidiEvent::ChannelPressure { channel: c, value },
+            ) if channel == c => Some(value as f32 / 127.0),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for MidiSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MidiSource::ControlChange {
+                channel,
+                controller,
+            } => write!(f, "CC {} ch {}", controller, channel + 1),
+            MidiSource::PitchBend { channel } => write!(f, "Bend ch {}", channel + 1),
+            MidiSource::Aftertouch { channel } => write!(f, "Aftertouch ch {}", channel + 1),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum MidiCurve {
+    Linear,
+    /// Geometric interpolation between min and max, both must have the same sign.
+    Exponential,
+    /// Every press (position rising to at least one half) switches between min and max.
+    Toggle,
+}
+
+/// Mapping of a MIDI controller onto the range of a parameter.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct MidiBinding {
+    pub source: MidiSource,
+    pub min: f32,
+    pub max: f32,
+    pub curve: MidiCurve,
+}
+
+impl MidiBinding {
+    pub fn new(source: MidiSource, min: f32, max: f32) -> Self {
+        MidiBinding {
+            source,
+            min,
+            max,
+            curve: MidiCurve::Linear,
+        }
+    }
+}
+
+/// Binding of a controller to a slot of the signal arena, as applied on the audio thread.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MidiMapping {
+    pub slot: usize,
+    pub binding: MidiBinding,
+    toggled: bool,
+    /// Last position was at least one half, repeated values of a held button do not toggle.
+    pressed: bool,
+}
+
+impl MidiMapping {
+    pub fn new(slot: usize, binding: MidiBinding) -> Self {
+        MidiMapping {
+            slot,
+            binding,
+            toggled: false,
+            pressed: false,
+        }
+    }
+    /// New value of the slot if the event concerns this mapping.
+    pub fn apply(&mut self, event: &MidiEvent) -> Option<f32> {
+        let x = self.binding.source.position(event)?;
+        let MidiBinding { min, max, .. } = self.binding;
+        match self.binding.curve {
+            MidiCurve::Linear => Some(min + (max - min) * x),
+            MidiCurve::Exponential if min * max > 0.0 => Some(min * (max / min).powf(x)),
+            MidiCurve::Exponential => Some(min + (max - min) * x),
+            MidiCurve::Toggle => {
+                let pressed = x >= 0.5;
+                let press = pressed && !self.pressed;
+                self.pressed = pressed;
+                if press {
+                    self.toggled = !self.toggled;
+                    Some(if self.toggled { max } else { min })
+                } else {
+                    None
+                }
+            }
+        }
+    }
+}
+
+/// Frequency of a MIDI note in equal temperament (A4 = 440 Hz), `bend` in semitones.
+pub fn note_frequency(note: u8, bend: f32) -> f32 {
+    440.0 * 2.0f32.powf((note as f32 - 69.0 + bend) / 12.0)
+}
+
+/// Producer ends of the MIDI queues, shared by all input connections:
+/// one queue feeds the audio thread, the other lets the GUI watch the incoming messages (for MIDI learn).
+/// The mutex is only contended between MIDI callbacks, the audio thread reads its own consumer end.
+#[derive(Clone)]
+pub struct MidiSender {
+    producers: Arc<Mutex<(Producer<MidiMessage>, Producer<MidiMessage>)>>,
+}
+
+impl MidiSender {
+    pub fn send(&self, message: MidiMessage) {
+        if let Ok(mut producers) = self.producers.lock() {
+            // A full queue means nobody is reading, the message is dropped.
+            let _ = producers.0.push(message);
+            let _ = producers.1.push(message);
+        }
+    }
+}
+
+/// Creates the MIDI queues, returns the sender, the audio consumer and the monitor consumer.
+pub fn midi_queue(capacity: usize) -> (MidiSender, Consumer<MidiMessage>, Consumer<MidiMessage>) {
+    let (audio_producer, audio) = RingBuffer::new(capacity);
+    let (monitor_producer, monitor) = RingBuffer::new(capacity);
+    let sender = MidiSender {
+        producers: Arc::new(Mutex::new((audio_producer, monitor_producer))),
+    };
+    (sender, audio, monitor)
+}
+
+pub fn input_port_names() -> Result<Vec<String>, Box<dyn Error>> {
+    let midi_in = MidiInput::new("physynth input")?;
+    let mut names = Vec::new();
+    for p in midi_in.ports().iter() {
+        names.push(midi_in.port_name(p)?);
+    }
+    Ok(names)
+}
+
+/// Opens the input port with the given index and forwards its messages to the sender.
+/// The port stays open as long as the returned connection is alive.
+pub fn connect_input(
+    port: usize,
+    sender: MidiSender,
+) -> Result<MidiInputConnection<()>, Box<dyn Error>> {
+    let mut midi_in = MidiInput::new("physynth input")?;
+    midi_in.ignore(Ignore::All);
+    let ports = midi_in.ports();
+    let port = ports
+        .get(port)
+        .ok_or_else(|| format!("No MIDI input port {}", port))?;
+    midi_in
+        .connect(
+            port,
+            "physynth",
+            move |_, bytes, _| sender.send(MidiMessage::new(bytes)),
+            (),
+        )
+        .map_err(|e| e.to_string().into())
+}
@@ -0,0 +1,334 @@
+use crate::gadget::*;
+use crate::registry::*;
+use egui::Ui;
+use physynth_derive::GadgetParameters;
+use serde::{Deserialize, Serialize};
+use std::f32::consts::PI;
+use std::path::Path;
+
+pub fn register(registry: &mut GadgetRegistry) {
+    registry.register(GadgetType {
+        id: "MB",
+        display_name: "Modal bank",
+        category: "Resonators",
+        description: "Bank of damped oscillators driven by one excitation, with bar, plate, bell and tube mode tables",
+        prefix: "Modes",
+        constructor: |name| Box::new(ModalBankGadget::new(name)),
+    });
+}
+
+/// Names of the built-in mode tables.
+pub const MODE_PRESETS: &[&str] = &["Bar", "Plate", "Bell", "Tube"];
+/// Largest number of modes in a table.
+pub const MAX_MODES: usize = 1024;
+
+/// One mode of a resonator.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Mode {
+    /// Frequency relative to the fundamental frequency of the bank.
+    pub ratio: f32,
+    /// Time in seconds for the mode to decay by 60 dB.
+    pub decay: f32,
+    pub gain: f32,
+}
+
+/// Mode table of the bank, saved with the patch.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ModeTable {
+    /// Preset or file the modes come from.
+    pub name: String,
+    pub modes: Vec<Mode>,
+}
+
+impl ModeTable {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.modes.is_empty() || self.modes.len() > MAX_MODES {
+            return Err(format!("A mode table has 1 to {} modes", MAX_MODES));
+        }
+        for (i, m) in self.modes.iter().enumerate() {
+            if !(m.ratio.is_finite()
+                && m.ratio > 0.0
+                && m.decay.is_finite()
+                && m.decay > 0.0
+                && m.gain.is_finite())
+            {
+                return Err(format!("Mode {} needs a positive ratio and decay", i + 1));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Built-in mode table, `None` for unknown names.
+pub fn mode_preset(name: &str) -> Option<ModeTable> {
+    let modes: Vec<Mode> = match name {
+        // Free-free Euler-Bernoulli beam, (beta_n / beta_1)^2 with beta_n ~ (2n + 1) pi / 2.
+        "Bar" => {
+            let beta = |n: usize| match n {
+                0 => 4.730_04,
+                1 => 7.853_20,
+                2 => 10.995_61,
+                n => (2 * n + 3) as f32 * PI / 2.0,
+            };
+            (0..16)
+                .map(|n| Mode {
+                    ratio: (beta(n) / beta(0)).powi(2),
+                    decay: 2.0 / (1.0 + n as f32),
+                    gain: 1.0 / (1.0 + n as f32),
+                })
+                .collect()
+        }
+        // Simply supported square plate, frequencies proportional to m^2 + n^2.
+        "Plate" => {
+            let mut modes: Vec<Mode> = Vec::new();
+            for m in 1..=10 {
+                for n in 1..=10 {
+                    let ratio = (m * m + n * n) as f32 / 2.0;
+                    match modes.iter_mut().find(|mode| mode.ratio == ratio) {
+                        Some(mode) => mode.gain += 1.0 / ratio,
+                        None => modes.push(Mode {
+                            ratio,
+                            decay: 3.0 / ratio.sqrt(),
+                            gain: 1.0 / ratio,
+                        }),
+                    }
+                }
+            }
+            modes.sort_by(|a, b| a.ratio.partial_cmp(&b.ratio).unwrap());
+            modes.truncate(48);
+            modes
+        }
+        // Partials of a church bell relative to the prime: hum, prime, tierce, quint, nominal, ...
+        "Bell" => [
+            (0.5, 8.0, 0.6),
+            (1.0, 6.0, 0.8),
+            (1.183, 5.0, 1.0),
+            (1.506, 4.0, 0.5),
+            (2.0, 4.0, 0.9),
+            (2.514, 3.0, 0.4),
+            (2.662, 3.0, 0.5),
+            (3.011, 2.5, 0.4),
+            (4.166, 2.0, 0.3),
+            (5.433, 1.5, 0.2),
+            (6.796, 1.2, 0.15),
+            (8.215, 1.0, 0.1),
+        ]
+        .iter()
+        .map(|&(ratio, decay, gain)| Mode { ratio, decay, gain })
+        .collect(),
+        // Air column of an open tube, slightly stretched harmonics.
+        "Tube" => (1..=24)
+            .map(|n| Mode {
+                ratio: n as f32 * (1.0 + 0.0005 * (n * n) as f32),
+                decay: 1.5 / (n as f32).sqrt(),
+                gain: 1.0 / n as f32,
+            })
+            .collect(),
+        _ => return None,
+    };
+    Some(ModeTable {
+        name: name.to_owned(),
+        modes,
+    })
+}
+
+/// Parses a mode table from CSV lines `frequency[,decay[,gain]]`, as written by analysis tools.
+///
+/// Frequencies are in Hz or relative, they are divided by the lowest one so that the bank
+/// plays the table at its `frequency` parameter. Decays (T60 in seconds) default to 1 and gains to 1.
+/// Empty lines, lines starting with `#` and a header line are skipped.
+pub fn parse_modes_csv(name: &str, text: &str) -> Result<ModeTable, String> {
+    let mut modes = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields: Vec<&str> = line
+            .split(&[',', ';', '\t'][..])
+            .map(str::trim)
+            .collect();
+        let number = |k: usize, default: f32| -> Result<f32, String> {
+            match fields.get(k) {
+                Some(f) if !f.is_empty() => f
+                    .parse::<f32>()
+                    .map_err(|_| format!("Line {}: {} is not a number", i + 1, f)),
+                _ => Ok(default),
+            }
+        };
+        let ratio = match number(0, 0.0) {
+            Ok(x) => x,
+            Err(_) if modes.is_empty() => continue,
+            Err(e) => return Err(e),
+        };
+        modes.push(Mode {
+            ratio,
+            decay: number(1, 1.0)?,
+            gain: number(2, 1.0)?,
+        });
+    }
+    let lowest = modes.iter().map(|m| m.ratio).fold(f32::INFINITY, f32::min);
+    for m in modes.iter_mut() {
+        m.ratio /= lowest;
+    }
+    let table = ModeTable {
+        name: name.to_owned(),
+        modes,
+    };
+    table.validate()?;
+    Ok(table)
+}
+
+pub fn load_modes_csv<P: AsRef<Path>>(path: P) -> Result<ModeTable, String> {
+    let path = path.as_ref();
+    let text = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let name = path
+        .file_stem()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    parse_modes_csv(&name, &text)
+}
+
+/// State and coefficients of one mode, z = x + i y rotates and decays every sample.
+#[derive(Debug, Clone, Copy, Default)]
+struct Resonator {
+    x: f32,
+    y: f32,
+    c: f32,
+    s: f32,
+    gain: f32,
+}
+
+/// Bank of damped oscillators with a shared excitation `inp` summed into `out`.
+///
+/// Every mode is updated exactly (like the Exact integrator of the damped oscillator)
+/// with one complex multiplication per sample; coefficients are recomputed only when
+/// `frequency`, `decay` or the sample rate change. Modes above Nyquist are muted.
+/// `decay` scales the decay times of all modes.
+#[derive(Clone, GadgetParameters)]
+#[gadget(name = "MB", custom_ui)]
+pub struct ModalBankGadget {
+    #[init(mode_preset(MODE_PRESETS[0]).unwrap())]
+    table: ModeTable,
+    #[param(default = 220.0, range = 0.0..=20000.0, units = "Hz")]
+    frequency: Parameter,
+    #[param(default = 1.0, range = 0.0..=10.0)]
+    decay: Parameter,
+    inp: Parameter,
+    #[param(output)]
+    out: Parameter,
+    #[init(1.0 / DEFAULT_SAMPLE_RATE as f32)]
+    dt: f32,
+    /// Frequency, decay and dt the coefficients were computed for.
+    key: Option<(f32, f32, f32)>,
+    /// One resonator per mode, allocated by `set_table` and `prepare`.
+    resonators: Vec<Resonator>,
+    #[init("modes.csv".to_owned())]
+    csv_path: String,
+    status: String,
+    instance_name: String,
+}
+
+impl ModalBankGadget {
+    pub fn table(&self) -> &ModeTable {
+        &self.table
+    }
+    pub fn set_table(&mut self, table: ModeTable) -> Result<(), String> {
+        table.validate()?;
+        self.resonators = vec![Resonator::default(); table.modes.len()];
+        self.table = table;
+        self.key = None;
+        Ok(())
+    }
+
+    fn update_coefficients(&mut self, frequency: f32, decay: f32) {
+        for (r, m) in self.resonators.iter_mut().zip(self.table.modes.iter()) {
+            let omega = 2.0 * PI * frequency * m.ratio * self.dt;
+            if omega < PI && decay > 0.0 {
+                // exp(-ln(1000) dt / T60)
+                let radius = (-6.907_755 * self.dt / (m.decay * decay)).exp();
+                r.c = radius * omega.cos();
+                r.s = radius * omega.sin();
+                r.gain = m.gain;
+            } else {
+                r.c = 0.0;
+                r.s = 0.0;
+                r.gain = 0.0;
+            }
+        }
+        self.key = Some((frequency, decay, self.dt));
+    }
+
+    fn table_gui(&mut self, ui: &mut Ui) {
+        ui.label(format!(
+            "{}: {} modes",
+            self.table.name,
+            self.table.modes.len()
+        ));
+        ui.horizontal(|ui| {
+            for &preset in MODE_PRESETS.iter() {
+                if ui.button(preset).clicked() {
+                    self.set_table(mode_preset(preset).unwrap()).unwrap();
+                    self.status.clear();
+                }
+            }
+        });
+        ui.horizontal(|ui| {
+            ui.label("CSV");
+            ui.text_edit_singleline(&mut self.csv_path);
+            if ui.button("Import").clicked() {
+                self.status = match load_modes_csv(&self.csv_path).and_then(|t| self.set_table(t)) {
+                    Ok(_) => String::new(),
+                    Err(e) => e,
+                };
+            }
+        });
+        if !self.status.is_empty() {
+            ui.colored_label(egui::Color32::RED, &self.status);
+        }
+    }
+}
+
+impl Gadget for ModalBankGadget {
+    fn config(&self) -> Option<serde_json::Value> {
+        serde_json::to_value(&self.table).ok()
+    }
+    fn set_config(&mut self, config: &serde_json::Value) -> Result<(), String> {
+        let table = serde_json::from_value(config.clone()).map_err(|e| e.to_string())?;
+        self.set_table(table)
+    }
+    fn prepare(&mut self, sample_rate: u32) {
+        self.dt = 1.0 / sample_rate as f32;
+        self.resonators = vec![Resonator::default(); self.table.modes.len()];
+        self.key = None;
+    }
+    #[inline]
+    fn run(&mut self, s: &mut Signals) {
+        let (frequency, decay) = (s[&self.frequency], s[&self.decay]);
+        if self.key != Some((frequency, decay, self.dt)) {
+            self.update_coefficients(frequency, decay);
+        }
+        let u = s[&self.inp];
+        let mut out = 0.0;
+        for r in self.resonators.iter_mut() {
+            let x = r.c * r.x - r.s * r.y + u;
+            let y = r.s * r.x + r.c * r.y;
+            r.x = x;
+            r.y = y;
+            out += r.gain * y;
+        }
+        s[&self.out] = out;
+    }
+}
+
+impl GadgetUI for ModalBankGadget {
+    fn gui(&mut self, state: &mut GuiState, ui: &mut Ui) {
+        let name = self.instance_name.clone();
+        ui.collapsing(&name, |ui| {
+            parameter_grid(self, state, ui);
+            ui.collapsing("Modes", |ui| self.table_gui(ui));
+        });
+    }
+}
+
+impl GadgetWithUI for ModalBankGadget {}
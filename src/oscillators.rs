@@ -1,167 +1,192 @@
 use crate::gadget::*;
-use egui::{Ui};
+use crate::integrators::*;
+use crate::registry::*;
+use physynth_derive::GadgetParameters;
 use std::f32::consts::PI;
 
+pub fn register(registry: &mut GadgetRegistry) {
+    registry.register(GadgetType {
+        id: "DO",
+        display_name: "Damped oscillator",
+        category: "Oscillators",
+        description: "Linear harmonic oscillator with damping and x/y excitation inputs",
+        prefix: "Osc",
+        constructor: |name| Box::new(DampedOscillatorGadget::new(name)),
+    });
+    registry.register(GadgetType {
+        id: "PwO",
+        display_name: "Power oscillator",
+        category: "Oscillators",
+        description: "Oscillator with a power-law restoring force rotated by alpha",
+        prefix: "PwOsc",
+        constructor: |name| Box::new(PowerOscillatorGadget::new(name)),
+    });
+}
+
+/// Damped oscillator with position `x`, `v` is its velocity x' = omega y + ys.
+/// `force` accelerates a mass of 1 kg, for couplings in physical units; `xs` adds omega `xs` to x''.
+#[derive(Clone, GadgetParameters)]
+#[gadget(name = "DO")]
 pub struct DampedOscillatorGadget {
+    #[param(default = 440.0, range = 0.0..=20000.0, units = "Hz")]
     frequency: Parameter,
+    #[param(default = 1.0, output)]
     x: Parameter,
     xs: Parameter,
+    #[param(output)]
     y: Parameter,
     ys: Parameter,
+    #[param(default = 1.0, range = 0.0..=100.0)]
     damp: Parameter,
+    #[param(choices = INTEGRATOR_NAMES)]
+    integrator: Parameter,
+    #[param(output, units = "m/s")]
+    v: Parameter,
+    #[param(units = "N")]
+    force: Parameter,
+    #[init(1.0 / DEFAULT_SAMPLE_RATE as f32)]
+    dt: f32,
+    #[init(Workspace::new(1))]
+    workspace: Workspace,
+    propagator: LinearPropagator,
     instance_name: String,
 }
 
-impl DampedOscillatorGadget {
-    pub fn new(name: &str) -> DampedOscillatorGadget {
-        DampedOscillatorGadget {
-            frequency: Parameter::new("frequency", 440.0),
-            x: Parameter::new("x", 1.0),
-            xs: Parameter::new("xs", 0.0),
-            y: Parameter::new("y", 0.0),
-            ys: Parameter::new("ys", 0.0),
-            damp: Parameter::new("damp",1.0),
-            instance_name: name.to_owned(),
-        }
-    }
+/// x' = omega y + ys, y' = -omega (x + 2 damp y) + xs
+struct DampedOscillator {
+    omega: f32,
+    damp: f32,
+    xs: f32,
+    ys: f32,
 }
 
-impl GadgetUI for DampedOscillatorGadget {
-    fn gui(&mut self, link: &mut Option<String>, ui: &mut Ui) {
-        gadget_gui(self, link, ui);
+impl Dynamics for DampedOscillator {
+    #[inline]
+    fn derivative(&self, q: &[f32], p: &[f32], dq: &mut [f32], dp: &mut [f32]) {
+        dq[0] = p[0] * self.omega + self.ys;
+        dp[0] = -(q[0] + 2.0 * self.damp * p[0]) * self.omega + self.xs;
     }
 }
 
-impl GadgetWithUI for DampedOscillatorGadget {}
-
 impl Gadget for DampedOscillatorGadget {
-    fn name(&self) -> &'static str {
-        "DO"
-    }
-    fn get_instance_name(&self) -> String {
-        self.instance_name.to_owned()
-    }
-    fn par(&self, i: usize) -> &Parameter {
-        match i {
-            0 => &self.frequency,
-            1 => &self.x,
-            2 => &self.xs,
-            3 => &self.y,
-            4 => &self.ys,
-            5 => &self.damp,
-            _ => panic!("Invalid parameter number in DampedOscillatorGadget"),
-        }
-    }
-    fn par_mut(&mut self, i: usize) -> &mut Parameter {
-        match i {
-            0 => &mut self.frequency,
-            1 => &mut self.x,
-            2 => &mut self.xs,
-            3 => &mut self.y,
-            4 => &mut self.ys,
-            5 => &mut self.damp,
-            _ => panic!("Invalid parameter number in DampedOscillatorGadget"),
-        }
-    }
-    fn parameter_count(&self) -> usize {
-        6
+    fn prepare(&mut self, sample_rate: u32) {
+        self.dt = 1.0 / sample_rate as f32;
     }
     #[inline]
-    fn run(&mut self) {
-        let omega = 2.0 * PI * *self.frequency;
-        *self.x += *self.y * omega * DT + *self.ys * DT;
-        *self.y += -(*self.x + 2.0* *self.damp * *self.y) * omega * DT + *self.xs*DT;
+    fn run(&mut self, s: &mut Signals) {
+        let omega = 2.0 * PI * s[&self.frequency];
+        let force = if omega > 0.0 {
+            s[&self.force] / omega
+        } else {
+            0.0
+        };
+        let system = DampedOscillator {
+            omega,
+            damp: s[&self.damp],
+            xs: s[&self.xs] + force,
+            ys: s[&self.ys],
+        };
+        match Integrator::from_value(s[&self.integrator]) {
+            Integrator::Exact => {
+                let DampedOscillator { omega, damp, xs, ys } = system;
+                let a = [[0.0, omega], [-omega, -2.0 * damp * omega]];
+                let mut z = [s[&self.x], s[&self.y]];
+                self.propagator.step(a, [ys, xs], &mut z, self.dt);
+                s[&self.x] = z[0];
+                s[&self.y] = z[1];
+            }
+            integrator => {
+                let mut q = [s[&self.x]];
+                let mut p = [s[&self.y]];
+                self.workspace.step(integrator, &system, &mut q, &mut p, self.dt);
+                s[&self.x] = q[0];
+                s[&self.y] = p[0];
+            }
+        }
+        s[&self.v] = omega * s[&self.y] + system.ys;
     }
 }
 
+#[derive(Clone, GadgetParameters)]
+#[gadget(name = "PwO")]
 pub struct PowerOscillatorGadget {
+    #[param(default = 440.0, range = 0.0..=20000.0, units = "Hz")]
     frequency: Parameter,
+    #[param(default = 1.0, output)]
     x: Parameter,
     xs: Parameter,
+    #[param(output)]
     y: Parameter,
     ys: Parameter,
+    #[param(default = 1.0, range = 0.0..=100.0)]
     damp: Parameter,
     power: Parameter,
     alpha: Parameter,
+    #[param(choices = INTEGRATOR_NAMES)]
+    integrator: Parameter,
+    #[init(1.0 / DEFAULT_SAMPLE_RATE as f32)]
+    dt: f32,
+    #[init(Workspace::new(1))]
+    workspace: Workspace,
     instance_name: String,
 }
 
-impl PowerOscillatorGadget {
-    pub fn new(name: &str) -> PowerOscillatorGadget {
-        PowerOscillatorGadget {
-            frequency: Parameter::new("frequency", 440.0),
-            x: Parameter::new("x", 1.0),
-            xs: Parameter::new("xs", 0.0),
-            y: Parameter::new("y", 0.0),
-            ys: Parameter::new("ys", 0.0),
-            damp: Parameter::new("damp",1.0),
-            power: Parameter::new("power", 0.0),
-            alpha: Parameter::new("alpha", 0.0),
-            instance_name: name.to_owned(),
-        }
-    }
+/// Power-law restoring force (gx, gy) rotated by alpha (in units of pi).
+struct PowerOscillator {
+    omega: f32,
+    damp: f32,
+    power: f32,
+    ca: f32,
+    sa: f32,
+    xs: f32,
+    ys: f32,
 }
 
-impl GadgetUI for PowerOscillatorGadget {
-    fn gui(&mut self, link: &mut Option<String>, ui: &mut Ui) {
-        gadget_gui(self, link, ui);
+impl PowerOscillator {
+    #[inline]
+    fn force(&self, x: f32, y: f32) -> (f32, f32) {
+        let (ca, sa) = (self.ca, self.sa);
+        let wx = x * ca + y * sa;
+        let wy = y * ca - x * sa;
+        let pwx = wx.powf(self.power).abs()*wx;
+        let pwy = wy.powf(self.power).abs()*wy;
+        let n = (pwx*pwx + pwy*pwy).sqrt().max(0.01);
+        let gx = pwx*ca/n - pwy*sa/n;
+        let gy = pwx*sa/n + pwy*ca/n;
+        (gx, gy)
     }
 }
 
-impl GadgetWithUI for PowerOscillatorGadget {}
+impl Dynamics for PowerOscillator {
+    #[inline]
+    fn derivative(&self, q: &[f32], p: &[f32], dq: &mut [f32], dp: &mut [f32]) {
+        let (gx, gy) = self.force(q[0], p[0]);
+        dq[0] = gy * self.omega + self.ys;
+        dp[0] = -(gx + 2.0 * self.damp * p[0]) * self.omega + self.xs;
+    }
+}
 
 impl Gadget for PowerOscillatorGadget {
-    fn name(&self) -> &'static str {
-        "PwO"
+    fn prepare(&mut self, sample_rate: u32) {
+        self.dt = 1.0 / sample_rate as f32;
     }
-    fn get_instance_name(&self) -> String {
-        self.instance_name.to_owned()
-    }
-    fn par(&self, i: usize) -> &Parameter {
-        match i {
-            0 => &self.frequency,
-            1 => &self.x,
-            2 => &self.xs,
-            3 => &self.y,
-            4 => &self.ys,
-            5 => &self.damp,
-            6 => &self.power,
-            7 => &self.alpha,
-            _ => panic!("Invalid parameter number in PowerOscillatorGadget"),
-        }
-    }
-    fn par_mut(&mut self, i: usize) -> &mut Parameter {
-        match i {
-            0 => &mut self.frequency,
-            1 => &mut self.x,
-            2 => &mut self.xs,
-            3 => &mut self.y,
-            4 => &mut self.ys,
-            5 => &mut self.damp,
-            6 => &mut self.power,
-            7 => &mut self.alpha,
-            _ => panic!("Invalid parameter number in PowerOscillatorGadget"),
-        }
-    }
-    fn parameter_count(&self) -> usize {
-        8
-    }
-
     #[inline]
-    fn run(&mut self) {
-        let omega = 2.0 * PI * *self.frequency;
-        let ca = (*self.alpha * PI).cos();
-        let sa = (*self.alpha * PI).sin();
-        let wx = *self.x * ca +*self.y * sa;
-        let wy = *self.y * ca -*self.x * sa;
-        let pwx = wx.powf(*self.power).abs()*wx;
-        let pwy = wy.powf(*self.power).abs()*wy;
-        let n = (pwx*pwx + pwy*pwy).sqrt().max(0.01);
-        let gx = pwx*ca/n - pwy*sa/n;
-        let gy = pwx*sa/n + pwy*ca/n;
-
-
-        *self.x += gy * omega * DT + *self.ys * DT;
-        *self.y += -(gx + 2.0* *self.damp * *self.y) * omega * DT + *self.xs*DT;
+    fn run(&mut self, s: &mut Signals) {
+        let system = PowerOscillator {
+            omega: 2.0 * PI * s[&self.frequency],
+            damp: s[&self.damp],
+            power: s[&self.power],
+            ca: (s[&self.alpha] * PI).cos(),
+            sa: (s[&self.alpha] * PI).sin(),
+            xs: s[&self.xs],
+            ys: s[&self.ys],
+        };
+        let mut q = [s[&self.x]];
+        let mut p = [s[&self.y]];
+        // The force is not linear, Exact falls back to RK4.
+        let integrator = Integrator::from_value(s[&self.integrator]);
+        self.workspace.step(integrator, &system, &mut q, &mut p, self.dt);
+        s[&self.x] = q[0];
+        s[&self.y] = p[0];
     }
 }
@@ -0,0 +1,332 @@
+use crate::gadget::*;
+use crate::registry::*;
+use egui::Ui;
+use std::f64::consts::PI;
+
+pub fn register(registry: &mut GadgetRegistry) {
+    registry.register(GadgetType {
+        id: "OS",
+        display_name: "Oversampler",
+        category: "Containers",
+        description: "Runs the wrapped gadgets at 2x, 4x or 8x the sample rate with anti-aliasing filters",
+        prefix: "OS",
+        constructor: |name| Box::new(OversampledGadget::new(name)),
+    });
+}
+
+/// Names of the oversampling factors, in the order of their parameter values.
+pub const OVERSAMPLING_FACTORS: &[&str] = &["2x", "4x", "8x"];
+/// Length of each polyphase branch of the anti-aliasing filters (in samples at the engine rate).
+pub const TAPS_PER_PHASE: usize = 32;
+const MAX_FACTOR: usize = 8;
+
+/// Oversampling factor selected by a parameter value (index into [`OVERSAMPLING_FACTORS`]).
+pub fn oversampling_factor(value: f32) -> usize {
+    2 << (value.round().max(0.0) as usize).min(OVERSAMPLING_FACTORS.len() - 1)
+}
+
+/// Blackman windowed sinc low-pass cutting at 0.45 of the engine sample rate,
+/// `TAPS_PER_PHASE * factor` taps at the oversampled rate.
+#[derive(Debug, Clone)]
+struct AntiAliasing {
+    factor: usize,
+    /// Taps scaled so that every polyphase branch has unity gain at DC.
+    interpolation: Vec<f32>,
+    /// Taps with unity gain at DC.
+    decimation: Vec<f32>,
+}
+
+impl AntiAliasing {
+    fn new(factor: usize) -> Self {
+        let len = TAPS_PER_PHASE * factor;
+        let center = (len - 1) as f64 / 2.0;
+        let cutoff = 0.45 / factor as f64;
+        let taps: Vec<f64> = (0..len)
+            .map(|n| {
+                let t = n as f64 - center;
+                let phase = 2.0 * PI * n as f64 / (len - 1) as f64;
+                let window = 0.42 - 0.5 * phase.cos() + 0.08 * (2.0 * phase).cos();
+                (2.0 * PI * cutoff * t).sin() / (PI * t) * window
+            })
+            .collect();
+        let sum: f64 = taps.iter().sum();
+        let decimation = taps.iter().map(|&h| (h / sum) as f32).collect();
+        let mut interpolation = vec![0.0; len];
+        for k in 0..factor {
+            let branch: f64 = taps.iter().skip(k).step_by(factor).sum();
+            for n in (k..len).step_by(factor) {
+                interpolation[n] = (taps[n] / branch) as f32;
+            }
+        }
+        AntiAliasing {
+            factor,
+            interpolation,
+            decimation,
+        }
+    }
+}
+
+/// Interpolates the values of an input slot to the oversampled rate.
+#[derive(Debug, Clone)]
+struct Upsampler {
+    slot: usize,
+    /// Input at the engine rate, `history[position]` is the newest value.
+    history: [f32; TAPS_PER_PHASE],
+    position: usize,
+    primed: bool,
+}
+
+impl Upsampler {
+    fn new(slot: usize) -> Self {
+        Upsampler {
+            slot,
+            history: [0.0; TAPS_PER_PHASE],
+            position: 0,
+            primed: false,
+        }
+    }
+    #[inline]
+    fn push(&mut self, x: f32) {
+        if !self.primed {
+            // Start from a steady state instead of ramping up from zero.
+            self.history = [x; TAPS_PER_PHASE];
+            self.primed = true;
+        }
+        self.position = (self.position + 1) % TAPS_PER_PHASE;
+        self.history[self.position] = x;
+    }
+    /// Value at sub-sample `k` of the current engine sample.
+    #[inline]
+    fn value(&self, filter: &AntiAliasing, k: usize) -> f32 {
+        let mut sum = 0.0;
+        for (i, h) in filter.interpolation[k..].iter().step_by(filter.factor).enumerate() {
+            sum += h * self.history[(self.position + TAPS_PER_PHASE - i) % TAPS_PER_PHASE];
+        }
+        sum
+    }
+}
+
+/// Low-pass filters the values of an output slot and decimates them to the engine rate.
+#[derive(Debug, Clone)]
+struct Decimator {
+    slot: usize,
+    /// Value of the slot seen by the inner gadgets, kept apart from the filtered value seen outside.
+    state: f32,
+    history: Vec<f32>,
+    position: usize,
+    primed: bool,
+}
+
+impl Decimator {
+    fn new(slot: usize) -> Self {
+        Decimator {
+            slot,
+            state: 0.0,
+            history: vec![0.0; TAPS_PER_PHASE * MAX_FACTOR],
+            position: 0,
+            primed: false,
+        }
+    }
+    #[inline]
+    fn push(&mut self, x: f32) {
+        if !self.primed {
+            self.history.iter_mut().for_each(|h| *h = x);
+            self.primed = true;
+        }
+        self.position = (self.position + 1) % self.history.len();
+        self.history[self.position] = x;
+    }
+    #[inline]
+    fn value(&self, filter: &AntiAliasing) -> f32 {
+        let len = self.history.len();
+        let mut sum = 0.0;
+        for (j, h) in filter.decimation.iter().enumerate() {
+            sum += h * self.history[(self.position + len - j) % len];
+        }
+        sum
+    }
+}
+
+/// Runs the wrapped gadgets at a multiple of the engine sample rate.
+///
+/// Inputs of the wrapped gadgets (parameters they read but do not write) are interpolated,
+/// outputs are low-pass filtered and decimated, so gadgets outside see band limited signals.
+/// Both filters are linear phase, together they delay the signals by about `TAPS_PER_PHASE` samples.
+/// The parameters of the wrapped gadgets keep their names, links to them work as before.
+/// Feedback links between the wrapped gadgets are delayed by one engine sample.
+/// The `factor` is read when the gadgets are prepared (on Play), links to it are ignored.
+#[derive(Clone)]
+pub struct OversampledGadget {
+    pub inner: GadgetContainer,
+    factor: Parameter,
+    instance_name: String,
+    sample_rate: u32,
+    /// Factor the inner gadgets were prepared for.
+    prepared: usize,
+    filters: Vec<AntiAliasing>,
+    upsamplers: Vec<Upsampler>,
+    decimators: Vec<Decimator>,
+}
+
+impl OversampledGadget {
+    pub fn new(instance_name: &str) -> Self {
+        OversampledGadget {
+            inner: GadgetContainer::new(),
+            factor: Parameter::new("factor", 0.0).with_choices(OVERSAMPLING_FACTORS),
+            instance_name: instance_name.to_owned(),
+            sample_rate: DEFAULT_SAMPLE_RATE,
+            prepared: 0,
+            filters: (0..OVERSAMPLING_FACTORS.len())
+                .map(|i| AntiAliasing::new(oversampling_factor(i as f32)))
+                .collect(),
+            upsamplers: Vec::new(),
+            decimators: Vec::new(),
+        }
+    }
+    /// Oversampler running a single gadget.
+    pub fn wrap(instance_name: &str, gadget: Box<dyn GadgetWithUI>) -> Self {
+        let mut oversampled = Self::new(instance_name);
+        oversampled.inner.container.push(gadget);
+        oversampled
+    }
+
+    fn set_factor(&mut self, factor: usize) {
+        self.prepared = factor;
+        self.inner.prepare(self.sample_rate * factor as u32);
+        for u in self.upsamplers.iter_mut() {
+            u.primed = false;
+        }
+        for d in self.decimators.iter_mut() {
+            d.primed = false;
+        }
+    }
+}
+
+impl GadgetParameters for OversampledGadget {
+    fn name(&self) -> &'static str {
+        "OS"
+    }
+    fn get_instance_name(&self) -> String {
+        self.instance_name.clone()
+    }
+    fn par(&self, i: usize) -> &Parameter {
+        let count = self.inner.parameter_count();
+        match i {
+            i if i < count => self.inner.par(i),
+            i if i == count => &self.factor,
+            _ => panic!("Invalid parameter number {} in {}", i, self.instance_name),
+        }
+    }
+    fn par_mut(&mut self, i: usize) -> &mut Parameter {
+        let count = self.inner.parameter_count();
+        match i {
+            i if i < count => self.inner.par_mut(i),
+            i if i == count => &mut self.factor,
+            _ => panic!("Invalid parameter number {} in {}", i, self.instance_name),
+        }
+    }
+    fn parameter_count(&self) -> usize {
+        self.inner.parameter_count() + 1
+    }
+}
+
+impl Gadget for OversampledGadget {
+    fn children(&self) -> Option<&GadgetContainer> {
+        Some(&self.inner)
+    }
+    fn children_mut(&mut self) -> Option<&mut GadgetContainer> {
+        Some(&mut self.inner)
+    }
+    fn output_channels(&self) -> Vec<String> {
+        self.inner.output_channels()
+    }
+    fn instance_names(&self) -> Vec<String> {
+        let mut names = vec![self.get_instance_name()];
+        names.extend(self.inner.instance_names());
+        names
+    }
+    fn parameter_names(&self) -> Vec<String> {
+        let mut names = self.inner.parameter_names();
+        names.push(format!("{}: {}", self.instance_name, self.factor.name));
+        names
+    }
+    fn schedule(&mut self) -> Vec<Feedback> {
+        self.inner.schedule()
+    }
+    fn prepare(&mut self, sample_rate: u32) {
+        self.sample_rate = sample_rate;
+        let mut outputs: Vec<usize> = Vec::new();
+        let mut inputs: Vec<usize> = Vec::new();
+        for i in 0..self.inner.parameter_count() {
+            let p = self.inner.par(i);
+            if let (Some(slot), true) = (p.slot, p.output) {
+                if !outputs.contains(&slot) {
+                    outputs.push(slot);
+                }
+            }
+        }
+        for i in 0..self.inner.parameter_count() {
+            let p = self.inner.par(i);
+            // Discrete parameters are held, interpolating them would produce meaningless values.
+            if let (Some(slot), false, true) = (p.slot, p.output, p.choices.is_empty()) {
+                if !outputs.contains(&slot) && !inputs.contains(&slot) {
+                    inputs.push(slot);
+                }
+            }
+        }
+        self.upsamplers = inputs.into_iter().map(Upsampler::new).collect();
+        self.decimators = outputs.into_iter().map(Decimator::new).collect();
+        let factor = match self.factor.link {
+            Link::Value(x) => oversampling_factor(x),
+            Link::Link(_) => oversampling_factor(0.0),
+        };
+        self.set_factor(factor);
+    }
+    fn run(&mut self, s: &mut Signals) {
+        // Changing the factor prepares the inner gadgets again, which allocates and resets them:
+        // changes of the value only take effect at the next `prepare`, never on the audio thread.
+        let factor = self.prepared;
+        let filter = &self.filters[factor.trailing_zeros() as usize - 1];
+
+        for u in self.upsamplers.iter_mut() {
+            u.push(s[u.slot]);
+        }
+        for d in self.decimators.iter_mut() {
+            if !d.primed {
+                d.state = s[d.slot];
+            }
+            s[d.slot] = d.state;
+        }
+        for k in 0..factor {
+            for u in self.upsamplers.iter() {
+                s[u.slot] = u.value(filter, k);
+            }
+            self.inner.run(s);
+            for d in self.decimators.iter_mut() {
+                d.push(s[d.slot]);
+            }
+        }
+        for u in self.upsamplers.iter() {
+            s[u.slot] = u.history[u.position];
+        }
+        for d in self.decimators.iter_mut() {
+            d.state = s[d.slot];
+            s[d.slot] = d.value(filter);
+        }
+    }
+}
+
+impl GadgetUI for OversampledGadget {
+    fn gui(&mut self, state: &mut GuiState, ui: &mut Ui) {
+        let name = self.instance_name.clone();
+        ui.collapsing(&name, |ui| {
+            egui::Grid::new(format!("_Grid_{}", name)).show(ui, |ui| {
+                let pname = format!("{}: {}", name, self.factor.name);
+                parameter_row(&mut self.factor, &pname, state, ui);
+            });
+            self.inner.gui(state, ui);
+        });
+    }
+}
+
+impl GadgetWithUI for OversampledGadget {}
@@ -0,0 +1,258 @@
+use crate::engine::*;
+use crate::gadget::*;
+use crate::midi::MidiBinding;
+use crate::registry::*;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::path::Path;
+
+pub const PATCH_VERSION: u32 = 1;
+
+/// State of a single parameter in a patch file: either a plain value or a link to another parameter.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum ParameterRecord {
+    Value(f32),
+    Link { link: String },
+}
+
+impl From<&Link> for ParameterRecord {
+    fn from(link: &Link) -> Self {
+        match link {
+            Link::Value(x) => ParameterRecord::Value(*x),
+            Link::Link(name) => ParameterRecord::Link { link: name.clone() },
+        }
+    }
+}
+
+impl From<&ParameterRecord> for Link {
+    fn from(record: &ParameterRecord) -> Self {
+        match record {
+            ParameterRecord::Value(x) => Link::Value(*x),
+            ParameterRecord::Link { link } => Link::Link(link.clone()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GadgetRecord {
+    #[serde(rename = "type")]
+    pub gadget_type: String,
+    pub name: String,
+    #[serde(default)]
+    pub parameters: BTreeMap<String, ParameterRecord>,
+    /// MIDI controllers bound to parameters of the gadget.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub midi: BTreeMap<String, MidiBinding>,
+    /// Settings of the gadget that are not parameters (see [`Gadget::config`]).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub config: Option<serde_json::Value>,
+    /// Gadgets wrapped by this gadget (see [`Gadget::children`]).
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub gadgets: Vec<GadgetRecord>,
+}
+
+/// Human readable (JSON) description of a gadget graph.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Patch {
+    pub version: u32,
+    pub gadgets: Vec<GadgetRecord>,
+}
+
+#[derive(Debug)]
+pub enum PatchError {
+    Io(std::io::Error),
+    Format(serde_json::Error),
+    UnsupportedVersion(u32),
+    UnknownGadgetType { gadget_type: String, name: String },
+    UnknownParameter { gadget: String, parameter: String },
+    DanglingLink { parameter: String, target: String },
+    InvalidConfig { gadget: String, message: String },
+    /// Gadgets are nested in a gadget that cannot wrap other gadgets.
+    NotAContainer(String),
+    Bind(BindError),
+}
+
+impl fmt::Display for PatchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PatchError::Io(e) => write!(f, "Patch file error: {}", e),
+            PatchError::Format(e) => write!(f, "Invalid patch: {}", e),
+            PatchError::UnsupportedVersion(v) => write!(f, "Unsupported patch version {}", v),
+            PatchError::UnknownGadgetType { gadget_type, name } => {
+                write!(f, "Unknown gadget type {} of {}", gadget_type, name)
+            }
+            PatchError::UnknownParameter { gadget, parameter } => {
+                write!(f, "Gadget {} has no parameter {}", gadget, parameter)
+            }
+            PatchError::DanglingLink { parameter, target } => {
+                write!(f, "{} is linked to unknown parameter {}", parameter, target)
+            }
+            PatchError::InvalidConfig { gadget, message } => {
+                write!(f, "Invalid settings of {}: {}", gadget, message)
+            }
+            PatchError::NotAContainer(name) => write!(f, "Gadget {} cannot contain gadgets", name),
+            PatchError::Bind(e) => write!(f, "Patch cannot be bound: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for PatchError {}
+
+impl From<std::io::Error> for PatchError {
+    fn from(e: std::io::Error) -> Self {
+        PatchError::Io(e)
+    }
+}
+
+impl From<BindError> for PatchError {
+    fn from(e: BindError) -> Self {
+        PatchError::Bind(e)
+    }
+}
+
+impl From<serde_json::Error> for PatchError {
+    fn from(e: serde_json::Error) -> Self {
+        PatchError::Format(e)
+    }
+}
+
+impl GadgetRecord {
+    pub fn from_gadget(gadget: &dyn GadgetWithUI) -> GadgetRecord {
+        let children = gadget.children();
+        // Parameters of wrapped gadgets are stored in their own records.
+        let own = children.map(|c| c.parameter_count()).unwrap_or(0)..gadget.parameter_count();
+        GadgetRecord {
+            gadget_type: gadget.name().to_owned(),
+            name: gadget.get_instance_name(),
+            parameters: own
+                .clone()
+                .map(|i| {
+                    let p = gadget.par(i);
+                    (p.name.to_owned(), ParameterRecord::from(&p.link))
+                })
+                .collect(),
+            midi: own
+                .filter_map(|i| {
+                    let p = gadget.par(i);
+                    p.midi.map(|binding| (p.name.to_owned(), binding))
+                })
+                .collect(),
+            config: gadget.config(),
+            gadgets: children
+                .map(|c| c.container.iter().map(|g| GadgetRecord::from_gadget(g.as_ref())).collect())
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Creates the gadget and the gadgets it wraps, links are not checked.
+    pub fn to_gadget(&self, registry: &GadgetRegistry) -> Result<Box<dyn GadgetWithUI>, PatchError> {
+        let mut gadget = registry.create(&self.gadget_type, &self.name).ok_or_else(|| {
+            PatchError::UnknownGadgetType {
+                gadget_type: self.gadget_type.clone(),
+                name: self.name.clone(),
+            }
+        })?;
+        if let Some(config) = &self.config {
+            gadget.set_config(config).map_err(|message| PatchError::InvalidConfig {
+                gadget: self.name.clone(),
+                message,
+            })?;
+        }
+        if !self.gadgets.is_empty() {
+            let children = gadget
+                .children_mut()
+                .ok_or_else(|| PatchError::NotAContainer(self.name.clone()))?;
+            for record in self.gadgets.iter() {
+                children.container.push(record.to_gadget(registry)?);
+            }
+        }
+        let own = gadget.children().map(|c| c.parameter_count()).unwrap_or(0);
+        let find = |parameter: &String| {
+            (own..gadget.parameter_count())
+                .find(|&i| gadget.par(i).name == parameter)
+                .ok_or_else(|| PatchError::UnknownParameter {
+                    gadget: self.name.clone(),
+                    parameter: parameter.clone(),
+                })
+        };
+        let mut links = Vec::with_capacity(self.parameters.len());
+        for (parameter, state) in self.parameters.iter() {
+            links.push((find(parameter)?, Link::from(state)));
+        }
+        let mut bindings = Vec::with_capacity(self.midi.len());
+        for (parameter, binding) in self.midi.iter() {
+            bindings.push((find(parameter)?, *binding));
+        }
+        for (i, link) in links {
+            gadget.par_mut(i).link = link;
+        }
+        for (i, binding) in bindings {
+            gadget.par_mut(i).midi = Some(binding);
+        }
+        Ok(gadget)
+    }
+}
+
+impl Patch {
+    pub fn from_container(container: &GadgetContainer) -> Patch {
+        Patch {
+            version: PATCH_VERSION,
+            gadgets: container
+                .container
+                .iter()
+                .map(|gadget| GadgetRecord::from_gadget(gadget.as_ref()))
+                .collect(),
+        }
+    }
+
+    /// Builds the gadget graph, checking gadget types, parameter names and link targets.
+    pub fn to_container(&self, registry: &GadgetRegistry) -> Result<GadgetContainer, PatchError> {
+        if self.version > PATCH_VERSION {
+            return Err(PatchError::UnsupportedVersion(self.version));
+        }
+        let mut container = GadgetContainer::new();
+        for record in self.gadgets.iter() {
+            container.container.push(record.to_gadget(registry)?);
+        }
+
+        let names: HashSet<String> = container.parameter_names().into_iter().collect();
+        for (i, name) in container.parameter_names().iter().enumerate() {
+            if let Link::Link(ref target) = container.par(i).link {
+                if !names.contains(target) {
+                    return Err(PatchError::DanglingLink {
+                        parameter: name.clone(),
+                        target: target.clone(),
+                    });
+                }
+            }
+        }
+        Ok(container)
+    }
+
+    /// Builds the gadget graph and binds it in a new engine.
+    pub fn to_engine(
+        &self,
+        registry: &GadgetRegistry,
+        sample_rate: u32,
+    ) -> Result<Engine<GadgetContainer>, PatchError> {
+        let mut engine = Engine::with_sample_rate(self.to_container(registry)?, sample_rate);
+        engine.bind()?;
+        Ok(engine)
+    }
+
+    pub fn to_json(&self) -> Result<String, PatchError> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+    pub fn from_json(text: &str) -> Result<Patch, PatchError> {
+        Ok(serde_json::from_str(text)?)
+    }
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), PatchError> {
+        std::fs::write(path, self.to_json()?)?;
+        Ok(())
+    }
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Patch, PatchError> {
+        Patch::from_json(&std::fs::read_to_string(path)?)
+    }
+}
@@ -0,0 +1,118 @@
+use crate::engine;
+use crate::envelopes;
+use crate::exciters;
+use crate::filters;
+use crate::gadget::*;
+use crate::lfo;
+use crate::mass_spring;
+use crate::mesh;
+use crate::modal;
+use crate::oscillators;
+use crate::oversampling;
+use crate::transformations;
+use crate::waveguides;
+
+pub type GadgetConstructor = fn(&str) -> Box<dyn GadgetWithUI>;
+
+/// Description of a gadget type known to the registry.
+#[derive(Clone)]
+pub struct GadgetType {
+    /// Type identifier, equal to [`Gadget::name`] of the created gadgets and used in patch files.
+    pub id: &'static str,
+    pub display_name: &'static str,
+    pub category: &'static str,
+    pub description: &'static str,
+    /// Prefix of generated instance names.
+    pub prefix: &'static str,
+    pub constructor: GadgetConstructor,
+}
+
+/// Maps gadget type identifiers to constructors and descriptions.
+#[derive(Clone, Default)]
+pub struct GadgetRegistry {
+    types: Vec<GadgetType>,
+}
+
+impl GadgetRegistry {
+    pub fn new() -> Self {
+        GadgetRegistry { types: Vec::new() }
+    }
+
+    /// Registry with all gadgets shipped with physynth.
+    pub fn standard() -> Self {
+        let mut registry = GadgetRegistry::new();
+        engine::register(&mut registry);
+        oscillators::register(&mut registry);
+        transformations::register(&mut registry);
+        waveguides::register(&mut registry);
+        mass_spring::register(&mut registry);
+        modal::register(&mut registry);
+        mesh::register(&mut registry);
+        exciters::register(&mut registry);
+        envelopes::register(&mut registry);
+        lfo::register(&mut registry);
+        filters::register(&mut registry);
+        oversampling::register(&mut registry);
+        registry
+    }
+
+    /// Adds a gadget type; panics if the identifier is already taken.
+    pub fn register(&mut self, gadget_type: GadgetType) {
+        if self.get(gadget_type.id).is_some() {
+            panic!("Gadget type {} registered twice", gadget_type.id);
+        }
+        self.types.push(gadget_type);
+    }
+
+    pub fn get(&self, id: &str) -> Option<&GadgetType> {
+        self.types.iter().find(|t| t.id == id)
+    }
+
+    pub fn types(&self) -> impl Iterator<Item = &GadgetType> {
+        self.types.iter()
+    }
+
+    /// Categories in registration order.
+    pub fn categories(&self) -> Vec<&'static str> {
+        let mut categories = Vec::new();
+        for t in self.types.iter() {
+            if !categories.contains(&t.category) {
+                categories.push(t.category);
+            }
+        }
+        categories
+    }
+
+    pub fn types_in_category<'a>(
+        &'a self,
+        category: &'a str,
+    ) -> impl Iterator<Item = &'a GadgetType> + 'a {
+        self.types.iter().filter(move |t| t.category == category)
+    }
+
+    /// Creates an unbound gadget of the given type.
+    pub fn create(&self, id: &str, instance_name: &str) -> Option<Box<dyn GadgetWithUI>> {
+        self.get(id).map(|t| (t.constructor)(instance_name))
+    }
+
+    /// First instance name of the form prefix + number not used in the container.
+    pub fn unique_instance_name(&self, id: &str, container: &GadgetContainer) -> Option<String> {
+        let prefix = self.get(id)?.prefix;
+        (1..)
+            .map(|i| format!("{}{}", prefix, i))
+            .find(|name| !container.has_instance(name))
+    }
+
+    /// Creates a gadget with a unique instance name and appends it to the container.
+    /// Returns the instance name, or None if the type is unknown or its (fixed) name is taken.
+    pub fn add(&self, id: &str, container: &mut GadgetContainer) -> Option<String> {
+        let name = self.unique_instance_name(id, container)?;
+        let gadget = self.create(id, &name)?;
+        let name = gadget.get_instance_name();
+        if container.has_instance(&name) {
+            return None;
+        }
+        container.container.push(gadget);
+        Some(name)
+    }
+}
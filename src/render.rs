@@ -0,0 +1,151 @@
+use crate::engine::*;
+use crate::gadget::*;
+use crate::oscillators::*;
+use std::error::Error;
+use std::path::Path;
+
+/// Sample formats supported by the WAV writer.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WavFormat {
+    Int16,
+    Int24,
+    Float32,
+}
+
+impl WavFormat {
+    pub fn parse(text: &str) -> Option<WavFormat> {
+        match text {
+            "16" => Some(WavFormat::Int16),
+            "24" => Some(WavFormat::Int24),
+            "32" | "32f" | "float" => Some(WavFormat::Float32),
+            _ => None,
+        }
+    }
+    fn spec(&self, channels: u16, sample_rate: u32) -> hound::WavSpec {
+        let (bits_per_sample, sample_format) = match self {
+            WavFormat::Int16 => (16, hound::SampleFormat::Int),
+            WavFormat::Int24 => (24, hound::SampleFormat::Int),
+            WavFormat::Float32 => (32, hound::SampleFormat::Float),
+        };
+        hound::WavSpec {
+            channels,
+            sample_rate,
+            bits_per_sample,
+            sample_format,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RenderOptions {
+    pub sample_rate: u32,
+    /// Length in samples per channel.
+    pub samples: usize,
+    pub format: WavFormat,
+    pub normalize: bool,
+    /// Parameters written to the channels, the channels of the output gadget if empty.
+    pub outputs: Vec<String>,
+}
+
+impl Default for RenderOptions {
+    fn default() -> Self {
+        RenderOptions {
+            sample_rate: DEFAULT_SAMPLE_RATE,
+            samples: DEFAULT_SAMPLE_RATE as usize,
+            format: WavFormat::Int16,
+            normalize: false,
+            outputs: Vec::new(),
+        }
+    }
+}
+
+impl RenderOptions {
+    pub fn set_duration(&mut self, seconds: f32) {
+        self.samples = (seconds * self.sample_rate as f32).round() as usize;
+    }
+}
+
+/// Patch used when nothing else is loaded: a single oscillator sent to the output.
+pub fn default_patch() -> GadgetContainer {
+    let mut container = GadgetContainer::new();
+    container.container.push(Box::new(OutputGadget::new()));
+    container
+        .container
+        .push(Box::new(DampedOscillatorGadget::new("Osc")));
+    if let Some(p) = container.parameter_mut("OUT") {
+        p.set_link("Osc: x");
+    }
+    container
+}
+
+/// Interleaved samples produced by [`render`].
+#[derive(Debug, Clone)]
+pub struct Rendering {
+    pub channels: u16,
+    pub samples: Vec<f32>,
+}
+
+/// Runs a copy of the gadget offline and collects the output channels.
+pub fn render<G: Gadget + Clone>(
+    gadget: &G,
+    options: &RenderOptions,
+) -> Result<Rendering, Box<dyn Error>> {
+    let mut engine = Engine::with_sample_rate(gadget.clone(), options.sample_rate);
+    engine.bind()?;
+    if !options.outputs.is_empty() && !engine.bind_outputs(&options.outputs) {
+        return Err(format!("Unknown output parameter in {}", options.outputs.join(", ")).into());
+    }
+    if engine.outputs.is_empty() {
+        return Err("The patch has no output".into());
+    }
+    let channels = engine.channels();
+    let mut samples: Vec<f32> = engine.take(options.samples * channels).collect();
+    if options.normalize {
+        normalize(&mut samples);
+    }
+    Ok(Rendering {
+        channels: channels as u16,
+        samples,
+    })
+}
+
+/// Scales the samples so that the peak reaches full scale.
+pub fn normalize(samples: &mut [f32]) {
+    let peak = samples.iter().fold(0.0f32, |m, x| m.max(x.abs()));
+    if peak > 0.0 && peak.is_finite() {
+        for x in samples.iter_mut() {
+            *x /= peak;
+        }
+    }
+}
+
+/// Writes interleaved samples.
+pub fn write_wav<P: AsRef<Path>>(
+    path: P,
+    samples: &[f32],
+    channels: u16,
+    sample_rate: u32,
+    format: WavFormat,
+) -> Result<(), Box<dyn Error>> {
+    let mut writer = hound::WavWriter::create(path, format.spec(channels, sample_rate))?;
+    match format {
+        WavFormat::Int16 => {
+            for &x in samples {
+                writer.write_sample((x.clamp(-1.0, 1.0) * i16::MAX as f32) as i16)?;
+            }
+        }
+        WavFormat::Int24 => {
+            let full_scale = ((1 << 23) - 1) as f32;
+            for &x in samples {
+                writer.write_sample((x.clamp(-1.0, 1.0) * full_scale) as i32)?;
+            }
+        }
+        WavFormat::Float32 => {
+            for &x in samples {
+                writer.write_sample(x)?;
+            }
+        }
+    }
+    writer.finalize()?;
+    Ok(())
+}
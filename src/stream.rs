@@ -0,0 +1,402 @@
+use crate::engine::*;
+use crate::gadget::*;
+use crate::midi::*;
+use rodio::source::Source;
+use rtrb::{Consumer, Producer, RingBuffer};
+use std::time::Duration;
+
+/// Number of samples between two checks of the command queue on the audio thread.
+pub const CONTROL_BLOCK: usize = 64;
+/// Length (in samples) of the gain ramps used by start, stop and rebind.
+pub const FADE_LENGTH: usize = 512;
+
+/// Size of the queue carrying MIDI messages to the audio thread.
+pub const MIDI_QUEUE_LENGTH: usize = 1024;
+
+/// Something the audio thread can play: a single engine or a set of voices sharing one slot layout.
+pub trait Player: Send {
+    fn sample_rate(&self) -> u32;
+    fn set_sample_rate(&mut self, sample_rate: u32);
+    /// Sets a slot of the signal arena, ignoring slots out of range.
+    fn set(&mut self, slot: usize, value: f32);
+    /// Number of channels of a frame, between 1 and `MAX_CHANNELS`.
+    fn channels(&self) -> usize {
+        1
+    }
+    /// Computes the next sample of every channel, `frame` has `channels()` elements.
+    fn next_frame(&mut self, frame: &mut [f32]);
+    /// Next sample of the first channel.
+    fn next_sample(&mut self) -> f32 {
+        let mut frame = [0.0; MAX_CHANNELS];
+        let channels = self.channels();
+        self.next_frame(&mut frame[..channels]);
+        frame[0]
+    }
+    fn midi(&mut self, _message: &MidiMessage) {}
+    /// Binds a MIDI controller to a slot, None removes the binding. Must not allocate.
+    fn map_midi(&mut self, _slot: usize, _binding: Option<MidiBinding>) {}
+}
+
+impl<G: Gadget + Send> Player for Engine<G> {
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+    fn set_sample_rate(&mut self, sample_rate: u32) {
+        Engine::set_sample_rate(self, sample_rate);
+    }
+    fn set(&mut self, slot: usize, value: f32) {
+        if slot < self.signals.len() {
+            self.signals[slot] = value;
+        }
+    }
+    fn channels(&self) -> usize {
+        Engine::channels(self)
+    }
+    #[inline]
+    fn next_frame(&mut self, frame: &mut [f32]) {
+        self.run();
+        for (c, x) in frame.iter_mut().enumerate() {
+            *x = self.out_channel(c);
+        }
+    }
+    fn midi(&mut self, message: &MidiMessage) {
+        if let Some(event) = message.event() {
+            self.apply_midi(&event);
+        }
+    }
+    fn map_midi(&mut self, slot: usize, binding: Option<MidiBinding>) {
+        Engine::map_midi(self, slot, binding);
+    }
+}
+
+/// Messages sent from the GUI to the player running on the audio thread.
+pub enum Command<P: Player> {
+    Set { slot: usize, value: f32 },
+    MapMidi { slot: usize, binding: Option<MidiBinding> },
+    Replace(Box<P>),
+    Start,
+    Stop,
+}
+
+/// Error returned when the command queue to the audio thread is full.
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueueFull;
+
+impl std::fmt::Display for QueueFull {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Audio command queue is full")
+    }
+}
+
+impl std::error::Error for QueueFull {}
+
+/// Creates a connected pair of a GUI side handle and an audio source running at `sample_rate`
+/// with `channels` interleaved channels (at most `MAX_CHANNELS`).
+/// The source is meant to be played by rodio, the handle stays with the GUI.
+pub fn engine_stream<P: Player>(
+    capacity: usize,
+    sample_rate: u32,
+    channels: usize,
+) -> (EngineHandle<P>, EngineSource<P>) {
+    let (commands, command_consumer) = RingBuffer::new(capacity);
+    let (garbage_producer, garbage) = RingBuffer::new(capacity);
+    let (midi_sender, midi, midi_monitor) = midi_queue(MIDI_QUEUE_LENGTH);
+    (
+        EngineHandle {
+            commands,
+            garbage,
+            midi_sender,
+            midi_monitor,
+            bound: Vec::new(),
+            layout: 0,
+            sent: Vec::new(),
+            mapped: Vec::new(),
+            sample_rate,
+        },
+        EngineSource {
+            player: None,
+            previous: None,
+            commands: command_consumer,
+            garbage: garbage_producer,
+            midi,
+            fade: 0,
+            gain: 0.0,
+            playing: false,
+            countdown: 0,
+            sample_rate,
+            channels: channels.clamp(1, MAX_CHANNELS),
+            channel: 0,
+            frame: [0.0; MAX_CHANNELS],
+        },
+    )
+}
+
+/// GUI side of the stream. Owns the producer end of the lock-free command queue.
+pub struct EngineHandle<P: Player> {
+    commands: Producer<Command<P>>,
+    garbage: Consumer<Box<P>>,
+    midi_sender: MidiSender,
+    midi_monitor: Consumer<MidiMessage>,
+    bound: Vec<(usize, usize)>,
+    /// Number of parameters of the engine when the player was last replaced.
+    layout: usize,
+    sent: Vec<f32>,
+    /// MIDI binding of every bound parameter as last sent: (parameter, slot, binding).
+    mapped: Vec<(usize, usize, Option<MidiBinding>)>,
+    sample_rate: u32,
+}
+
+impl<G: Gadget + Clone + Send> EngineHandle<Engine<G>> {
+    /// Sends a copy of a bound engine to the audio thread, which crossfades to it.
+    pub fn rebind(&mut self, engine: &Engine<G>) -> Result<(), QueueFull> {
+        self.replace(Box::new(engine.clone()), engine)
+    }
+}
+
+impl<P: Player> EngineHandle<P> {
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+    /// Sender for MIDI input connections feeding the player.
+    pub fn midi_sender(&self) -> MidiSender {
+        self.midi_sender.clone()
+    }
+    /// Next MIDI message received by the input connections, for MIDI learn.
+    pub fn poll_midi(&mut self) -> Option<MidiMessage> {
+        self.midi_monitor.pop().ok()
+    }
+    /// Sends a player to the audio thread, which crossfades to it.
+    /// The player is prepared for the sample rate of the stream,
+    /// `layout` is the bound engine whose slots the player uses.
+    pub fn replace<G: Gadget>(
+        &mut self,
+        mut player: Box<P>,
+        layout: &Engine<G>,
+    ) -> Result<(), QueueFull> {
+        if player.sample_rate() != self.sample_rate {
+            player.set_sample_rate(self.sample_rate);
+        }
+        self.commands
+            .push(Command::Replace(player))
+            .map_err(|_| QueueFull)?;
+        self.bound.clear();
+        self.mapped.clear();
+        self.layout = layout.gadget.parameter_count();
+        for i in 0..self.layout {
+            let p = layout.gadget.par(i);
+            // Linked parameters share the slot of their target, they cannot be bound to MIDI.
+            if let (Link::Value(_), Some(slot)) = (&p.link, p.slot) {
+                self.bound.push((i, slot));
+                self.mapped.push((i, slot, p.midi));
+            }
+        }
+        self.sent = (0..layout.signals.len())
+            .map(|slot| layout.signals[slot])
+            .collect();
+        Ok(())
+    }
+    /// Forwards values and MIDI bindings edited in the GUI to the running player.
+    /// Only parameters that were free when the player was last replaced are synchronized,
+    /// structural changes (new gadgets, links) need a rebind. Parameters are tracked by index:
+    /// once the layout changes (e.g. the ports of a new topology) nothing is synchronized.
+    pub fn sync<G: Gadget>(&mut self, engine: &Engine<G>) -> Result<(), QueueFull> {
+        let gadget = &engine.gadget;
+        if gadget.parameter_count() != self.layout
+            || self
+                .bound
+                .iter()
+                .any(|&(i, slot)| gadget.par(i).slot != Some(slot))
+        {
+            self.bound.clear();
+            self.mapped.clear();
+            return Ok(());
+        }
+        for &(i, slot) in self.bound.iter() {
+            if let Link::Value(value) = gadget.par(i).link {
+                if self.sent[slot] != value {
+                    self.commands
+                        .push(Command::Set { slot, value })
+                        .map_err(|_| QueueFull)?;
+                    self.sent[slot] = value;
+                }
+            }
+        }
+        for (i, slot, sent) in self.mapped.iter_mut() {
+            let binding = gadget.par(*i).midi;
+            if *sent != binding {
+                self.commands
+                    .push(Command::MapMidi {
+                        slot: *slot,
+                        binding,
+                    })
+                    .map_err(|_| QueueFull)?;
+                *sent = binding;
+            }
+        }
+        Ok(())
+    }
+    pub fn start(&mut self) -> Result<(), QueueFull> {
+        self.commands.push(Command::Start).map_err(|_| QueueFull)
+    }
+    pub fn stop(&mut self) -> Result<(), QueueFull> {
+        self.commands.push(Command::Stop).map_err(|_| QueueFull)
+    }
+    /// Drops players retired by the audio thread, so that it never deallocates.
+    pub fn collect_garbage(&mut self) {
+        while self.garbage.pop().is_ok() {}
+    }
+}
+
+/// Audio side of the stream: runs the player sample by sample and applies queued commands.
+pub struct EngineSource<P: Player> {
+    player: Option<Box<P>>,
+    previous: Option<Box<P>>,
+    commands: Consumer<Command<P>>,
+    garbage: Producer<Box<P>>,
+    midi: Consumer<MidiMessage>,
+    fade: usize,
+    gain: f32,
+    playing: bool,
+    countdown: usize,
+    sample_rate: u32,
+    channels: usize,
+    /// Channel returned by the next call of `next`.
+    channel: usize,
+    frame: [f32; MAX_CHANNELS],
+}
+
+/// Runs the player for one frame of the stream. Mono players are copied to all channels,
+/// missing channels are silent and channels beyond the stream are dropped.
+#[inline]
+fn play<P: Player>(player: &mut P, frame: &mut [f32]) {
+    let mut own = [0.0; MAX_CHANNELS];
+    let n = player.channels().clamp(1, MAX_CHANNELS);
+    player.next_frame(&mut own[..n]);
+    for (c, x) in frame.iter_mut().enumerate() {
+        *x = if n == 1 { own[0] } else { own.get(c).copied().unwrap_or(0.0) };
+    }
+}
+
+impl<P: Player> EngineSource<P> {
+    fn retire(&mut self, player: Box<P>) {
+        if let Err(rtrb::PushError::Full(player)) = self.garbage.push(player) {
+            // The GUI is not collecting, we have no choice but to free it here.
+            drop(player);
+        }
+    }
+    fn apply_commands(&mut self) {
+        while let Ok(command) = self.commands.pop() {
+            match command {
+                Command::Set { slot, value } => {
+                    if let Some(player) = self.player.as_mut() {
+                        player.set(slot, value);
+                    }
+                }
+                Command::MapMidi { slot, binding } => {
+                    if let Some(player) = self.player.as_mut() {
+                        player.map_midi(slot, binding);
+                    }
+                }
+                Command::Replace(player) => {
+                    if let Some(previous) = self.previous.take() {
+                        self.retire(previous);
+                    }
+                    self.previous = self.player.replace(player);
+                    self.fade = if self.previous.is_some() {
+                        FADE_LENGTH
+                    } else {
+                        0
+                    };
+                }
+                Command::Start => self.playing = true,
+                Command::Stop => self.playing = false,
+            }
+        }
+        while let Ok(message) = self.midi.pop() {
+            if let Some(player) = self.player.as_mut() {
+                player.midi(&message);
+            }
+        }
+    }
+}
+
+impl<P: Player> EngineSource<P> {
+    fn next_frame(&mut self) {
+        if self.countdown == 0 {
+            self.apply_commands();
+            self.countdown = CONTROL_BLOCK;
+        }
+        self.countdown -= 1;
+
+        let frame = &mut self.frame[..self.channels];
+        let step = 1.0 / FADE_LENGTH as f32;
+        if self.playing {
+            self.gain = (self.gain + step).min(1.0);
+        } else {
+            self.gain = (self.gain - step).max(0.0);
+        }
+        if self.gain == 0.0 {
+            frame.iter_mut().for_each(|x| *x = 0.0);
+            return;
+        }
+
+        match self.player.as_mut() {
+            Some(player) => play(player.as_mut(), frame),
+            None => frame.iter_mut().for_each(|x| *x = 0.0),
+        }
+        if self.fade > 0 {
+            let mix = self.fade as f32 / FADE_LENGTH as f32;
+            if let Some(previous) = self.previous.as_mut() {
+                let mut old = [0.0; MAX_CHANNELS];
+                play(previous.as_mut(), &mut old[..frame.len()]);
+                for (x, y) in frame.iter_mut().zip(old.iter()) {
+                    *x = *x * (1.0 - mix) + y * mix;
+                }
+            }
+            self.fade -= 1;
+        }
+        let gain = self.gain;
+        frame.iter_mut().for_each(|x| *x *= gain);
+        if self.fade == 0 {
+            if let Some(previous) = self.previous.take() {
+                self.retire(previous);
+            }
+        }
+    }
+}
+
+impl<P: Player> Iterator for EngineSource<P> {
+    type Item = f32;
+
+    #[inline]
+    fn next(&mut self) -> Option<f32> {
+        if self.channel == 0 {
+            self.next_frame();
+        }
+        let x = self.frame[self.channel];
+        self.channel = (self.channel + 1) % self.channels;
+        Some(x)
+    }
+}
+
+impl<P: Player> Source for EngineSource<P> {
+    #[inline]
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    #[inline]
+    fn channels(&self) -> u16 {
+        self.channels as u16
+    }
+
+    #[inline]
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    #[inline]
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
@@ -1,178 +1,114 @@
 use crate::gadget::*;
-use egui::{Ui};
+use crate::registry::*;
+use physynth_derive::GadgetParameters;
+
+pub fn register(registry: &mut GadgetRegistry) {
+    registry.register(GadgetType {
+        id: "ABS",
+        display_name: "Abs",
+        category: "Transformations",
+        description: "Absolute value of the input",
+        prefix: "ABS",
+        constructor: |name| Box::new(AbsGadget::new(name)),
+    });
+    registry.register(GadgetType {
+        id: "DABS",
+        display_name: "Double abs",
+        category: "Transformations",
+        description: "Difference of the absolute values |x| - |y|",
+        prefix: "DABS",
+        constructor: |name| Box::new(DoubleAbsGadget::new(name)),
+    });
+    registry.register(GadgetType {
+        id: "AP",
+        display_name: "Amplitude/phase",
+        category: "Transformations",
+        description: "Converts x/y coordinates to amplitude and phase (in units of pi/2)",
+        prefix: "AP",
+        constructor: |name| Box::new(AmplitudePhaseGadget::new(name)),
+    });
+    registry.register(GadgetType {
+        id: "PAN",
+        display_name: "Pan",
+        category: "Transformations",
+        description: "Equal power panning of the input to left and right",
+        prefix: "Pan",
+        constructor: |name| Box::new(PanGadget::new(name)),
+    });
+}
 
+#[derive(Clone, GadgetParameters)]
+#[gadget(name = "ABS")]
 pub struct AbsGadget {
     inp: Parameter,
+    #[param(output)]
     out: Parameter,
     instance_name: String,
 }
 
-impl AbsGadget {
-    pub fn new(name: &str) -> AbsGadget {
-        AbsGadget {
-            inp: Parameter::new("inp", 0.0),
-            out: Parameter::new("out", 0.0),
-            instance_name: name.to_owned(),
-        }
-    }
-}
-
-impl GadgetUI for AbsGadget {
-    fn gui(&mut self, link: &mut Option<String>, ui: &mut Ui) {
-        gadget_gui(self, link, ui);
-    }
-}
-
-impl GadgetWithUI for AbsGadget {}
-
 impl Gadget for AbsGadget {
-    fn name(&self) -> &'static str {
-        "ABS"
-    }
-    fn get_instance_name(&self) -> String {
-        self.instance_name.to_owned()
-    }
-    fn par(&self, i: usize) -> &Parameter {
-        match i {
-            0 => &self.inp,
-            1 => &self.out,
-            _ => panic!("Invalid parameter number in AbsGadget"),
-        }
-    }
-    fn par_mut(&mut self, i: usize) -> &mut Parameter {
-        match i {
-            0 => &mut self.inp,
-            1 => &mut self.out,
-            _ => panic!("Invalid parameter number in AbsGadget"),
-        }
-    }
-    fn parameter_count(&self) -> usize {
-        2
-    }
     #[inline]
-    fn run(&mut self) {
-        *self.out = (*self.inp).abs();
+    fn run(&mut self, s: &mut Signals) {
+        s[&self.out] = s[&self.inp].abs();
     }
 }
 
+#[derive(Clone, GadgetParameters)]
+#[gadget(name = "DABS")]
 pub struct DoubleAbsGadget {
     x: Parameter,
     y: Parameter,
+    #[param(output)]
     out: Parameter,
     instance_name: String,
 }
 
-impl DoubleAbsGadget {
-    pub fn new(name: &str) -> DoubleAbsGadget {
-        DoubleAbsGadget {
-            x: Parameter::new("x", 0.0),
-            y: Parameter::new("y", 0.0),
-            out: Parameter::new("out", 0.0),
-            instance_name: name.to_owned(),
-        }
-    }
-}
-
-impl GadgetUI for DoubleAbsGadget {
-    fn gui(&mut self, link: &mut Option<String>, ui: &mut Ui) {
-        gadget_gui(self, link, ui);
-    }
-}
-
-impl GadgetWithUI for DoubleAbsGadget {}
-
 impl Gadget for DoubleAbsGadget {
-    fn name(&self) -> &'static str {
-        "DABS"
-    }
-    fn get_instance_name(&self) -> String {
-        self.instance_name.to_owned()
-    }
-    fn par(&self, i: usize) -> &Parameter {
-        match i {
-            0 => &self.x,
-            1 => &self.y,
-            2 => &self.out,
-            _ => panic!("Invalid parameter number in DoubleAbsGadget"),
-        }
-    }
-    fn par_mut(&mut self, i: usize) -> &mut Parameter {
-        match i {
-            0 => &mut self.x,
-            1 => &mut self.y,
-            2 => &mut self.out,
-            _ => panic!("Invalid parameter number in DoubleAbsGadget"),
-        }
-    }
-    fn parameter_count(&self) -> usize {
-        3
-    }
     #[inline]
-    fn run(&mut self) {
-        *self.out = (*self.x).abs() - (*self.y).abs();
+    fn run(&mut self, s: &mut Signals) {
+        s[&self.out] = s[&self.x].abs() - s[&self.y].abs();
     }
 }
 
-
+#[derive(Clone, GadgetParameters)]
+#[gadget(name = "AP")]
 pub struct AmplitudePhaseGadget {
     x: Parameter,
     y: Parameter,
+    #[param(output)]
     amplitude: Parameter,
+    #[param(output)]
     phase: Parameter,
     instance_name: String,
 }
 
-impl AmplitudePhaseGadget {
-    pub fn new(name: &str) -> AmplitudePhaseGadget {
-        AmplitudePhaseGadget {
-            x: Parameter::new("x", 0.0),
-            y: Parameter::new("y", 0.0),
-            amplitude: Parameter::new("amplitude", 0.0),
-            phase: Parameter::new("phase", 0.0),
-            instance_name: name.to_owned(),
-        }
+impl Gadget for AmplitudePhaseGadget {
+    #[inline]
+    fn run(&mut self, s: &mut Signals) {
+        s[&self.amplitude] = (s[&self.x] * s[&self.x] + s[&self.y] * s[&self.y]).sqrt();
+        s[&self.phase] = s[&self.y].atan2(s[&self.x]) / std::f32::consts::FRAC_PI_2;
     }
 }
 
-impl GadgetUI for AmplitudePhaseGadget {
-    fn gui(&mut self, link: &mut Option<String>, ui: &mut Ui) {
-        gadget_gui(self, link, ui);
-    }
+#[derive(Clone, GadgetParameters)]
+#[gadget(name = "PAN")]
+pub struct PanGadget {
+    inp: Parameter,
+    /// -1 is left, 1 is right.
+    #[param(range = -1.0..=1.0)]
+    pan: Parameter,
+    #[param(output)]
+    left: Parameter,
+    #[param(output)]
+    right: Parameter,
+    instance_name: String,
 }
 
-impl GadgetWithUI for AmplitudePhaseGadget {}
-
-impl Gadget for AmplitudePhaseGadget {
-    fn name(&self) -> &'static str {
-        "DABS"
-    }
-    fn get_instance_name(&self) -> String {
-        self.instance_name.to_owned()
-    }
-    fn par(&self, i: usize) -> &Parameter {
-        match i {
-            0 => &self.x,
-            1 => &self.y,
-            2 => &self.amplitude,
-            3 => &self.phase,
-            _ => panic!("Invalid parameter number in AmplitudePhaseGadget"),
-        }
-    }
-    fn par_mut(&mut self, i: usize) -> &mut Parameter {
-        match i {
-            0 => &mut self.x,
-            1 => &mut self.y,
-            2 => &mut self.amplitude,
-            3 => &mut self.phase,
-            _ => panic!("Invalid parameter number in AmplitudePhaseGadget"),
-        }
-    }
-    fn parameter_count(&self) -> usize {
-        4
-    }
+impl Gadget for PanGadget {
     #[inline]
-    fn run(&mut self) {
-        *self.amplitude = ((*self.x)*(*self.x) + (*self.y)*(*self.y)).sqrt();
-        *self.phase = (*self.y).atan2(*self.x)/std::f32::consts::FRAC_PI_2;
+    fn run(&mut self, s: &mut Signals) {
+        let angle = (s[&self.pan].clamp(-1.0, 1.0) + 1.0) * std::f32::consts::FRAC_PI_4;
+        s[&self.left] = s[&self.inp] * angle.cos();
+        s[&self.right] = s[&self.inp] * angle.sin();
     }
 }
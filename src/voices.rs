@@ -0,0 +1,300 @@
+use crate::engine::*;
+use crate::gadget::*;
+use crate::midi::*;
+use crate::stream::Player;
+use std::fmt;
+
+/// Parameters of the voice template driven by note events.
+#[derive(Debug, Clone, PartialEq)]
+pub struct VoiceSettings {
+    pub polyphony: usize,
+    /// Set to the note frequency in Hz.
+    pub frequency: Option<String>,
+    /// Set to the note velocity (0 to 1) while the note is held, 0 after release.
+    pub velocity: Option<String>,
+    /// Set to 1 while the note is held, 0 after release.
+    pub gate: Option<String>,
+    /// Set to the note velocity at note on and back to 0 after one sample, unless the gadgets
+    /// changed it: a rising edge for hammers, envelopes or plucked strings, or the initial
+    /// displacement of an oscillator.
+    pub trigger: Option<String>,
+    /// Pitch bend range in semitones.
+    pub bend_range: f32,
+}
+
+impl Default for VoiceSettings {
+    fn default() -> Self {
+        VoiceSettings {
+            polyphony: 1,
+            frequency: None,
+            velocity: None,
+            gate: None,
+            trigger: None,
+            bend_range: 2.0,
+        }
+    }
+}
+
+impl VoiceSettings {
+    /// True if notes drive at least one parameter of the template.
+    pub fn uses_notes(&self) -> bool {
+        self.frequency.is_some()
+            || self.velocity.is_some()
+            || self.gate.is_some()
+            || self.trigger.is_some()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum VoiceError {
+    Bind(BindError),
+    UnknownParameter(String),
+}
+
+impl fmt::Display for VoiceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VoiceError::Bind(e) => write!(f, "Voice cannot be bound: {}", e),
+            VoiceError::UnknownParameter(name) => write!(f, "Unknown voice parameter {}", name),
+        }
+    }
+}
+
+impl std::error::Error for VoiceError {}
+
+impl From<BindError> for VoiceError {
+    fn from(e: BindError) -> Self {
+        VoiceError::Bind(e)
+    }
+}
+
+#[derive(Clone)]
+struct Voice<G: Gadget> {
+    engine: Engine<G>,
+    note: Option<u8>,
+    /// Value of the note counter when the voice was last started or released.
+    age: u64,
+    /// Idle voices are silent until their first note, self-excited templates would sound otherwise.
+    started: bool,
+    /// Value written to the trigger slot at note on, until the next frame.
+    trigger: Option<f32>,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct VoiceSlots {
+    frequency: Option<usize>,
+    velocity: Option<usize>,
+    gate: Option<usize>,
+    trigger: Option<usize>,
+}
+
+/// Polyphonic player: one copy of a bound template engine per voice, all sharing the same slot layout.
+///
+/// Without voice parameters notes cannot change the template, it plays at once as a single voice
+/// (e.g. a self-excited patch auditioned without MIDI).
+///
+/// A stolen voice gets the gadget outputs of the template back, e.g. the initial displacement
+/// of an oscillator. Buffers inside gadgets (delay lines, meshes) keep ringing: clearing them
+/// would need `prepare`, which allocates on the audio thread.
+#[derive(Clone)]
+pub struct VoiceManager<G: Gadget> {
+    voices: Vec<Voice<G>>,
+    slots: VoiceSlots,
+    /// Output slots of the template with their initial values.
+    initial: Vec<(usize, f32)>,
+    bend_range: f32,
+    bend: f32,
+    counter: u64,
+}
+
+impl<G: Gadget + Clone> VoiceManager<G> {
+    /// Clones the template engine once per voice; the template should already be bound.
+    pub fn new(template: &Engine<G>, settings: &VoiceSettings) -> Result<Self, VoiceError> {
+        let slot = |name: &Option<String>| -> Result<Option<usize>, VoiceError> {
+            match name {
+                None => Ok(None),
+                Some(name) => template
+                    .gadget
+                    .parameter(name)
+                    .and_then(|p| p.slot)
+                    .map(Some)
+                    .ok_or_else(|| VoiceError::UnknownParameter(name.clone())),
+            }
+        };
+        let slots = VoiceSlots {
+            frequency: slot(&settings.frequency)?,
+            velocity: slot(&settings.velocity)?,
+            gate: slot(&settings.gate)?,
+            trigger: slot(&settings.trigger)?,
+        };
+        let initial = (0..template.gadget.parameter_count())
+            .map(|i| template.gadget.par(i))
+            .filter(|p| p.output)
+            .filter_map(|p| p.slot)
+            .map(|slot| (slot, template.signals[slot]))
+            .collect();
+        let mono = !settings.uses_notes();
+        let voice = Voice {
+            engine: template.clone(),
+            note: None,
+            age: 0,
+            started: mono,
+            trigger: None,
+        };
+        let polyphony = if mono { 1 } else { settings.polyphony.max(1) };
+        Ok(VoiceManager {
+            voices: vec![voice; polyphony],
+            slots,
+            initial,
+            bend_range: settings.bend_range,
+            bend: 0.0,
+            counter: 0,
+        })
+    }
+}
+
+impl<G: Gadget> VoiceManager<G> {
+    pub fn polyphony(&self) -> usize {
+        self.voices.len()
+    }
+    /// Notes currently held, in voice order.
+    pub fn notes(&self) -> Vec<Option<u8>> {
+        self.voices.iter().map(|v| v.note).collect()
+    }
+
+    /// Voice for a new note: the same note if it is still sounding,
+    /// otherwise the longest released voice, otherwise the oldest held voice is stolen.
+    fn allocate(&self, note: u8) -> usize {
+        if let Some(i) = self.voices.iter().position(|v| v.note == Some(note)) {
+            return i;
+        }
+        let oldest = |held: bool| {
+            self.voices
+                .iter()
+                .enumerate()
+                .filter(|(_, v)| v.note.is_some() == held)
+                .min_by_key(|(_, v)| v.age)
+                .map(|(i, _)| i)
+        };
+        oldest(false).or_else(|| oldest(true)).unwrap_or(0)
+    }
+
+    pub fn note_on(&mut self, note: u8, velocity: u8) {
+        let velocity = velocity as f32 / 127.0;
+        let i = self.allocate(note);
+        self.counter += 1;
+        let frequency = note_frequency(note, self.bend);
+        let slots = self.slots;
+        let voice = &mut self.voices[i];
+        let signals = &mut voice.engine.signals;
+        if voice.note.is_some() && voice.note != Some(note) {
+            for &(slot, value) in self.initial.iter() {
+                signals[slot] = value;
+            }
+        }
+        voice.note = Some(note);
+        voice.age = self.counter;
+        voice.started = true;
+        if let Some(slot) = slots.frequency {
+            signals[slot] = frequency;
+        }
+        if let Some(slot) = slots.velocity {
+            signals[slot] = velocity;
+        }
+        if let Some(slot) = slots.gate {
+            signals[slot] = 1.0;
+        }
+        if let Some(slot) = slots.trigger {
+            signals[slot] = velocity;
+            voice.trigger = Some(velocity);
+        }
+    }
+
+    pub fn note_off(&mut self, note: u8) {
+        self.counter += 1;
+        let slots = self.slots;
+        for voice in self.voices.iter_mut().filter(|v| v.note == Some(note)) {
+            voice.note = None;
+            voice.age = self.counter;
+            if let Some(slot) = slots.velocity {
+                voice.engine.signals[slot] = 0.0;
+            }
+            if let Some(slot) = slots.gate {
+                voice.engine.signals[slot] = 0.0;
+            }
+        }
+    }
+
+    /// Bend in semitones, applied to all held notes.
+    pub fn pitch_bend(&mut self, semitones: f32) {
+        self.bend = semitones;
+        if let Some(slot) = self.slots.frequency {
+            for voice in self.voices.iter_mut() {
+                if let Some(note) = voice.note {
+                    voice.engine.signals[slot] = note_frequency(note, semitones);
+                }
+            }
+        }
+    }
+
+    pub fn handle_event(&mut self, event: MidiEvent) {
+        match event {
+            MidiEvent::NoteOn { note, velocity, .. } => self.note_on(note, velocity),
+            MidiEvent::NoteOff { note, .. } => self.note_off(note),
+            MidiEvent::PitchBend { value, .. } => {
+                self.pitch_bend(value as f32 / 8192.0 * self.bend_range)
+            }
+            _ => {}
+        }
+    }
+}
+
+impl<G: Gadget + Send> Player for VoiceManager<G> {
+    fn sample_rate(&self) -> u32 {
+        self.voices[0].engine.sample_rate
+    }
+    fn set_sample_rate(&mut self, sample_rate: u32) {
+        for voice in self.voices.iter_mut() {
+            voice.engine.set_sample_rate(sample_rate);
+        }
+    }
+    fn set(&mut self, slot: usize, value: f32) {
+        for voice in self.voices.iter_mut() {
+            voice.engine.set(slot, value);
+        }
+    }
+    fn channels(&self) -> usize {
+        self.voices[0].engine.channels()
+    }
+    #[inline]
+    fn next_frame(&mut self, frame: &mut [f32]) {
+        let mut voice_frame = [0.0; MAX_CHANNELS];
+        let voice_frame = &mut voice_frame[..frame.len()];
+        frame.iter_mut().for_each(|x| *x = 0.0);
+        let trigger = self.slots.trigger;
+        for voice in self.voices.iter_mut().filter(|v| v.started) {
+            voice.engine.next_frame(voice_frame);
+            if let (Some(slot), Some(value)) = (trigger, voice.trigger.take()) {
+                if voice.engine.signals[slot] == value {
+                    voice.engine.signals[slot] = 0.0;
+                }
+            }
+            for (x, v) in frame.iter_mut().zip(voice_frame.iter()) {
+                *x += v;
+            }
+        }
+    }
+    fn midi(&mut self, message: &MidiMessage) {
+        if let Some(event) = message.event() {
+            self.handle_event(event);
+            for voice in self.voices.iter_mut() {
+                voice.engine.apply_midi(&event);
+            }
+        }
+    }
+    fn map_midi(&mut self, slot: usize, binding: Option<MidiBinding>) {
+        for voice in self.voices.iter_mut() {
+            voice.engine.map_midi(slot, binding);
+        }
+    }
+}
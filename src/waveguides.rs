@@ -0,0 +1,477 @@
+use crate::gadget::*;
+use crate::registry::*;
+use physynth_derive::GadgetParameters;
+use std::f32::consts::PI;
+
+pub fn register(registry: &mut GadgetRegistry) {
+    registry.register(GadgetType {
+        id: "WG",
+        display_name: "Waveguide string",
+        category: "Strings",
+        description:
+            "Digital waveguide string with loss and dispersion, plucked or struck at a position",
+        prefix: "String",
+        constructor: |name| Box::new(WaveguideStringGadget::new(name)),
+    });
+    registry.register(GadgetType {
+        id: "KS",
+        display_name: "Karplus-Strong string",
+        category: "Strings",
+        description: "Delay loop excited by a noise burst",
+        prefix: "KS",
+        constructor: |name| Box::new(KarplusStrongGadget::new(name)),
+    });
+    registry.register(GadgetType {
+        id: "TUBE",
+        display_name: "Waveguide tube",
+        category: "Winds",
+        description:
+            "Cylindrical bore closed at the mouthpiece and open at the end, driven by a flow",
+        prefix: "Tube",
+        constructor: |name| Box::new(TubeGadget::new(name)),
+    });
+}
+
+/// Lowest frequency of the strings, decides the length of the delay lines.
+pub const MIN_STRING_FREQUENCY: f32 = 20.0;
+const DISPERSION_STAGES: usize = 4;
+/// Forces below this frequency (Hz) do not move the string.
+const FORCE_CUTOFF: f32 = 5.0;
+
+/// y = (1 - c) x + c y1, unity gain at DC.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct OnePole {
+    pub c: f32,
+    y1: f32,
+}
+
+impl OnePole {
+    #[inline]
+    pub fn process(&mut self, x: f32) -> f32 {
+        self.y1 = (1.0 - self.c) * x + self.c * self.y1;
+        self.y1
+    }
+    /// Phase delay in samples at the angular frequency `omega` (radians per sample).
+    pub fn phase_delay(&self, omega: f32) -> f32 {
+        (self.c * omega.sin()).atan2(1.0 - self.c * omega.cos()) / omega
+    }
+}
+
+/// First order allpass (a + z^-1) / (1 + a z^-1).
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct Allpass {
+    pub a: f32,
+    x1: f32,
+    y1: f32,
+}
+
+impl Allpass {
+    /// Thiran allpass with a delay of `d` samples at low frequencies, best for d between 0.5 and 1.5.
+    pub fn set_delay(&mut self, d: f32) {
+        self.a = (1.0 - d) / (1.0 + d);
+    }
+    #[inline]
+    pub fn process(&mut self, x: f32) -> f32 {
+        let y = self.a * x + self.x1 - self.a * self.y1;
+        self.x1 = x;
+        self.y1 = y;
+        y
+    }
+    pub fn phase_delay(&self, omega: f32) -> f32 {
+        1.0 - 2.0 * (self.a * omega.sin()).atan2(1.0 + self.a * omega.cos()) / omega
+    }
+}
+
+/// Filters closing the delay loop of a string: loss, dispersion and the fractional part of the delay.
+#[derive(Debug, Clone, Default)]
+struct LoopFilter {
+    key: Option<[f32; 4]>,
+    gain: f32,
+    loss: OnePole,
+    dispersion: [Allpass; DISPERSION_STAGES],
+    fraction: Allpass,
+}
+
+impl LoopFilter {
+    /// Configures the loss and dispersion filters for a string at `frequency` (Hz) decaying by 60 dB in
+    /// `decay` seconds; returns the delay left for the delay lines (in samples, at the fundamental).
+    fn configure(
+        &mut self,
+        sample_rate: u32,
+        frequency: f32,
+        decay: f32,
+        brightness: f32,
+        dispersion: f32,
+    ) -> f32 {
+        let omega = 2.0 * PI * frequency / sample_rate as f32;
+        self.gain = 10.0f32.powf(-3.0 / (decay.max(0.01) * frequency));
+        self.loss.c = 0.9 * (1.0 - brightness.clamp(0.0, 1.0));
+        let mut delay = self.loss.phase_delay(omega);
+        for ap in self.dispersion.iter_mut() {
+            ap.a = -0.7 * dispersion.clamp(0.0, 1.0);
+            delay += ap.phase_delay(omega);
+        }
+        sample_rate as f32 / frequency - delay
+    }
+    /// Reconfigures only when one of the settings changed, returns the delay left for the delay lines.
+    fn update(&mut self, sample_rate: u32, settings: [f32; 4], remaining: &mut f32) -> bool {
+        if self.key == Some(settings) {
+            return false;
+        }
+        let [frequency, decay, brightness, dispersion] = settings;
+        *remaining = self.configure(sample_rate, frequency, decay, brightness, dispersion);
+        self.key = Some(settings);
+        true
+    }
+    #[inline]
+    fn process(&mut self, x: f32) -> f32 {
+        let mut y = self.loss.process(x) * self.gain;
+        for ap in self.dispersion.iter_mut() {
+            y = ap.process(y);
+        }
+        self.fraction.process(y)
+    }
+}
+
+/// Circular buffer, `get(i)` returns the value written `i` samples ago.
+#[derive(Debug, Clone, Default)]
+struct DelayLine {
+    buffer: Vec<f32>,
+    position: usize,
+}
+
+impl DelayLine {
+    fn with_len(len: usize) -> Self {
+        DelayLine {
+            buffer: vec![0.0; len],
+            position: 0,
+        }
+    }
+    #[inline]
+    fn index(&self, delay: usize) -> usize {
+        (self.position + self.buffer.len() - delay) % self.buffer.len()
+    }
+    #[inline]
+    fn get(&self, delay: usize) -> f32 {
+        self.buffer[self.index(delay)]
+    }
+    #[inline]
+    fn get_mut(&mut self, delay: usize) -> &mut f32 {
+        let i = self.index(delay);
+        &mut self.buffer[i]
+    }
+    #[inline]
+    fn push(&mut self, x: f32) {
+        self.position = (self.position + 1) % self.buffer.len();
+        self.buffer[self.position] = x;
+    }
+}
+
+/// Ideal string between a rigid nut and a lossy bridge, modelled by two delay lines
+/// (rails) of displacement waves travelling in opposite directions.
+///
+/// A positive edge on `pluck` plucks the string at `position` with the given amplitude,
+/// `strike` is added to the string at `position` in every sample (e.g. from a hammer or an oscillator).
+/// `out` is the displacement at `pickup`, `bridge` the wave arriving at the bridge.
+/// Positions are relative to the length of the string (0 is the nut, 1 the bridge).
+///
+/// `force` (N) pushes the string at `position`, launching waves of velocity `force / (2 impedance)`
+/// to both sides; forces below a few Hz are filtered out so that a steady push cannot build up.
+/// `velocity` is the velocity the point at `position` will have in the next sample without
+/// a new force, for a bow with `admittance` 1 / (2 `impedance`).
+#[derive(Clone, GadgetParameters)]
+#[gadget(name = "WG")]
+pub struct WaveguideStringGadget {
+    #[param(default = 220.0, range = 20.0..=5000.0, units = "Hz")]
+    frequency: Parameter,
+    pluck: Parameter,
+    strike: Parameter,
+    #[param(default = 0.2, range = 0.0..=1.0)]
+    position: Parameter,
+    #[param(default = 0.8, range = 0.0..=1.0)]
+    pickup: Parameter,
+    #[param(default = 2.0, range = 0.01..=30.0, units = "s")]
+    decay: Parameter,
+    #[param(default = 0.5, range = 0.0..=1.0)]
+    brightness: Parameter,
+    #[param(range = 0.0..=1.0)]
+    dispersion: Parameter,
+    #[param(output)]
+    out: Parameter,
+    #[param(output)]
+    bridge: Parameter,
+    #[param(units = "N")]
+    force: Parameter,
+    #[param(default = 0.5, range = 0.01..=10.0, units = "N s/m")]
+    impedance: Parameter,
+    #[param(output, units = "m/s")]
+    velocity: Parameter,
+    #[init(DEFAULT_SAMPLE_RATE)]
+    sample_rate: u32,
+    /// Displacement added to the waves leaving `position`, the integral of the force.
+    pushed: f32,
+    /// Waves travelling from the nut to the bridge.
+    right: DelayLine,
+    /// Waves travelling from the bridge to the nut.
+    left: DelayLine,
+    /// Length of the rails in samples.
+    length: usize,
+    filter: LoopFilter,
+    last_pluck: f32,
+    instance_name: String,
+}
+
+impl WaveguideStringGadget {
+    /// Rail position of a point of the string (0 is the nut, 1 the bridge).
+    fn point(&self, x: f32) -> usize {
+        ((x.clamp(0.0, 1.0) * self.length as f32).round() as usize).clamp(1, self.length - 1)
+    }
+    fn displacement(&self, k: usize) -> f32 {
+        self.right.get(k) + self.left.get(self.length - k)
+    }
+    /// Triangular shape with the peak `amplitude` at the rail position `k`.
+    fn pluck_at(&mut self, k: usize, amplitude: f32) {
+        for i in 0..=self.length {
+            let y = if i <= k {
+                i as f32 / k as f32
+            } else {
+                (self.length - i) as f32 / (self.length - k) as f32
+            };
+            *self.right.get_mut(i) = 0.5 * amplitude * y;
+            *self.left.get_mut(self.length - i) = 0.5 * amplitude * y;
+        }
+    }
+}
+
+impl Gadget for WaveguideStringGadget {
+    fn prepare(&mut self, sample_rate: u32) {
+        self.sample_rate = sample_rate;
+        let len = (sample_rate as f32 / MIN_STRING_FREQUENCY) as usize / 2 + 8;
+        self.right = DelayLine::with_len(len);
+        self.left = DelayLine::with_len(len);
+        self.filter = LoopFilter::default();
+        self.length = 2;
+        self.pushed = 0.0;
+    }
+    fn run(&mut self, s: &mut Signals) {
+        let max_frequency = self.sample_rate as f32 / 16.0;
+        let settings = [
+            s[&self.frequency].clamp(MIN_STRING_FREQUENCY, max_frequency),
+            s[&self.decay],
+            s[&self.brightness],
+            s[&self.dispersion],
+        ];
+        let mut remaining = 0.0;
+        if self
+            .filter
+            .update(self.sample_rate, settings, &mut remaining)
+        {
+            // Two rails of `length` samples and a fractional delay between 0.5 and 1.5 samples.
+            let length = ((remaining - 0.5) / 2.0).floor().max(2.0);
+            self.length = (length as usize).min(self.right.buffer.len() - 2);
+            self.filter
+                .fraction
+                .set_delay((remaining - 2.0 * length).clamp(0.5, 1.5));
+        }
+
+        let pluck = s[&self.pluck];
+        let position = self.point(s[&self.position]);
+        if pluck > 0.0 && self.last_pluck <= 0.0 {
+            self.pluck_at(position, pluck);
+        }
+        self.last_pluck = pluck;
+
+        // Read before pushing, the waves written `length` samples ago arrive at the ends.
+        let at_bridge = self.right.get(self.length - 1);
+        let at_nut = self.left.get(self.length - 1);
+        self.right.push(-at_nut);
+        self.left.push(-self.filter.process(at_bridge));
+
+        let dt = 1.0 / self.sample_rate as f32;
+        let leak = 1.0 - 2.0 * PI * FORCE_CUTOFF * dt;
+        let admittance = 1.0 / (2.0 * s[&self.impedance].max(0.01));
+        self.pushed = leak * self.pushed + s[&self.force] * admittance * dt;
+        let strike = 0.5 * s[&self.strike] + self.pushed;
+        *self.right.get_mut(position) += strike;
+        *self.left.get_mut(self.length - position) += strike;
+
+        // The point moves with the waves leaving it on one side and arriving on the other,
+        // the push counts once. Next sample it gets the waves now one step closer.
+        let y = self.displacement(position) - self.pushed;
+        let next = self.right.get(position - 1)
+            + self.left.get(self.length - position - 1)
+            + leak * self.pushed;
+        s[&self.velocity] = (next - y) / dt;
+
+        s[&self.out] = self.displacement(self.point(s[&self.pickup]));
+        s[&self.bridge] = at_bridge;
+    }
+}
+
+/// Karplus-Strong string: a single delay loop filled with noise when plucked.
+/// `strike` is fed into the loop in every sample.
+#[derive(Clone, GadgetParameters)]
+#[gadget(name = "KS")]
+pub struct KarplusStrongGadget {
+    #[param(default = 220.0, range = 20.0..=5000.0, units = "Hz")]
+    frequency: Parameter,
+    pluck: Parameter,
+    strike: Parameter,
+    #[param(default = 2.0, range = 0.01..=30.0, units = "s")]
+    decay: Parameter,
+    #[param(default = 0.5, range = 0.0..=1.0)]
+    brightness: Parameter,
+    #[param(output)]
+    out: Parameter,
+    #[init(DEFAULT_SAMPLE_RATE)]
+    sample_rate: u32,
+    line: DelayLine,
+    length: usize,
+    filter: LoopFilter,
+    last_pluck: f32,
+    #[init(0x2545_f491)]
+    noise: u32,
+    instance_name: String,
+}
+
+impl KarplusStrongGadget {
+    /// Uniform noise between -1 and 1 (xorshift).
+    fn noise(&mut self) -> f32 {
+        self.noise ^= self.noise << 13;
+        self.noise ^= self.noise >> 17;
+        self.noise ^= self.noise << 5;
+        self.noise as f32 / u32::MAX as f32 * 2.0 - 1.0
+    }
+}
+
+impl Gadget for KarplusStrongGadget {
+    fn prepare(&mut self, sample_rate: u32) {
+        self.sample_rate = sample_rate;
+        self.line = DelayLine::with_len((sample_rate as f32 / MIN_STRING_FREQUENCY) as usize + 8);
+        self.filter = LoopFilter::default();
+        self.length = 2;
+    }
+    fn run(&mut self, s: &mut Signals) {
+        let max_frequency = self.sample_rate as f32 / 8.0;
+        let settings = [
+            s[&self.frequency].clamp(MIN_STRING_FREQUENCY, max_frequency),
+            s[&self.decay],
+            s[&self.brightness],
+            0.0,
+        ];
+        let mut remaining = 0.0;
+        if self
+            .filter
+            .update(self.sample_rate, settings, &mut remaining)
+        {
+            let length = (remaining - 0.5).floor().max(2.0);
+            self.length = (length as usize).min(self.line.buffer.len() - 2);
+            self.filter
+                .fraction
+                .set_delay((remaining - length).clamp(0.5, 1.5));
+        }
+
+        let pluck = s[&self.pluck];
+        if pluck > 0.0 && self.last_pluck <= 0.0 {
+            for i in 0..self.length {
+                *self.line.get_mut(i) = pluck * self.noise();
+            }
+        }
+        self.last_pluck = pluck;
+
+        let y = self.filter.process(self.line.get(self.length - 1)) + s[&self.strike];
+        self.line.push(y);
+        s[&self.out] = y;
+    }
+}
+
+/// Cylindrical bore closed at the mouthpiece and open at the other end, in units where the
+/// characteristic impedance is 1: the pressure at the mouthpiece is `p+ + p-` and the flow `p+ - p-`.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Bore {
+    /// Waves leaving the mouthpiece, reflected with inverted sign at the open end.
+    line: DelayLine,
+    /// Round trip in samples.
+    length: usize,
+    filter: LoopFilter,
+}
+
+impl Bore {
+    pub fn prepare(&mut self, sample_rate: u32) {
+        self.line =
+            DelayLine::with_len((sample_rate as f32 / MIN_STRING_FREQUENCY) as usize / 2 + 8);
+        self.filter = LoopFilter::default();
+        self.length = 2;
+    }
+    /// Tunes the fundamental to `frequency`; the wave travels the bore four times per period.
+    pub fn update(&mut self, sample_rate: u32, frequency: f32, decay: f32, brightness: f32) {
+        let max_frequency = sample_rate as f32 / 32.0;
+        let settings = [
+            2.0 * frequency.clamp(MIN_STRING_FREQUENCY, max_frequency),
+            decay,
+            brightness,
+            0.0,
+        ];
+        let mut remaining = 0.0;
+        if self.filter.update(sample_rate, settings, &mut remaining) {
+            let length = (remaining - 0.5).floor().max(2.0);
+            self.length = (length as usize).min(self.line.buffer.len() - 2);
+            self.filter
+                .fraction
+                .set_delay((remaining - length).clamp(0.5, 1.5));
+        }
+    }
+    /// Wave arriving at the mouthpiece and the pressure radiated at the open end, call once per sample.
+    #[inline]
+    pub fn reflect(&mut self) -> (f32, f32) {
+        let arriving = self.line.get(self.length - 1);
+        let reflected = -self.filter.process(arriving);
+        (reflected, arriving + reflected)
+    }
+    /// Sends the wave `p+` leaving the mouthpiece.
+    #[inline]
+    pub fn push(&mut self, outgoing: f32) {
+        self.line.push(outgoing);
+    }
+}
+
+/// Tube of a wind instrument driven at the mouthpiece by `flow`, e.g. from a reed.
+/// `pressure` is the pressure at the mouthpiece, `out` the pressure radiated at the open end.
+/// The tube sounds at `frequency` and its odd harmonics.
+#[derive(Clone, GadgetParameters)]
+#[gadget(name = "TUBE")]
+pub struct TubeGadget {
+    #[param(default = 147.0, range = 20.0..=1500.0, units = "Hz")]
+    frequency: Parameter,
+    #[param(default = 0.5, range = 0.01..=10.0, units = "s")]
+    decay: Parameter,
+    #[param(default = 0.5, range = 0.0..=1.0)]
+    brightness: Parameter,
+    flow: Parameter,
+    #[param(output)]
+    pressure: Parameter,
+    #[param(output)]
+    out: Parameter,
+    #[init(DEFAULT_SAMPLE_RATE)]
+    sample_rate: u32,
+    bore: Bore,
+    instance_name: String,
+}
+
+impl Gadget for TubeGadget {
+    fn prepare(&mut self, sample_rate: u32) {
+        self.sample_rate = sample_rate;
+        self.bore.prepare(sample_rate);
+    }
+    fn run(&mut self, s: &mut Signals) {
+        let (frequency, decay, brightness) =
+            (s[&self.frequency], s[&self.decay], s[&self.brightness]);
+        self.bore
+            .update(self.sample_rate, frequency, decay, brightness);
+        let (incoming, radiated) = self.bore.reflect();
+        let flow = s[&self.flow];
+        self.bore.push(flow + incoming);
+        s[&self.pressure] = flow + 2.0 * incoming;
+        s[&self.out] = radiated;
+    }
+}